@@ -16,18 +16,17 @@
 //!
 //! Main network listener and idle loop.
 
-use std::collections::{DList, Deque};
+use std::collections::{DList, Deque, TreeMap};
 use std::default::Default;
 use std::io::{File, Open, Write, BufferedReader, BufferedWriter};
-use std::io::IoResult;
+use std::io::{IoError, IoResult, OtherIoError};
 use std::io::timer::{mod, Timer};
 use std::sync::{Arc, RWLock};
 use std::time::Duration;
-use serialize::json;
-use time;
+use time::precise_time_ns;
 
-use jsonrpc;
 
+use bitcoin::blockdata::block::BlockHeader;
 use bitcoin::blockdata::blockchain::Blockchain;
 use bitcoin::blockdata::utxoset::{UtxoSet, ValidationLevel, TxoValidation, ScriptValidation};
 use bitcoin::network::constants::Network;
@@ -38,15 +37,18 @@ use bitcoin::network::message::{mod, SocketResponse, NetworkMessage,
                                 MessageReceived, ConnectionFailed};
 use bitcoin::network::message_blockdata::{GetHeadersMessage, Inventory, InvBlock};
 use bitcoin::network::serialize::{BitcoinHash, RawEncoder, RawDecoder};
+use bitcoin::util::hash::Sha256dHash;
 use bitcoin::util::patricia_tree::PatriciaTree;
 use bitcoin::util::misc::consume_err;
 use bitcoin::wallet::wallet::Wallet;
 
 use coinjoin;
-use constants::BLOCKCHAIN_N_FULL_BLOCKS;
-use constants::UTXO_SYNC_N_BLOCKS;
-use constants::SAVE_FREQUENCY;
-use rpc_server::handle_rpc;
+use logger::{Logger, SyncLog, P2pLog, RpcLog, CoinjoinLog, WalletLog};
+use logger::{Debug, Notice, Status, Warning, Error, Fatal};
+use metrics::Metrics;
+use rpc_server::{RpcRequest, handle_public_rpc, handle_rpc};
+use snapshot::load_utxo_snapshot;
+use supervisor::NetworkStatus;
 use user_data::NetworkConfig;
 use wallet::load_or_create_wallet;
 
@@ -57,6 +59,12 @@ pub struct IdleState {
   pub sock: Socket,
   /// Network that we're on
   pub config: NetworkConfig,
+  /// Logger for this network
+  pub logger: Logger,
+  /// Status of this network's task, as kept by its supervisor
+  pub status: Arc<RWLock<NetworkStatus>>,
+  /// Metrics for this network
+  pub metrics: Metrics,
   /// Coinjoin server
   pub coinjoin: Option<coinjoin::server::Server>,
   /// Mutex for blockchain access
@@ -69,35 +77,26 @@ pub struct IdleState {
 
 enum WalletAction {
   SyncBlockchain,
-  SyncUtxoSet(ValidationLevel),
+  SyncUtxoSet,
   SaveToDisk,
 }
 
-user_enum!(
-  #[doc="An error message severity level"]
-  #[deriving(Clone, PartialEq, Eq, PartialOrd, Ord)]
-  pub enum DebugLevel {
-    #[doc="Developer interest only"]
-    Debug <-> "DEBUG",
-    #[doc="Detailed information about program state"]
-    Notice <-> "NOTE",
-    #[doc="High-level information about program state"]
-    Status <-> "STATUS",
-    #[doc="Something went possibly wrong."]
-    Warning <-> "WARN",
-    #[doc="Something went wrong."]
-    Error <-> "ERROR",
-    #[doc="Something went wrong, and the program must end because of it."]
-    Fatal <-> "FATAL"
-  }
-)
-
 /// The main Bitcoin network listener structure
 pub struct Bitcoind {
   /// Configuration for this network
   config: NetworkConfig,
+  /// Logger for this network, shared with the idle state
+  logger: Logger,
+  /// Status of this task, shared with the idle state
+  status: Arc<RWLock<NetworkStatus>>,
+  /// Metrics for this network, shared with the idle state
+  metrics: Metrics,
   /// Receiver on which RPC commands come in
-  rpc_rx: Receiver<(jsonrpc::Request, Sender<jsonrpc::JsonResult<json::Json>>)>,
+  rpc_rx: Receiver<RpcRequest>,
+  /// Receiver on which commands from the public coinjoin listener come in
+  coinjoin_rx: Receiver<RpcRequest>,
+  /// Receiver on which reloaded configuration comes in
+  config_rx: Receiver<NetworkConfig>
 }
 
 macro_rules! with_next_message(
@@ -118,7 +117,8 @@ macro_rules! with_next_message(
             }
           },
           ConnectionFailed(e, tx) => {
-            debug!($idle_state, Error, "Network error: `{}`, reconnecting.", e);
+            debug!($idle_state, P2pLog, Error, "Network error: `{}`, reconnecting.", e);
+            $idle_state.metrics.record_reconnect();
             tx.send(());
             let (chan, sock) = $bitcoind.loop_connect();
             $idle_state.net_chan = chan;
@@ -132,41 +132,48 @@ macro_rules! with_next_message(
 )
 
 macro_rules! fatal(
-  ($network:expr, $fmt:expr $(, $arg:expr)*) => (
-    fail!(concat!("{} [{:6}] {}: ", $fmt),
-          time::now().rfc3339(),
-          Fatal, $network,
-          $($arg),*);
+  ($bitcoind:expr, $subsystem:ident, $fmt:expr $(, $arg:expr)*) => (
+    {
+      let message = format!($fmt $(, $arg)*);
+      $bitcoind.logger.log($subsystem, Fatal, message.clone());
+      return Err(IoError {
+        kind: OtherIoError,
+        desc: "Fatal error",
+        detail: Some(message)
+      })
+    }
   )
 )
 
 macro_rules! debug(
-  (($network:expr, $debug_level:expr), $level:ident, $fmt:expr $(, $arg:expr)*) => (
-    if $level >= $debug_level {
-      println!(concat!("{} [{:6}] {}: ", $fmt),
-               time::now().rfc3339(),
-               $level, $network,
-               $($arg),*);
+  (($logger:expr), $subsystem:ident, $level:ident, $fmt:expr $(, $arg:expr)*) => (
+    if $logger.enabled($subsystem, $level) {
+      $logger.log($subsystem, $level, format!($fmt $(, $arg)*));
     }
   );
-  ($bitcoind:expr, $level:ident, $fmt:expr $(, $arg:expr)*) => (
-    if $level >= $bitcoind.config.debug_level {
-      println!(concat!("{} [{:6}] {}: ", $fmt),
-               time::now().rfc3339(),
-               $level, $bitcoind.config.network,
-               $($arg),*);
-    }
+  ($bitcoind:expr, $subsystem:ident, $level:ident, $fmt:expr $(, $arg:expr)*) => (
+    debug!(($bitcoind.logger), $subsystem, $level, $fmt $(, $arg)*)
   );
 )
 
 impl Bitcoind {
   /// Constructor
   pub fn new(config: NetworkConfig,
-             rpc_rx: Receiver<(jsonrpc::Request, Sender<jsonrpc::JsonResult<json::Json>>)>)
+             logger: Logger,
+             status: Arc<RWLock<NetworkStatus>>,
+             metrics: Metrics,
+             rpc_rx: Receiver<RpcRequest>,
+             coinjoin_rx: Receiver<RpcRequest>,
+             config_rx: Receiver<NetworkConfig>)
              -> Bitcoind {
     Bitcoind {
       config: config,
-      rpc_rx: rpc_rx
+      logger: logger,
+      status: status,
+      metrics: metrics,
+      rpc_rx: rpc_rx,
+      coinjoin_rx: coinjoin_rx,
+      config_rx: config_rx
     }
   }
 
@@ -175,7 +182,40 @@ impl Bitcoind {
       timer::sleep(Duration::seconds(3));
       match self.start() {
         Ok((chan, sock)) => { return (chan, sock); }
-        Err(e) => { debug!(self, Error, "Error connecting: `{}`, trying again..", e); }
+        Err(e) => {
+          debug!(self, P2pLog, Error, "Error connecting: `{}`, trying again..", e);
+          self.metrics.record_connect_failure();
+        }
+      }
+    }
+  }
+
+  // Loads the configured UTXO snapshot, if there is one and it checks out
+  fn import_utxo_snapshot(&self, blockchain: &Blockchain) -> Option<UtxoSet> {
+    let (path, hash) = match (&self.config.utxo_snapshot_path, self.config.utxo_snapshot_hash) {
+      (&Some(ref path), Some(hash)) => (path, hash),
+      _ => { return None; }
+    };
+    debug!(self, SyncLog, Status, "Importing UTXO snapshot {}...", path.display());
+    match load_utxo_snapshot(path, self.config.network, hash) {
+      Ok((utxo_set, info)) => {
+        // If we already have the snapshot's tip, it had better be where the snapshot says
+        match blockchain.get_block(info.tip_hash) {
+          Some(node) if node.height as u64 != info.height => {
+            debug!(self, SyncLog, Error,
+                   "UTXO snapshot puts {:x} at height {}, but our blockchain has it at {}.",
+                   info.tip_hash, info.height, node.height);
+            return None;
+          }
+          _ => {}
+        }
+        debug!(self, SyncLog, Status, "Imported UTXO snapshot at height {} ({:x}) with {} utxos.",
+               info.height, info.tip_hash, info.n_utxos);
+        Some(utxo_set)
+      }
+      Err(e) => {
+        debug!(self, SyncLog, Error, "Failed to import UTXO snapshot: {}", e);
+        None
       }
     }
   }
@@ -183,47 +223,53 @@ impl Bitcoind {
   /// Run the state machine
   pub fn listen(&mut self) -> IoResult<()> {
     let mut timer = Timer::new().unwrap();  // TODO: can this fail? what should we do?
-    let save_timer = timer.periodic(Duration::seconds(SAVE_FREQUENCY));
+    let mut save_timer = timer.periodic(Duration::seconds(self.config.save_frequency));
     let mut state_queue = DList::new();
 
     // Startup
     // Read wallet
-    debug!(self, Status, "Reading wallet...");
+    debug!(self, WalletLog, Status, "Reading wallet...");
     let mut wallet = match load_or_create_wallet(&self.config) {
       Ok(w) => w,
-      Err(e) => fatal!(self.config.network, "Unable to read wallet: {}", e)
+      Err(e) => fatal!(self, WalletLog, "Unable to read wallet: {}", e)
     };
-    debug!(self, Status, "Loaded wallet.");
+    debug!(self, WalletLog, Status, "Loaded wallet.");
 
     // Open socket
     let (chan, sock) = self.loop_connect();
     // Load cached blockchain and UTXO set from disk
-    debug!(self, Status, "Loading blockchain...");
+    debug!(self, SyncLog, Status, "Loading blockchain...");
     // Load blockchain from disk
     let mut decoder = RawDecoder::new(BufferedReader::new(File::open(&self.config.blockchain_path)));
     let blockchain = match ConsensusDecodable::consensus_decode(&mut decoder) {
       Ok(blockchain) => blockchain,
       Err(e) => {
-        debug!(self, Error, "Failed to load blockchain: {:}, starting from genesis.", e);
+        debug!(self, SyncLog, Error, "Failed to load blockchain: {:}, starting from genesis.", e);
         Blockchain::new(self.config.network)
       }
     };
-    debug!(self, Status, "Loading utxo set...");
+    debug!(self, SyncLog, Status, "Loading utxo set...");
     // Load UTXO set from disk
     let mut decoder = RawDecoder::new(BufferedReader::new(File::open(&self.config.utxo_set_path)));
     let utxo_set = match ConsensusDecodable::consensus_decode(&mut decoder) {
       Ok(utxo_set) => utxo_set,
       Err(e) => {
-        debug!(self, Error, "Failed to load UTXO set: {:}, starting from genesis.", e);
-        UtxoSet::new(self.config.network, BLOCKCHAIN_N_FULL_BLOCKS)
+        debug!(self, SyncLog, Error, "Failed to load UTXO set: {:}", e);
+        match self.import_utxo_snapshot(&blockchain) {
+          Some(utxo_set) => utxo_set,
+          None => {
+            debug!(self, SyncLog, Status, "Starting UTXO set from genesis.");
+            UtxoSet::new(self.config.network, self.config.blockchain_n_full_blocks)
+          }
+        }
       }
     };
 
-    debug!(self, Status, "Building address index for wallet.");
+    debug!(self, WalletLog, Status, "Building address index for wallet.");
     wallet.build_index(&utxo_set);
-    debug!(self, Status, "Done building address index.");
-    debug!(self, Debug, "Wallet coinjoin balance: {}", wallet.balance("coinjoin"));
-    debug!(self, Debug, "Wallet total balance: {}", wallet.total_balance());
+    debug!(self, WalletLog, Status, "Done building address index.");
+    debug!(self, WalletLog, Debug, "Wallet coinjoin balance: {}", wallet.balance("coinjoin"));
+    debug!(self, WalletLog, Debug, "Wallet total balance: {}", wallet.total_balance());
     // Setup idle state
     let mut idle_state = IdleState {
       sock: sock,
@@ -231,15 +277,23 @@ impl Bitcoind {
       // TODO: I'd rather this clone be some sort of take, but we need `self.config`
       //       to be around for the `Listener` trait getters below. Rework this.
       config: self.config.clone(),
+      logger: self.logger.clone(),
+      status: self.status.clone(),
+      metrics: self.metrics.clone(),
       blockchain: Arc::new(RWLock::new(blockchain)),
       utxo_set: Arc::new(RWLock::new(utxo_set)),
-      coinjoin: None,
+      // Started now, so that blinding keys are ready by the first session
+      coinjoin: if self.config.coinjoin_on {
+        Some(coinjoin::server::Server::new(self.config.coinjoin_history_path.clone()))
+      } else {
+        None
+      },
       wallet: wallet
     };
 
     // Eternal state machine loop
     state_queue.push(SyncBlockchain);
-    state_queue.push(SyncUtxoSet(TxoValidation));  // for initial sync only do TXO validation
+    state_queue.push(SyncUtxoSet);
     state_queue.push(SaveToDisk);
     loop {
       match state_queue.pop_front() {
@@ -247,12 +301,12 @@ impl Bitcoind {
         Some(SyncBlockchain) => {
           // Borrow the blockchain mutably
           let mut blockchain = idle_state.blockchain.write();
-          debug!(idle_state, Status, "Syncing blockheaders: last best tip {:x}",
+          debug!(idle_state, SyncLog, Status, "Syncing blockheaders: last best tip {:x}",
                  blockchain.best_tip_hash());
           // Do a headers-first sync of all blocks
           let mut done = false;
           while !done {
-            debug!(idle_state, Notice, "Starting headers sync from {:x}",
+            debug!(idle_state, SyncLog, Notice, "Starting headers sync from {:x}",
                    blockchain.best_tip_hash());
 
             // Request headers
@@ -264,18 +318,32 @@ impl Bitcoind {
             while !received_headers {
               with_next_message!(self, idle_state,
                 message::Headers(headers) => {
+                  let mut rejected = false;
                   for lone_header in headers.iter() {
+                    match contradicted_checkpoint(&idle_state.config.checkpoints, &*blockchain,
+                                                  &lone_header.header) {
+                      Some(height) => {
+                        debug!(idle_state, SyncLog, Error,
+                               "Headers sync: rejecting {:x}, which contradicts the checkpoint at height {}.",
+                               lone_header.header.bitcoin_hash(), height);
+                        // The rest of this fork would only be orphans
+                        rejected = true;
+                        break;
+                      }
+                      None => {}
+                    }
                     match blockchain.add_header(lone_header.header) {
                       Err(e) => {
-                        debug!(idle_state, Error, "Headers sync: failed to add {:x}: {}", 
+                        debug!(idle_state, SyncLog, Error, "Headers sync: failed to add {:x}: {}", 
                                lone_header.header.bitcoin_hash(), e);
                       }
                        _ => {}
                     }
                   }
                   received_headers = true;
-                  // We are done if this `headers` message did not update our status
-                  done = headers.len() == 0;
+                  // We are done if this `headers` message did not update our status,
+                  // or if asking again would only get us the same bad fork
+                  done = headers.len() == 0 || rejected;
                 }
                 message::Ping(nonce) => {
                   consume_err("Warning: failed to send pong in response to ping",
@@ -285,42 +353,68 @@ impl Bitcoind {
             }
           }
           // Done!
-          debug!(idle_state, Status, "Done headers sync.");
+          match blockchain.get_block(blockchain.best_tip_hash()) {
+            Some(node) => idle_state.metrics.set_header_height(node.height as u64),
+            None => {}
+          }
+          debug!(idle_state, SyncLog, Status, "Done headers sync.");
         },
-        Some(SyncUtxoSet(validation_level)) => {
+        Some(SyncUtxoSet) => {
           let mut failed = false;
-          let mut cache = Vec::with_capacity(UTXO_SYNC_N_BLOCKS);
+          let mut refused = false;
+          let mut cache = Vec::with_capacity(idle_state.config.utxo_sync_n_blocks);
           // Scope here to make sure we drop the read handle before we try to write
           {
             let blockchain = idle_state.blockchain.read();
+            // Scripts are only checked above the assume-valid block, and everywhere
+            // if it is not on our best chain
+            let assume_valid_height = best_chain_height(&*blockchain, idle_state.config.assume_valid);
+            if assume_valid_height.is_none() {
+              debug!(idle_state, SyncLog, Warning,
+                     "Assume-valid block {:x} is not on our best chain, checking all scripts.",
+                     idle_state.config.assume_valid);
+            }
             let last_hash = {
               let mut utxo_set = idle_state.utxo_set.write();
               let last_hash = utxo_set.last_hash();
-              debug!(idle_state, Status, "Starting UTXO sync from {:x}", last_hash);
-
-              // Unwind any reorg'd blooks
-              for block in blockchain.rev_stale_iter(last_hash) {
-                debug!(idle_state, Notice, "Rewinding stale block {}", block.bitcoin_hash());
-                if !utxo_set.rewind(block) {
-                  debug!(idle_state, Notice, " Failed to rewind stale block {}",
-                         block.bitcoin_hash());
+              debug!(idle_state, SyncLog, Status, "Starting UTXO sync from {:x}", last_hash);
+
+              // Unwind any reorg'd blooks, unless there are more than we were
+              // told to expect; then leave the UTXO set alone until somebody
+              // looks into it, or raises max_reorg_depth
+              let n_stale = blockchain.rev_stale_iter(last_hash).count();
+              if n_stale > idle_state.config.max_reorg_depth {
+                debug!(idle_state, SyncLog, Error,
+                       "Refusing to rewind {} stale blocks, more than max_reorg_depth {}; \
+                        not syncing UTXO set.", n_stale, idle_state.config.max_reorg_depth);
+                refused = true;
+              } else {
+                for block in blockchain.rev_stale_iter(last_hash) {
+                  debug!(idle_state, SyncLog, Notice, "Rewinding stale block {}", block.bitcoin_hash());
+                  if !utxo_set.rewind(block) {
+                    debug!(idle_state, SyncLog, Notice, " Failed to rewind stale block {}",
+                           block.bitcoin_hash());
+                  }
+                }
+                if n_stale > 0 {
+                  idle_state.metrics.record_reorg();
                 }
               }
               utxo_set.last_hash()
             };
             // Loop through blockchain for new data
             let mut iter = blockchain.iter(last_hash).skip(1).peekable();
-            while !failed && !iter.is_empty() {
+            while !failed && !refused && !iter.is_empty() {
               // Cache a bunch of blocks to minimize network messages (bitcoind puts delays into each one)
               let mut height = 0;
-              for node in iter.by_ref().take(UTXO_SYNC_N_BLOCKS) {
+              for node in iter.by_ref().take(idle_state.config.utxo_sync_n_blocks) {
                 cache.push(Inventory { inv_type: InvBlock, hash: node.block.bitcoin_hash() });
                 height = node.height;
               }
 
               // Once we've cached enough, send a message requesting them
               let mut utxo_set = idle_state.utxo_set.write();
-              debug!(idle_state, Notice, "UTXO sync: height {} n_utxos {} pruned {}",
+              debug!(idle_state, SyncLog, Notice, "UTXO sync: height {} n_utxos {} pruned {}",
                      height, utxo_set.n_utxos(), utxo_set.n_pruned());
               consume_err("UTXO sync: failed to send `getdata` message",
                 idle_state.sock.send_message(message::GetData(cache.clone())));
@@ -334,7 +428,7 @@ impl Bitcoind {
                     block_count += 1;
                   }
                   message::NotFound(_) => {
-                    debug!(idle_state, Error,
+                    debug!(idle_state, SyncLog, Error,
                            "UTXO sync: received `notfound` from sync peer, failing sync.");
                     failed = true;
                     block_count += 1;
@@ -349,13 +443,24 @@ impl Bitcoind {
                 let block_opt = recv_data.lookup(&recv_inv.hash.into_le().low_128(), 128);
                 match block_opt {
                   Some(block) => {
-                    let height = height as uint - UTXO_SYNC_N_BLOCKS + 1 + n;
-                    debug!(idle_state, Debug, "Updating UTXO set with block {}: {:x}",
+                    let height = height as uint - cache.len() + 1 + n;
+                    debug!(idle_state, SyncLog, Debug, "Updating UTXO set with block {}: {:x}",
                            height, block.bitcoin_hash());
-                    match utxo_set.update(block, height, validation_level) {
-                      Ok(_) => {}
+                    match utxo_set.update(block, height, validation_level(height, assume_valid_height)) {
+                      Ok(_) => {
+                        // Let the coinjoin server know about any inputs spent out
+                        // from under its sessions
+                        match idle_state.coinjoin {
+                          Some(ref mut server) => {
+                            for tx in block.txdata.iter() {
+                              server.process_transaction(tx);
+                            }
+                          }
+                          None => {}
+                        }
+                      }
                       Err(e) => {
-                        debug!(idle_state, Error,
+                        debug!(idle_state, SyncLog, Error,
                                "Failed to update UTXO set with block {:x}: {}",
                                block.bitcoin_hash(), e);
                         failed = true;
@@ -366,7 +471,7 @@ impl Bitcoind {
                     }
                   }
                   None => {
-                    debug!(idle_state, Error, "Uh oh, requested block {:x} but didn't get it!",
+                    debug!(idle_state, SyncLog, Error, "Uh oh, requested block {:x} but didn't get it!",
                            recv_inv.hash);
                     failed = true;
                   }
@@ -376,11 +481,11 @@ impl Bitcoind {
             }
           }
           if failed {
-            debug!(idle_state, Error, "Failed to sync UTXO set, will resync chain and try again.");
-            debug!(idle_state, Debug, "Pausing for 3 seconds.");
+            debug!(idle_state, SyncLog, Error, "Failed to sync UTXO set, will resync chain and try again.");
+            debug!(idle_state, SyncLog, Debug, "Pausing for 3 seconds.");
             timer::sleep(Duration::seconds(3));
             state_queue.push(SyncBlockchain);
-            state_queue.push(SyncUtxoSet(validation_level));
+            state_queue.push(SyncUtxoSet);
           } else {
             // Now that we're done with reorgs, update our cached block data
             let mut hashes_to_drop_data = vec![];
@@ -388,7 +493,7 @@ impl Bitcoind {
             {
               let blockchain = idle_state.blockchain.read();
               for (n, node) in blockchain.rev_iter(blockchain.best_tip_hash()).enumerate() {
-                if n < BLOCKCHAIN_N_FULL_BLOCKS {
+                if n < idle_state.config.blockchain_n_full_blocks {
                   if !node.has_txdata {
                     inv_to_add_data.push(Inventory { inv_type: InvBlock,
                                                      hash: node.block.bitcoin_hash() });
@@ -405,9 +510,9 @@ impl Bitcoind {
               let mut blockchain = idle_state.blockchain.write();
               // Delete old block data
               for hash in hashes_to_drop_data.move_iter() {
-                debug!(idle_state, Notice, "Dropping old blockdata for {:x}", hash);
+                debug!(idle_state, SyncLog, Notice, "Dropping old blockdata for {:x}", hash);
                 match blockchain.remove_txdata(hash) {
-                  Err(e) => { debug!(idle_state, Error, "Failed to remove txdata: {}", e); }
+                  Err(e) => { debug!(idle_state, SyncLog, Error, "Failed to remove txdata: {}", e); }
                   _ => {}
                 }
               }
@@ -416,15 +521,15 @@ impl Bitcoind {
               while block_count < inv_to_add_data.len() {
                 with_next_message!(self, idle_state,
                   message::Block(block) => {
-                    debug!(idle_state, Notice, "Adding blockdata for {:x}", block.bitcoin_hash());
+                    debug!(idle_state, SyncLog, Notice, "Adding blockdata for {:x}", block.bitcoin_hash());
                     match blockchain.add_txdata(block) {
-                      Err(e) => { debug!(idle_state, Error, "Failed to add txdata: {}", e); }
+                      Err(e) => { debug!(idle_state, SyncLog, Error, "Failed to add txdata: {}", e); }
                       _ => {}
                     }
                     block_count += 1;
                   }
                   message::NotFound(_) => {
-                    debug!(idle_state, Error,
+                    debug!(idle_state, SyncLog, Error,
                            "Blockchain sync: received `notfound` on full blockdata, \
                            will not be able to handle reorgs past this block.");
                     block_count += 1;
@@ -436,19 +541,28 @@ impl Bitcoind {
                 )
               }
             }
-            debug!(idle_state, Status, "Done UTXO sync.");
+            {
+              let blockchain = idle_state.blockchain.read();
+              let utxo_set = idle_state.utxo_set.read();
+              let height = blockchain.get_block(utxo_set.last_hash()).map_or(0, |node| node.height);
+              idle_state.metrics.set_utxo_set(height as u64, utxo_set.n_utxos() as u64,
+                                              utxo_set.n_pruned() as u64);
+            }
+            debug!(idle_state, SyncLog, Status, "Done UTXO sync.");
           }
         },
         // Idle loop
         None => {
-          debug!(idle_state, Debug, "Idling...");
+          debug!(idle_state, SyncLog, Debug, "Idling...");
           let mut replace_socket = false;
+          let mut new_config = None;
           nu_select!(
             response from idle_state.net_chan => {
               match response {
                 MessageReceived(message) => idle_message(&mut state_queue, &mut idle_state, message),
                 ConnectionFailed(e, tx) => {
-                  debug!(idle_state, Error, "Network error: `{}`, reconnecting.", e);
+                  debug!(idle_state, P2pLog, Error, "Network error: `{}`, reconnecting.", e);
+                  idle_state.metrics.record_reconnect();
                   tx.send(());
                   timer::sleep(Duration::seconds(1));
                   replace_socket = true;
@@ -457,18 +571,57 @@ impl Bitcoind {
             },
             () from save_timer => {
               state_queue.push(SyncBlockchain);
-              state_queue.push(SyncUtxoSet(ScriptValidation));
+              state_queue.push(SyncUtxoSet);
               state_queue.push(SaveToDisk);
             },
             (request, tx) from self.rpc_rx => {
+              debug!(idle_state, RpcLog, Debug, "RPC call `{}`", request.method);
               tx.send(handle_rpc(request, &mut idle_state));
+            },
+            (request, tx) from self.coinjoin_rx => {
+              debug!(idle_state, CoinjoinLog, Debug, "Public RPC call `{}`", request.method);
+              tx.send(handle_public_rpc(request, &mut idle_state));
+            },
+            config from self.config_rx => {
+              new_config = Some(config);
             }
           );
+          match idle_state.coinjoin {
+            Some(ref server) => {
+              idle_state.metrics.set_coinjoin_sessions(server.session_states().as_slice());
+            }
+            None => {}
+          }
           if replace_socket {
             let (chan, sock) = self.loop_connect();
             idle_state.net_chan = chan;
             idle_state.sock = sock;
           }
+          match new_config {
+            Some(config) => {
+              let old_save_frequency = idle_state.config.save_frequency;
+              match idle_state.config.reload(&config) {
+                Ok(restart) => {
+                  let _ = self.config.reload(&config);
+                  idle_state.logger.reload(&idle_state.config);
+                  if idle_state.config.save_frequency != old_save_frequency {
+                    save_timer = timer.periodic(Duration::seconds(idle_state.config.save_frequency));
+                  }
+                  if restart.is_empty() {
+                    debug!(idle_state, SyncLog, Status, "Reloaded configuration.");
+                  } else {
+                    debug!(idle_state, SyncLog, Warning,
+                           "Reloaded configuration; changes to {} need a restart to take effect.",
+                           restart.connect(", "));
+                  }
+                }
+                Err(e) => {
+                  debug!(idle_state, SyncLog, Error, "Rejected configuration reload: {}", e);
+                }
+              }
+            }
+            None => {}
+          }
         },
         // Temporary states
         Some(SaveToDisk) => {
@@ -476,33 +629,35 @@ impl Bitcoind {
           let us_arc = idle_state.utxo_set.clone();
           let blockchain_path = idle_state.config.blockchain_path.clone();
           let utxo_set_path = idle_state.config.utxo_set_path.clone();
-          let network = idle_state.config.network;
-          let debug_level = idle_state.config.debug_level;
+          let logger = idle_state.logger.clone();
+          let metrics = idle_state.metrics.clone();
           spawn(proc() {
+            let start = precise_time_ns();
             // Lock the blockchain for reading while we are saving it.
             {
               let blockchain = bc_arc.read();
-              debug!((network, debug_level), Status, "Saving blockchain...");
+              debug!((logger), SyncLog, Status, "Saving blockchain...");
               let mut encoder = RawEncoder::new(BufferedWriter::new(File::open_mode(&blockchain_path, Open, Write)));
               match blockchain.consensus_encode(&mut encoder) {
-                Ok(()) => { debug!((network, debug_level), Status,
+                Ok(()) => { debug!((logger), SyncLog, Status,
                                    "Done saving blockchain."); },
-                Err(e) => { debug!((network, debug_level), Error,
+                Err(e) => { debug!((logger), SyncLog, Error,
                             "Failed to write blockchain: {}", e); }
               }
             }
             // Lock the UTXO set for reading while we are saving it.
             {
               let utxo_set = us_arc.read();
-              debug!((network, debug_level), Status, "Saving UTXO set...");
+              debug!((logger), SyncLog, Status, "Saving UTXO set...");
               let mut encoder = RawEncoder::new(BufferedWriter::new(File::open_mode(&utxo_set_path, Open, Write)));
               match utxo_set.consensus_encode(&mut encoder) {
-                Ok(()) => { debug!((network, debug_level), Status,
+                Ok(()) => { debug!((logger), SyncLog, Status,
                                    "Done saving UTXO set.") },
-                Err(e) => { debug!((network, debug_level), Error,
+                Err(e) => { debug!((logger), SyncLog, Error,
                                    "Failed to write UTXO set: {:}", e); }
               }
             }
+            metrics.record_save(precise_time_ns() - start);
           });
         }
       };
@@ -524,6 +679,62 @@ impl Listener for Bitcoind {
   }
 }
 
+/// Returns the height of the checkpoint a header contradicts, if any: one at
+/// the header's own height, or the highest one we have if the header would
+/// fork off below it. Only headers which connect to the blockchain can be
+/// checked.
+fn contradicted_checkpoint(checkpoints: &TreeMap<uint, Sha256dHash>, blockchain: &Blockchain,
+                           header: &BlockHeader) -> Option<uint> {
+  let height = match blockchain.get_block(header.prev_blockhash) {
+    Some(prev) => prev.height as uint + 1,
+    None => { return None; }
+  };
+  match checkpoints.find(&height) {
+    Some(hash) if *hash != header.bitcoin_hash() => { return Some(height); }
+    _ => {}
+  }
+
+  // Anything higher up only got in by building on the checkpointed block,
+  // so below it is the only place a header can leave its branch
+  let highest = checkpoints.rev_iter().find(|&(_, hash)| blockchain.get_block(*hash).is_some());
+  match highest {
+    Some((&cp_height, &cp_hash)) if height <= cp_height + 1 => {
+      let on_branch = if height == cp_height + 1 {
+        header.prev_blockhash == cp_hash
+      } else {
+        blockchain.rev_iter(cp_hash).find(|node| node.height as uint == height)
+                  .map_or(false, |node| node.block.bitcoin_hash() == header.bitcoin_hash())
+      };
+      if on_branch { None } else { Some(cp_height) }
+    }
+    _ => None
+  }
+}
+
+/// Returns the height of a block, if it is on the best chain
+fn best_chain_height(blockchain: &Blockchain, hash: Sha256dHash) -> Option<uint> {
+  let height = match blockchain.get_block(hash) {
+    Some(node) => node.height,
+    None => { return None; }
+  };
+  // Walk back from the tip to that height, and see if it's the same block
+  for node in blockchain.rev_iter(blockchain.best_tip_hash()) {
+    if node.height == height {
+      return if node.block.bitcoin_hash() == hash { Some(height as uint) } else { None };
+    }
+  }
+  None
+}
+
+/// Works out how far to check a block's transactions: scripts are skipped
+/// at or below the assume-valid block, if it is on our best chain
+fn validation_level(height: uint, assume_valid_height: Option<uint>) -> ValidationLevel {
+  match assume_valid_height {
+    Some(assume_valid_height) if height <= assume_valid_height => TxoValidation,
+    _ => ScriptValidation
+  }
+}
+
 /// Idle message handler
 fn idle_message<S:Deque<WalletAction>>(state_queue: &mut S,
                                        idle_state: &mut IdleState,
@@ -540,39 +751,58 @@ fn idle_message<S:Deque<WalletAction>>(state_queue: &mut S,
     }
     message::Block(block) => {
       let mut lock = idle_state.blockchain.write();
-      debug!(idle_state, Notice, "Received block: {:x}", block.bitcoin_hash());
+      debug!(idle_state, P2pLog, Notice, "Received block: {:x}", block.bitcoin_hash());
       if lock.get_block(block.header.prev_blockhash).is_some() {
         // non-orphan, add it
-        debug!(idle_state, Notice, "Received non-orphan, adding to blockchain...");
-        match lock.add_block(block) {
-          Err(e) => {
-            debug!(idle_state, Error, "Failed to add block: {}", e);
+        let contradicted = contradicted_checkpoint(&idle_state.config.checkpoints, &*lock,
+                                                   &block.header);
+        match contradicted {
+          Some(height) => {
+            debug!(idle_state, SyncLog, Error,
+                   "Rejecting block {:x}, which contradicts the checkpoint at height {}.",
+                   block.bitcoin_hash(), height);
+          }
+          None => {
+            debug!(idle_state, SyncLog, Notice, "Received non-orphan, adding to blockchain...");
+            match lock.add_block(block) {
+              Err(e) => {
+                debug!(idle_state, SyncLog, Error, "Failed to add block: {}", e);
+              }
+              _ => {}
+            }
+            debug!(idle_state, SyncLog, Notice, "Done adding block.");
           }
-          _ => {}
         }
-        debug!(idle_state, Notice, "Done adding block.");
       } else {
-        debug!(idle_state, Notice, "Received orphan, resyncing blockchain...");
+        debug!(idle_state, SyncLog, Notice, "Received orphan, resyncing blockchain...");
         state_queue.push(SyncBlockchain);
       }
       // In either case we want to sync the UTXO set afterward
-      state_queue.push(SyncUtxoSet(ScriptValidation));
+      state_queue.push(SyncUtxoSet);
     },
     message::Headers(headers) => {
       for lone_header in headers.iter() {
-        debug!(idle_state, Debug, "Received header: {:x}, ignoring.",
+        debug!(idle_state, P2pLog, Debug, "Received header: {:x}, ignoring.",
                lone_header.header.bitcoin_hash());
       }
     },
     message::Inv(inv) => {
-      debug!(idle_state, Debug, "Received inv.");
+      debug!(idle_state, P2pLog, Debug, "Received inv.");
       let sendmsg = message::GetData(inv);
       // Send
       consume_err("Warning: failed to send getdata in response to inv",
         idle_state.sock.send_message(sendmsg));
     }
-    message::Tx(_) => {
-      debug!(idle_state, Debug, "Received tx, ignoring");
+    message::Tx(tx) => {
+      match idle_state.coinjoin {
+        Some(ref mut server) => {
+          debug!(idle_state, CoinjoinLog, Debug, "Received tx, checking against coinjoin sessions");
+          server.process_transaction(&tx);
+        }
+        None => {
+          debug!(idle_state, P2pLog, Debug, "Received tx, ignoring");
+        }
+      }
     }
     message::GetData(_) => {}
     message::NotFound(_) => {}
@@ -588,6 +818,367 @@ fn idle_message<S:Deque<WalletAction>>(state_queue: &mut S,
 
 #[cfg(test)]
 mod tests {
-  // TODO
-}
+  use std::collections::TreeMap;
+  use std::io::TempDir;
+  use std::sync::{Arc, RWLock};
+  use serialize::json;
+
+  use bitcoin::blockdata::block::{Block, BlockHeader};
+  use bitcoin::blockdata::blockchain::Blockchain;
+  use bitcoin::blockdata::utxoset::{UtxoSet, TxoValidation, ScriptValidation};
+  use bitcoin::network::constants::Bitcoin;
+  use bitcoin::network::message;
+  use bitcoin::network::message_blockdata::{Inventory, InvBlock};
+  use bitcoin::network::serialize::BitcoinHash;
+  use bitcoin::util::hash::Sha256dHash;
+
+  use bitcoind::{Bitcoind, best_chain_height, contradicted_checkpoint, validation_level};
+  use constants::DEFAULT_BLOCKCHAIN_N_FULL_BLOCKS;
+  use logger::Logger;
+  use metrics::Metrics;
+  use mock_peer::{MockPeer, fork_block, mainnet_blocks, test_config};
+  use rpc_server::{JsonResult, Positional, Request, RpcRequest, backend_unavailable};
+  use supervisor::NetworkStatus;
+  use user_data::NetworkConfig;
+
+  // A wallet connected to a mock peer, with its data in a temporary directory
+  struct Harness {
+    peer: MockPeer,
+    rpc_tx: Option<Sender<RpcRequest>>,
+    coinjoin_tx: Option<Sender<RpcRequest>>,
+    config_tx: Option<Sender<NetworkConfig>>,
+    // Hung up when the wallet task ends, however it ends
+    done_rx: Receiver<()>,
+    dir: TempDir
+  }
 
+  impl Harness {
+    fn start() -> Harness {
+      Harness::start_with(vec![])
+    }
+
+    // Starts the wallet against a peer whose chain is genesis followed by
+    // the given blocks
+    fn start_with(blocks: Vec<Block>) -> Harness {
+      let peer = MockPeer::new(Bitcoin).unwrap();
+      for block in blocks.move_iter() {
+        peer.add_block(block);
+      }
+      Harness::connect(peer, |_| {})
+    }
+
+    // Starts the wallet against the given peer, with any changes to the test
+    // configuration. Whatever the test has told the peer so far is handled
+    // before anything the wallet sends it.
+    fn connect(peer: MockPeer, configure: |&mut NetworkConfig|) -> Harness {
+      let dir = TempDir::new("wizards-wallet-test").unwrap();
+      let mut config = test_config(dir.path(), peer.port);
+      configure(&mut config);
+      let (rpc_tx, rpc_rx) = channel();
+      let (coinjoin_tx, coinjoin_rx) = channel();
+      let (config_tx, config_rx) = channel();
+      let logger = Logger::new(&config);
+      let status = Arc::new(RWLock::new(NetworkStatus::new(Bitcoin)));
+      let bitcoind = Bitcoind::new(config, logger, status, Metrics::new(Bitcoin),
+                                   rpc_rx, coinjoin_rx, config_rx);
+      let (done_tx, done_rx) = channel::<()>();
+      spawn(proc() {
+        let _done_tx = done_tx;
+        let mut bitcoind = bitcoind;
+        let _ = bitcoind.listen();
+      });
+      Harness {
+        peer: peer,
+        rpc_tx: Some(rpc_tx),
+        coinjoin_tx: Some(coinjoin_tx),
+        config_tx: Some(config_tx),
+        done_rx: done_rx,
+        dir: dir
+      }
+    }
+
+    // RPC calls are only handled once the wallet is idle, so this also
+    // waits for any sync to finish
+    fn rpc(&self, method: &str) -> JsonResult {
+      call(self.rpc_tx.get_ref(), method)
+    }
+
+    fn rpc_u64(&self, method: &str) -> u64 {
+      match self.rpc(method) {
+        Ok(json::U64(n)) => n,
+        Ok(res) => fail!("{} returned {}", method, res),
+        Err(e) => fail!("{} failed: {}", method, e.message)
+      }
+    }
+  }
+
+  impl Drop for Harness {
+    fn drop(&mut self) {
+      // Hanging up the RPC channels ends the listener task; wait for it to
+      // notice before the peer goes away, or it will try to reconnect
+      self.rpc_tx.take();
+      self.coinjoin_tx.take();
+      self.config_tx.take();
+      let _ = self.done_rx.recv_opt();
+    }
+  }
+
+  fn genesis_utxos() -> u64 {
+    UtxoSet::new(Bitcoin, DEFAULT_BLOCKCHAIN_N_FULL_BLOCKS).n_utxos() as u64
+  }
+
+  fn call(rpc_tx: &Sender<RpcRequest>, method: &str) -> JsonResult {
+    let (tx, rx) = channel();
+    let request = Request { method: method.to_string(), params: Positional(vec![]), id: json::Null };
+    rpc_tx.send((request, tx));
+    // As in the RPC listener, a task which dies holding the request is down
+    match rx.recv_opt() {
+      Ok(result) => result,
+      Err(()) => Err(backend_unavailable(None))
+    }
+  }
+
+  // A block whose parent nobody has heard of
+  fn orphan_block() -> Block {
+    Block {
+      header: BlockHeader {
+        version: 1,
+        prev_blockhash: Sha256dHash::from_data(b"no such block"),
+        merkle_root: Sha256dHash::from_data(b""),
+        time: 0,
+        bits: 0x1d00ffff,
+        nonce: 0
+      },
+      txdata: vec![]
+    }
+  }
+
+  #[test]
+  fn initial_sync_from_genesis() {
+    let harness = Harness::start();
+    harness.peer.wait_for("version");
+    match harness.peer.wait_for("getheaders") {
+      message::GetHeaders(gh) => { assert_eq!(gh.locator_hashes.len(), 1); }
+      _ => fail!("wait_for returned the wrong message")
+    }
+    // The peer only knows genesis, so that is all we end up with
+    assert_eq!(harness.rpc_u64("getblockcount"), 0);
+    assert_eq!(harness.rpc_u64("getutxocount"), genesis_utxos());
+  }
+
+  #[test]
+  fn initial_sync_past_genesis() {
+    let harness = Harness::start_with(mainnet_blocks());
+    match harness.peer.wait_for("getdata") {
+      message::GetData(invs) => {
+        let hashes: Vec<Sha256dHash> = invs.iter().map(|inv| inv.hash).collect();
+        let expected: Vec<Sha256dHash> = mainnet_blocks().iter().map(|b| b.bitcoin_hash()).collect();
+        assert_eq!(hashes, expected);
+      }
+      _ => fail!("wait_for returned the wrong message")
+    }
+    // Each block adds its coinbase output
+    assert_eq!(harness.rpc_u64("getblockcount"), 4);
+    assert_eq!(harness.rpc_u64("getutxocount"), genesis_utxos() + 4);
+  }
+
+  #[test]
+  fn reorg_rewinds_stale_blocks() {
+    let harness = Harness::start_with(vec![fork_block()]);
+    assert_eq!(harness.rpc_u64("getblockcount"), 1);
+    assert_eq!(harness.rpc_u64("getutxocount"), genesis_utxos() + 1);
+
+    // The peer switches to the real chain, which has more work, and tells
+    // us about its tip; we don't know its parent, so resync
+    let blocks = mainnet_blocks();
+    harness.peer.rewind(0);
+    for block in blocks.iter() {
+      harness.peer.add_block(block.clone());
+    }
+    harness.peer.send(message::Block(blocks[3].clone()));
+    harness.peer.wait_for("getheaders");
+
+    // The fork block's coinbase is gone, and the real blocks' are there
+    assert_eq!(harness.rpc_u64("getblockcount"), 4);
+    assert_eq!(harness.rpc_u64("getutxocount"), genesis_utxos() + 4);
+  }
+
+  #[test]
+  fn reorg_deeper_than_max_is_refused() {
+    let peer = MockPeer::new(Bitcoin).unwrap();
+    peer.add_block(fork_block());
+    let harness = Harness::connect(peer, |config| { config.max_reorg_depth = 0; });
+    assert_eq!(harness.rpc_u64("getutxocount"), genesis_utxos() + 1);
+
+    let blocks = mainnet_blocks();
+    harness.peer.rewind(0);
+    for block in blocks.iter() {
+      harness.peer.add_block(block.clone());
+    }
+    harness.peer.send(message::Block(blocks[3].clone()));
+    harness.peer.wait_for("getheaders");
+
+    // The headers still switch over, but the UTXO set stays on the fork
+    assert_eq!(harness.rpc_u64("getblockcount"), 4);
+    assert_eq!(harness.rpc_u64("getutxocount"), genesis_utxos() + 1);
+  }
+
+  #[test]
+  fn notfound_mid_sync_is_retried() {
+    let blocks = mainnet_blocks();
+    let missing = blocks[2].bitcoin_hash();
+    let peer = MockPeer::new(Bitcoin).unwrap();
+    for block in blocks.iter() {
+      peer.add_block(block.clone());
+    }
+    peer.withhold(missing);
+    let harness = Harness::connect(peer, |_| {});
+    match harness.peer.wait_for("getdata") {
+      message::GetData(invs) => { assert!(invs.iter().any(|inv| inv.hash == missing)); }
+      _ => fail!("wait_for returned the wrong message")
+    }
+    // The wallet pauses before retrying, which is plenty of time for this
+    harness.peer.release(missing);
+
+    // The retry picks up after the last block the UTXO set took
+    harness.peer.wait_for("getheaders");
+    match harness.peer.wait_for("getdata") {
+      message::GetData(invs) => {
+        let hashes: Vec<Sha256dHash> = invs.iter().map(|inv| inv.hash).collect();
+        assert_eq!(hashes, vec![missing, blocks[3].bitcoin_hash()]);
+      }
+      _ => fail!("wait_for returned the wrong message")
+    }
+    assert_eq!(harness.rpc_u64("getblockcount"), 4);
+    assert_eq!(harness.rpc_u64("getutxocount"), genesis_utxos() + 4);
+  }
+
+  #[test]
+  fn answers_ping_when_idle() {
+    let harness = Harness::start();
+    harness.rpc_u64("getblockcount");
+    harness.peer.send(message::Ping(0xdeadbeef));
+    match harness.peer.wait_for("pong") {
+      message::Pong(nonce) => { assert_eq!(nonce, 0xdeadbeef); }
+      _ => fail!("wait_for returned the wrong message")
+    }
+  }
+
+  #[test]
+  fn reconnects_after_disconnect() {
+    let harness = Harness::start();
+    harness.peer.wait_for_connection();
+    harness.rpc_u64("getblockcount");
+
+    harness.peer.disconnect();
+    harness.peer.wait_for_connection();
+    harness.peer.wait_for("version");
+    // Still alive and still synced
+    assert_eq!(harness.rpc_u64("getblockcount"), 0);
+  }
+
+  #[test]
+  fn orphan_block_triggers_resync() {
+    let harness = Harness::start();
+    harness.peer.wait_for("getheaders");
+    harness.rpc_u64("getblockcount");
+
+    harness.peer.send(message::Block(orphan_block()));
+    harness.peer.wait_for("getheaders");
+    // The orphan is not added
+    assert_eq!(harness.rpc_u64("getblockcount"), 0);
+  }
+
+  #[test]
+  fn inv_is_requested_and_notfound_ignored() {
+    let harness = Harness::start();
+    harness.rpc_u64("getblockcount");
+
+    let hash = Sha256dHash::from_data(b"unknown block");
+    harness.peer.withhold(hash);
+    harness.peer.send(message::Inv(vec![Inventory { inv_type: InvBlock, hash: hash }]));
+    match harness.peer.wait_for("getdata") {
+      message::GetData(invs) => {
+        assert_eq!(invs.len(), 1);
+        assert_eq!(invs[0].hash, hash);
+      }
+      _ => fail!("wait_for returned the wrong message")
+    }
+    // The peer answers `notfound`, which the idle loop should shrug off
+    assert_eq!(harness.rpc_u64("getblockcount"), 0);
+  }
+
+  #[test]
+  fn headers_contradicting_checkpoints_are_rejected() {
+    let blockchain = Blockchain::new(Bitcoin);
+    let mut header = orphan_block().header;
+    header.prev_blockhash = blockchain.genesis_hash();
+
+    let mut checkpoints = TreeMap::new();
+    checkpoints.insert(1u, header.bitcoin_hash());
+    assert_eq!(contradicted_checkpoint(&checkpoints, &blockchain, &header), None);
+    checkpoints.insert(1u, Sha256dHash::from_data(b"another block"));
+    assert_eq!(contradicted_checkpoint(&checkpoints, &blockchain, &header), Some(1));
+    // Checkpoints at other heights say nothing about it
+    checkpoints.remove(&1u);
+    checkpoints.insert(2u, Sha256dHash::from_data(b"another block"));
+    assert_eq!(contradicted_checkpoint(&checkpoints, &blockchain, &header), None);
+  }
+
+  #[test]
+  fn headers_forking_below_checkpoints_are_rejected() {
+    let mut blockchain = Blockchain::new(Bitcoin);
+    let blocks = mainnet_blocks();
+    for block in blocks.iter() {
+      blockchain.add_header(block.header).unwrap();
+    }
+    let mut checkpoints = TreeMap::new();
+    checkpoints.insert(2u, blocks[1].bitcoin_hash());
+
+    // A competitor to block 1 would make a branch without the checkpoint
+    let fork = fork_block().header;
+    assert_eq!(contradicted_checkpoint(&checkpoints, &blockchain, &fork), Some(2));
+    // but the blocks of the checkpointed branch are fine, below it and above
+    assert_eq!(contradicted_checkpoint(&checkpoints, &blockchain, &blocks[0].header), None);
+    assert_eq!(contradicted_checkpoint(&checkpoints, &blockchain, &blocks[2].header), None);
+    assert_eq!(contradicted_checkpoint(&checkpoints, &blockchain, &blocks[3].header), None);
+
+    // Until we have the checkpointed block, we can't tell
+    let mut checkpoints = TreeMap::new();
+    checkpoints.insert(5u, Sha256dHash::from_data(b"block 5"));
+    assert_eq!(contradicted_checkpoint(&checkpoints, &blockchain, &fork), None);
+  }
+
+  #[test]
+  fn scripts_are_skipped_up_to_assume_valid() {
+    let mut blockchain = Blockchain::new(Bitcoin);
+    let blocks = mainnet_blocks();
+    blockchain.add_header(fork_block().header).unwrap();
+    for block in blocks.iter() {
+      blockchain.add_header(block.header).unwrap();
+    }
+
+    let assume_valid_height = best_chain_height(&blockchain, blocks[1].bitcoin_hash());
+    assert_eq!(assume_valid_height, Some(2));
+    assert!(validation_level(1, assume_valid_height) == TxoValidation);
+    assert!(validation_level(2, assume_valid_height) == TxoValidation);
+    assert!(validation_level(3, assume_valid_height) == ScriptValidation);
+
+    // Off our best chain, or unknown, it skips nothing
+    for hash in [fork_block().bitcoin_hash(), Sha256dHash::from_data(b"block 5")].iter() {
+      let assume_valid_height = best_chain_height(&blockchain, *hash);
+      assert_eq!(assume_valid_height, None);
+      assert!(validation_level(1, assume_valid_height) == ScriptValidation);
+    }
+  }
+
+  #[test]
+  fn verifychain_passes_genesis() {
+    let harness = Harness::start();
+    let result = harness.rpc("verifychain").ok().unwrap();
+    assert_eq!(result.find(&"valid".to_string()), Some(&json::Boolean(true)));
+    // The mock peer's chain is just genesis
+    assert_eq!(result.find(&"checked".to_string()), Some(&json::U64(1)));
+    assert_eq!(result.find(&"failure".to_string()), Some(&json::Null));
+  }
+}
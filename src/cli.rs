@@ -0,0 +1,292 @@
+/* The Wizards' Wallet
+ * Written in 2014 by
+ *   Andrew Poelstra <apoelstra@wpsoftware.net>
+ *
+ * To the extent possible under law, the author(s) have dedicated all
+ * copyright and related and neighboring rights to this software to
+ * the public domain worldwide. This software is distributed without
+ * any warranty.
+ *
+ * You should have received a copy of the CC0 Public Domain Dedication
+ * along with this software.
+ * If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+ */
+
+//! # The Wizards' Wallet CLI
+//!
+//! A command-line client for the wallet's JSON-RPC interface. It finds the
+//! RPC server and cookie file for the requested network from the same
+//! configuration file as the wallet itself, converts its arguments to JSON
+//! using the types given by the server's `help` call, and pretty-prints the
+//! result.
+//!
+//! Arguments are positional, unless they are given as `name=value`, in which
+//! case they are sent as named parameters.
+//!
+
+#![crate_name = "wizards-wallet-cli"]
+
+#![comment = "The Wizards' Wallet CLI"]
+#![license = "CC0"]
+
+// Experimental features we need
+#![feature(globs)]
+#![feature(phase)]
+#![feature(macro_rules)]
+
+// Coding conventions
+#![warn(non_uppercase_statics)]
+#![deny(non_camel_case_types)]
+#![deny(non_snake_case)]
+#![deny(unused_mut)]
+#![warn(missing_doc)]
+
+extern crate getopts;
+extern crate serialize;
+
+extern crate bitcoin;
+extern crate openssl;
+extern crate wizards_wallet;
+
+#[cfg(not(test))]
+use std::ascii::StrAsciiExt;
+#[cfg(not(test))]
+use std::collections::TreeMap;
+#[cfg(not(test))]
+use std::io::{BufferedStream, File, IoError, IoResult, OtherIoError};
+#[cfg(not(test))]
+use std::io::net::tcp::TcpStream;
+#[cfg(not(test))]
+use std::os;
+#[cfg(not(test))]
+use serialize::base64::{STANDARD, ToBase64};
+#[cfg(not(test))]
+use serialize::json;
+
+#[cfg(not(test))]
+use getopts::{getopts, optflag, optopt, usage};
+#[cfg(not(test))]
+use openssl::ssl::{SslContext, SslStream, SslVerifyPeer, Sslv23};
+
+#[cfg(not(test))]
+use bitcoin::network::constants::{Bitcoin, BitcoinTestnet};
+
+#[cfg(not(test))]
+use wizards_wallet::rpc_client::{completion_script, convert_params, param_schema};
+#[cfg(not(test))]
+use wizards_wallet::user_data::{NetworkConfig, config_path, read_configuration_or_default};
+
+/// Entry point
+#[cfg(not(test))]
+fn main() {
+  let args = os::args();
+  let program = args[0].clone();
+  let opts = [
+    optopt("c", "config", "read configuration from PATH", "PATH"),
+    optopt("d", "datadir", "look for the cookie file in DIR, as given to the wallet", "DIR"),
+    optopt("n", "network", "network to connect to: bitcoin (default) or testnet", "NETWORK"),
+    optopt("u", "rpcuser", "authenticate as USER rather than with the cookie file", "USER"),
+    optopt("p", "rpcpassword", "password for --rpcuser", "PASSWORD"),
+    optflag("", "completion", "print a bash completion script, generated from `help`"),
+    optflag("h", "help", "print this message")
+  ];
+  let matches = match getopts(args.tail(), opts) {
+    Ok(m) => m,
+    Err(e) => { fail(e.to_string().as_slice()); return; }
+  };
+  let brief = format!("Usage: {} [options] <command> [params...]", program);
+  if matches.opt_present("h") {
+    println!("{}", usage(brief.as_slice(), opts));
+    return;
+  }
+
+  let network = match matches.opt_str("n") {
+    None => Bitcoin,
+    Some(ref s) if s.as_slice() == "bitcoin" => Bitcoin,
+    Some(ref s) if s.as_slice() == "testnet" => BitcoinTestnet,
+    Some(s) => { fail(format!("unknown network `{}`", s).as_slice()); return; }
+  };
+  let path = matches.opt_str("c").map_or(config_path(), |s| Path::new(s));
+  let data_dir = matches.opt_str("d").map(|s| Path::new(s));
+  // Our output may be a script or a JSON result, so the configuration must be
+  // loaded without printing anything to stdout
+  let config = match read_configuration_or_default(&path, data_dir.as_ref()) {
+    Ok(config) => config,
+    Err(e) => { fail(format!("failed to load configuration: {}", e).as_slice()); return; }
+  };
+  let config = match config.move_iter().find(|c| c.network == network) {
+    Some(config) => config,
+    None => { fail(format!("network {} is not configured", network).as_slice()); return; }
+  };
+
+  let auth = match (matches.opt_str("u"), matches.opt_str("p")) {
+    (Some(user), Some(password)) => format!("{}:{}", user, password),
+    (None, None) => {
+      match File::open(&config.rpc_cookie_path).read_to_string() {
+        Ok(cookie) => cookie.as_slice().trim().to_string(),
+        Err(e) => {
+          fail(format!("could not read cookie file {}: {} (is the wallet running?)",
+                       config.rpc_cookie_path.display(), e).as_slice());
+          return;
+        }
+      }
+    }
+    _ => { fail("--rpcuser and --rpcpassword must be given together"); return; }
+  };
+  let auth = auth.as_bytes().to_base64(STANDARD);
+
+  if matches.opt_present("completion") {
+    match call(&config, auth.as_slice(), "help", json::List(vec![])) {
+      Ok(json::Object(help)) => print!("{}", completion_script(&help)),
+      Ok(_) => fail("server gave a malformed `help` response"),
+      Err(e) => fail(e.as_slice())
+    }
+    return;
+  }
+
+  if matches.free.is_empty() {
+    println!("{}", usage(brief.as_slice(), opts));
+    return;
+  }
+  let method = matches.free[0].as_slice();
+  let args = matches.free.slice_from(1);
+
+  // Find the parameter types from the server, so that we know how to
+  // convert each argument
+  let schema = if args.is_empty() {
+    vec![]
+  } else {
+    match call(&config, auth.as_slice(), "help", json::List(vec![])) {
+      Ok(json::Object(help)) => param_schema(&help, method),
+      Ok(_) => { fail("server gave a malformed `help` response"); return; }
+      Err(e) => { fail(e.as_slice()); return; }
+    }
+  };
+  let params = match convert_params(&schema, args) {
+    Ok(params) => params,
+    Err(e) => { fail(e.as_slice()); return; }
+  };
+
+  match call(&config, auth.as_slice(), method, params) {
+    // Print strings bare, so that they can be used directly in scripts
+    Ok(json::String(s)) => println!("{}", s),
+    Ok(json::Null) => {}
+    Ok(result) => println!("{}", result.to_pretty_str()),
+    Err(e) => fail(e.as_slice())
+  }
+}
+
+/// Prints an error and sets a failing exit status
+#[cfg(not(test))]
+fn fail(msg: &str) {
+  let mut stderr = std::io::stderr();
+  let _ = writeln!(stderr, "error: {}", msg);
+  os::set_exit_status(1);
+}
+
+/// Makes an RPC call, returning its result or a printable error
+#[cfg(not(test))]
+fn call(config: &NetworkConfig, auth: &str, method: &str, params: json::Json) -> Result<json::Json, String> {
+  let mut request = TreeMap::new();
+  request.insert("method".to_string(), json::String(method.to_string()));
+  request.insert("params".to_string(), params);
+  request.insert("id".to_string(), json::U64(1));
+  let body = json::Object(request).to_string();
+
+  let response = try!(post(config, auth, body.as_slice()).map_err(|e| e.to_string()));
+  let mut response = match json::from_str(response.as_slice()) {
+    Ok(json::Object(obj)) => obj,
+    Ok(_) => { return Err("server response was not an object".to_string()); }
+    Err(e) => { return Err(format!("could not parse server response: {}", e)); }
+  };
+  match response.pop(&"error".to_string()) {
+    None | Some(json::Null) => Ok(response.pop(&"result".to_string()).unwrap_or(json::Null)),
+    Some(json::Object(error)) => {
+      let mut ret = String::new();
+      match error.find(&"code".to_string()) {
+        Some(code) => { ret.push_str(format!("code {}: ", code).as_slice()); }
+        None => {}
+      }
+      match error.find(&"message".to_string()) {
+        Some(&json::String(ref message)) => { ret.push_str(message.as_slice()); }
+        _ => {}
+      }
+      match error.find(&"data".to_string()) {
+        None | Some(&json::Null) => {}
+        Some(data) => { ret.push_str(format!("\n{}", data.to_pretty_str()).as_slice()); }
+      }
+      Err(ret)
+    }
+    Some(error) => Err(error.to_string())
+  }
+}
+
+/// Sends a request body to the RPC server, speaking TLS if it is configured to
+#[cfg(not(test))]
+fn post(config: &NetworkConfig, auth: &str, body: &str) -> IoResult<String> {
+  let stream = try!(TcpStream::connect(config.rpc_server_addr.as_slice(), config.rpc_server_port));
+  match config.rpc_tls_cert {
+    Some(ref cert) => {
+      let tls_error = |e| IoError {
+        kind: OtherIoError,
+        desc: "failed to set up TLS",
+        detail: Some(format!("{}", e))
+      };
+      // The server's own certificate is the only one we trust
+      let mut ctx = try!(SslContext::new(Sslv23).map_err(|e| tls_error(e)));
+      match ctx.set_CA_file(cert) {
+        Some(e) => { return Err(tls_error(e)); }
+        None => {}
+      }
+      ctx.set_verify(SslVerifyPeer, None);
+      let stream = try!(SslStream::new(&ctx, stream).map_err(|e| tls_error(e)));
+      http_post(stream, auth, body)
+    }
+    None => http_post(stream, auth, body)
+  }
+}
+
+/// Makes an HTTP POST request with Basic auth, returning the response body
+#[cfg(not(test))]
+fn http_post<S: Reader + Writer>(stream: S, auth: &str, body: &str) -> IoResult<String> {
+  let http_error = |detail: String| IoError {
+    kind: OtherIoError,
+    desc: "bad HTTP response from RPC server",
+    detail: Some(detail)
+  };
+
+  let mut stream = BufferedStream::new(stream);
+  try!(stream.write_str(format!("POST / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\
+                                 Authorization: Basic {}\r\nContent-Type: application/json\r\n\
+                                 Content-Length: {}\r\n\r\n", auth, body.len()).as_slice()));
+  try!(stream.write_str(body));
+  try!(stream.flush());
+
+  let status = try!(stream.read_line());
+  let status = status.as_slice().trim_right().to_string();
+  let mut content_length = None;
+  loop {
+    let line = try!(stream.read_line());
+    let line = line.as_slice().trim_right();
+    if line.is_empty() {
+      break;
+    }
+    match line.find(':') {
+      Some(idx) if line.slice_to(idx).trim().eq_ignore_ascii_case("content-length") => {
+        content_length = from_str::<uint>(line.slice_from(idx + 1).trim());
+      }
+      _ => {}
+    }
+  }
+
+  match status.as_slice().split(' ').nth(1) {
+    Some("200") => {}
+    Some("401") => { return Err(http_error("authentication failed".to_string())); }
+    _ => { return Err(http_error(status)); }
+  }
+  let body = match content_length {
+    Some(len) => try!(stream.read_exact(len)),
+    None => try!(stream.read_to_end())
+  };
+  String::from_utf8(body).map_err(|_| http_error("response was not UTF-8".to_string()))
+}
@@ -0,0 +1,333 @@
+/* The Wizards' Wallet
+ * Written in 2014 by
+ *   Andrew Poelstra <apoelstra@wpsoftware.net>
+ *
+ * To the extent possible under law, the author(s) have dedicated all
+ * copyright and related and neighboring rights to this software to
+ * the public domain worldwide. This software is distributed without
+ * any warranty.
+ *
+ * You should have received a copy of the CC0 Public Domain Dedication
+ * along with this software.
+ * If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+ */
+
+//! # Blind Signatures
+//!
+//! RSA blind signatures, used by blinded coinjoin sessions so that participants
+//! can register an output without the server learning which inputs it belongs
+//! to. The participant hashes and blinds a message, the server signs it blind,
+//! and the participant unblinds the result to obtain an ordinary signature.
+//!
+//! Messages are signed with full-domain-hash RSA: the SHA256 hash of the
+//! message is expanded with MGF1 to one bit short of the modulus, so that
+//! signatures cannot be combined multiplicatively to forge new ones.
+
+use std::collections::TreeMap;
+use std::num::{FromPrimitive, One, ToStrRadix, Zero, from_str_radix};
+use std::rand::Rng;
+use serialize::hex::ToHex;
+use serialize::json;
+
+use num::Integer;
+use num::bigint::{BigInt, BigUint, RandBigInt, ToBigInt};
+
+use crypto::digest::Digest;
+use crypto::sha2::Sha256;
+
+/// The RSA public exponent
+static PUBLIC_EXPONENT: uint = 65537;
+
+/// The number of Miller-Rabin rounds used when generating primes
+static MILLER_RABIN_ROUNDS: uint = 40;
+
+/// A public key, against which unblinded signatures can be verified
+#[deriving(Clone, PartialEq, Eq, Show)]
+pub struct PublicKey {
+  /// The modulus
+  pub n: BigUint,
+  /// The public exponent
+  pub e: BigUint
+}
+
+/// A secret key, used to sign blinded messages
+pub struct SecretKey {
+  public: PublicKey,
+  d: BigUint
+}
+
+impl SecretKey {
+  /// Generates a new key with a modulus of `bits` bits
+  pub fn generate<R: Rng>(rng: &mut R, bits: uint) -> SecretKey {
+    let one: BigUint = One::one();
+    let e: BigUint = FromPrimitive::from_uint(PUBLIC_EXPONENT).unwrap();
+    loop {
+      let p = random_prime(rng, bits / 2);
+      let q = random_prime(rng, bits - bits / 2);
+      if p == q {
+        continue;
+      }
+      let phi = (p - one) * (q - one);
+      match mod_inverse(&e, &phi) {
+        Some(d) => {
+          return SecretKey {
+            public: PublicKey { n: p * q, e: e },
+            d: d
+          };
+        }
+        None => {}
+      }
+    }
+  }
+
+  /// Accessor for the public half of the key
+  pub fn public_key<'a>(&'a self) -> &'a PublicKey {
+    &self.public
+  }
+
+  /// Signs a blinded message. Nothing is learned about the underlying message.
+  pub fn sign_blinded(&self, blinded: &BigUint) -> BigUint {
+    mod_pow(blinded, &self.d, &self.public.n)
+  }
+}
+
+impl PublicKey {
+  /// Blinds `msg` using the blinding factor `r`, which must be coprime to
+  /// the modulus and kept secret until the signature is unblinded.
+  pub fn blind(&self, msg: &[u8], r: &BigUint) -> BigUint {
+    (full_domain_hash(msg, &self.n) * mod_pow(r, &self.e, &self.n)) % self.n
+  }
+
+  /// Removes the blinding factor `r` from a signature on a blinded message,
+  /// or returns None if `r` has no inverse modulo the modulus.
+  pub fn unblind(&self, sig: &BigUint, r: &BigUint) -> Option<BigUint> {
+    mod_inverse(r, &self.n).map(|r_inv| (*sig * r_inv) % self.n)
+  }
+
+  /// Checks an unblinded signature on `msg`
+  pub fn verify(&self, msg: &[u8], sig: &BigUint) -> bool {
+    *sig < self.n && mod_pow(sig, &self.e, &self.n) == full_domain_hash(msg, &self.n)
+  }
+}
+
+impl json::ToJson for PublicKey {
+  fn to_json(&self) -> json::Json {
+    let mut obj = TreeMap::new();
+    obj.insert("n".to_string(), json::String(to_hex(&self.n)));
+    obj.insert("e".to_string(), json::String(to_hex(&self.e)));
+    json::Object(obj)
+  }
+}
+
+/// Encodes a number as a hex string
+pub fn to_hex(n: &BigUint) -> String {
+  n.to_str_radix(16)
+}
+
+/// Decodes a number from a hex string
+pub fn from_hex(s: &str) -> Option<BigUint> {
+  from_str_radix(s, 16)
+}
+
+/// Hashes a message to an integer with one bit fewer than `n`, by expanding
+/// its SHA256 hash with MGF1
+fn full_domain_hash(msg: &[u8], n: &BigUint) -> BigUint {
+  let n_bits = n.bits();
+  let mut seed = [0u8, ..32];
+  let mut hasher = Sha256::new();
+  hasher.input(msg);
+  hasher.result(seed);
+
+  let mut output = Vec::with_capacity((n_bits + 7) / 8 + 32);
+  let mut counter = 0u32;
+  while output.len() < (n_bits + 7) / 8 {
+    let mut block = [0u8, ..32];
+    let mut hasher = Sha256::new();
+    hasher.input(seed);
+    hasher.input([(counter >> 24) as u8, (counter >> 16) as u8, (counter >> 8) as u8, counter as u8]);
+    hasher.result(block);
+    output.push_all(block);
+    counter += 1;
+  }
+  output.truncate((n_bits + 7) / 8);
+
+  // Clear the bits above the top bit of `n`, and its top bit too
+  let one: BigUint = One::one();
+  let hash: BigUint = from_hex(output.as_slice().to_hex().as_slice()).unwrap();
+  hash & ((one << (n_bits - 1)) - one)
+}
+
+/// Computes `base^exp mod modulus` by square-and-multiply
+fn mod_pow(base: &BigUint, exp: &BigUint, modulus: &BigUint) -> BigUint {
+  let mut result: BigUint = One::one();
+  let mut base = *base % *modulus;
+  let mut exp = exp.clone();
+  while !exp.is_zero() {
+    if exp.is_odd() {
+      result = (result * base) % *modulus;
+    }
+    base = (base * base) % *modulus;
+    exp = exp >> 1;
+  }
+  result
+}
+
+/// Computes the inverse of `a` modulo `m` by the extended Euclidean algorithm,
+/// or returns None if they are not coprime
+fn mod_inverse(a: &BigUint, m: &BigUint) -> Option<BigUint> {
+  let m = m.to_bigint().unwrap();
+  let mut old_r = a.to_bigint().unwrap();
+  let mut r = m.clone();
+  let mut old_s: BigInt = One::one();
+  let mut s: BigInt = Zero::zero();
+  while !r.is_zero() {
+    let q = old_r / r;
+    let new_r = old_r - q * r;
+    old_r = r;
+    r = new_r;
+    let new_s = old_s - q * s;
+    old_s = s;
+    s = new_s;
+  }
+  if old_r != One::one() {
+    return None;
+  }
+  (((old_s % m) + m) % m).to_biguint()
+}
+
+/// Generates a random prime of exactly `bits` bits
+fn random_prime<R: Rng>(rng: &mut R, bits: uint) -> BigUint {
+  let one: BigUint = One::one();
+  loop {
+    // Set the top two bits, so that the product of two such primes has
+    // the full length, and the bottom bit, so that the candidate is odd
+    let candidate = rng.gen_biguint(bits) | (one << (bits - 1)) | (one << (bits - 2)) | one;
+    if is_probable_prime(rng, &candidate) {
+      return candidate;
+    }
+  }
+}
+
+/// Miller-Rabin primality test
+fn is_probable_prime<R: Rng>(rng: &mut R, n: &BigUint) -> bool {
+  let one: BigUint = One::one();
+  let two = one + one;
+  let n_minus_one = *n - one;
+
+  // Write n - 1 as d * 2^s with d odd
+  let mut d = n_minus_one.clone();
+  let mut s = 0u;
+  while d.is_even() {
+    d = d >> 1;
+    s += 1;
+  }
+
+  'witness: for _ in range(0, MILLER_RABIN_ROUNDS) {
+    let a = rng.gen_biguint_range(&two, &n_minus_one);
+    let mut x = mod_pow(&a, &d, n);
+    if x == one || x == n_minus_one {
+      continue;
+    }
+    for _ in range(1, s) {
+      x = (x * x) % *n;
+      if x == n_minus_one {
+        continue 'witness;
+      }
+    }
+    return false;
+  }
+  true
+}
+
+#[cfg(test)]
+mod tests {
+  use std::num::{FromPrimitive, ToPrimitive};
+  use std::rand::task_rng;
+  use num::bigint::BigUint;
+
+  use super::{PublicKey, SecretKey, from_hex, full_domain_hash, is_probable_prime,
+              mod_inverse, mod_pow, random_prime};
+
+  fn n(x: u64) -> BigUint { FromPrimitive::from_u64(x).unwrap() }
+
+  // Mersenne primes 2^127 - 1 and 2^89 - 1, which give a small key
+  fn test_key() -> SecretKey {
+    SecretKey {
+      public: PublicKey {
+        n: from_hex("ffffffffffffffffffffff7ffffffffe0000000000000000000001").unwrap(),
+        e: n(65537)
+      },
+      d: from_hex("802a7fd5802a7fd5802a7f5555aaaa535500aaff5500aaff5500ad").unwrap()
+    }
+  }
+
+  #[test]
+  fn mod_pow_known_answers() {
+    assert_eq!(mod_pow(&n(4), &n(13), &n(497)), n(445));
+    assert_eq!(mod_pow(&n(2), &n(1000000000000000000), &n(1000000007)), n(719476260));
+    assert_eq!(mod_pow(&n(65), &n(17), &n(3233)), n(2790));
+    assert_eq!(mod_pow(&n(2790), &n(2753), &n(3233)), n(65));
+    assert_eq!(mod_pow(&n(12345), &n(0), &n(3233)), n(1));
+  }
+
+  #[test]
+  fn mod_inverse_known_answers() {
+    assert_eq!(mod_inverse(&n(17), &n(3120)), Some(n(2753)));
+    assert_eq!(mod_inverse(&n(3), &n(11)), Some(n(4)));
+    assert_eq!(mod_inverse(&n(123456789), &n(1000000007)), Some(n(18633540)));
+    assert_eq!(mod_inverse(&n(6), &n(9)), None);
+  }
+
+  #[test]
+  fn is_probable_prime_known_answers() {
+    let mut rng = task_rng();
+    for &p in [7919u64, 104729, 2147483647, 2305843009213693951].iter() {
+      assert!(is_probable_prime(&mut rng, &n(p)));
+    }
+    // Including Carmichael numbers, which fool the Fermat test
+    for &c in [561u64, 41041, 825265, 7919 * 104729, 2147483647 * 2147483647].iter() {
+      assert!(!is_probable_prime(&mut rng, &n(c)));
+    }
+    let m127 = from_hex("7fffffffffffffffffffffffffffffff").unwrap();
+    assert!(is_probable_prime(&mut rng, &m127));
+    assert!(!is_probable_prime(&mut rng, &(m127 + n(2))));
+  }
+
+  #[test]
+  fn random_prime_has_exact_length() {
+    let mut rng = task_rng();
+    for _ in range(0u, 10) {
+      let p = random_prime(&mut rng, 32);
+      assert_eq!(p.bits(), 32);
+      assert!(p >= n(0xc0000000));
+      // Small enough to check by trial division
+      let p = p.to_u64().unwrap();
+      let mut d = 2;
+      while d * d <= p {
+        assert!(p % d != 0);
+        d += 1;
+      }
+    }
+  }
+
+  #[test]
+  fn full_domain_hash_known_answer() {
+    let key = test_key();
+    let hash = full_domain_hash(b"wizards", &key.public.n);
+    assert_eq!(hash, from_hex("776ec1232af20234c66016420f586e7a35eef04afaab4b919068a1").unwrap());
+    assert!(hash.bits() < key.public.n.bits());
+  }
+
+  #[test]
+  fn blind_signature_round_trip() {
+    let key = test_key();
+    let public = key.public_key();
+    let r = n(1234567);
+    let blinded = public.blind(b"wizards", &r);
+    let sig = public.unblind(&key.sign_blinded(&blinded), &r).unwrap();
+    assert_eq!(sig, from_hex("c4b9235817d14c5de326208d47d392478150e3028ecea1046752e8").unwrap());
+    assert!(public.verify(b"wizards", &sig));
+    assert!(!public.verify(b"wizard", &sig));
+    assert!(!public.verify(b"wizards", &(sig + public.n)));
+  }
+}
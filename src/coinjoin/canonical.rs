@@ -0,0 +1,382 @@
+/* The Wizards' Wallet
+ * Written in 2014 by
+ *   Andrew Poelstra <apoelstra@wpsoftware.net>
+ *
+ * To the extent possible under law, the author(s) have dedicated all
+ * copyright and related and neighboring rights to this software to
+ * the public domain worldwide. This software is distributed without
+ * any warranty.
+ *
+ * You should have received a copy of the CC0 Public Domain Dedication
+ * along with this software.
+ * If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+ */
+
+//! # Canonical Encodings
+//!
+//! Normalization of scriptSigs submitted to a coinjoin. Any scriptSig which
+//! is not push-only with minimal pushes, which spends an output of a type we
+//! don't know the signatures of, or whose signatures are not strict DER, is
+//! rejected; signatures with a high S value are replaced by their low-S
+//! equivalent. This leaves third parties no freedom to change the txid of the
+//! final transaction once it is broadcast.
+
+use std::num::{FromPrimitive, ToStrRadix, Zero, from_str_radix};
+use serialize::hex::FromHex;
+
+use num::bigint::BigUint;
+
+use bitcoin::blockdata::script::Script;
+use bitcoin::network::serialize::{deserialize, serialize};
+
+/// The order of the secp256k1 group, in hex
+static CURVE_ORDER: &'static str =
+  "fffffffffffffffffffffffffffffffebaaedce6af48a03bbfd25e8cd0364141";
+
+/// Opcode for a push whose length is given in the following byte
+static OP_PUSHDATA1: u8 = 0x4c;
+/// Opcode for a push whose length is given in the following two bytes
+static OP_PUSHDATA2: u8 = 0x4d;
+/// Opcode for a push whose length is given in the following four bytes
+static OP_PUSHDATA4: u8 = 0x4e;
+/// Opcode pushing the number -1
+static OP_1NEGATE: u8 = 0x4f;
+/// Opcode pushing the number 1; those up to 16 follow it
+static OP_1: u8 = 0x51;
+/// Opcode pushing the number 16
+static OP_16: u8 = 0x60;
+/// Script number encoding of -1
+static NEGATIVE_ONE: u8 = 0x81;
+
+/// Returns a canonical version of `script_sig`, which spends an output with
+/// `script_pubkey`, with every signature in low-S form. Returns None if it
+/// is not push-only, uses non-minimal pushes, or has a signature which is
+/// not strict DER where the output's template expects one.
+pub fn canonicalize_script_sig(script_sig: &Script, script_pubkey: &Script) -> Option<Script> {
+  let pushes = match parse_pushes(script_bytes(script_sig).as_slice()) {
+    Some(pushes) => pushes,
+    None => { return None; }
+  };
+  let sig_indices = match signature_indices(script_bytes(script_pubkey).as_slice(),
+                                            pushes.as_slice()) {
+    Some(indices) => indices,
+    None => { return None; }
+  };
+
+  let mut ret = vec![];
+  for (i, push) in pushes.move_iter().enumerate() {
+    let data = if sig_indices.contains(&i) {
+      match low_s_signature(push.as_slice()) {
+        Some(sig) => sig,
+        None => { return None; }
+      }
+    } else {
+      push
+    };
+    write_push(&mut ret, data.as_slice());
+  }
+  Some(script_from_bytes(ret))
+}
+
+/// Works out which of the pushes of a scriptSig spending `script_pubkey`
+/// are signatures, or returns None if they do not fit a standard template
+fn signature_indices(script_pubkey: &[u8], pushes: &[Vec<u8>]) -> Option<Vec<uint>> {
+  // Pay-to-script-hash: the last push is the redeem script, which the
+  // others must satisfy
+  if script_pubkey.len() == 23 && script_pubkey[0] == 0xa9 && script_pubkey[1] == 0x14 &&
+     script_pubkey[22] == 0x87 {
+    return match pushes.last() {
+      Some(redeem_script) => template_signature_indices(redeem_script.as_slice(),
+                                                        pushes.slice_to(pushes.len() - 1)),
+      None => None
+    };
+  }
+  template_signature_indices(script_pubkey, pushes)
+}
+
+/// Works out which of `pushes` are signatures for the given pay-to-pubkey-hash,
+/// pay-to-pubkey or multisig script, or returns None if the script is none
+/// of these or the pushes do not fit it
+fn template_signature_indices(script: &[u8], pushes: &[Vec<u8>]) -> Option<Vec<uint>> {
+  let len = script.len();
+  // Pay-to-pubkey-hash: signature then public key
+  if len == 25 && script[0] == 0x76 && script[1] == 0xa9 && script[2] == 0x14 &&
+     script[23] == 0x88 && script[24] == 0xac {
+    return if pushes.len() == 2 { Some(vec![0]) } else { None };
+  }
+  // Pay-to-pubkey: just the signature
+  if (len == 35 || len == 67) && script[0] as uint == len - 2 && script[len - 1] == 0xac {
+    return if pushes.len() == 1 { Some(vec![0]) } else { None };
+  }
+  // Multisig: an empty dummy push, then one signature for each key required
+  if len >= 3 && script[len - 1] == 0xae && is_small_int(script[0]) &&
+     is_small_int(script[len - 2]) {
+    let n_required = (script[0] - OP_1 + 1) as uint;
+    let n_keys = (script[len - 2] - OP_1 + 1) as uint;
+    let keys = match parse_pushes(script.slice(1, len - 2)) {
+      Some(keys) => keys,
+      None => { return None; }
+    };
+    if keys.len() != n_keys || n_required > n_keys ||
+       keys.iter().any(|key| key.len() != 33 && key.len() != 65) {
+      return None;
+    }
+    return if pushes.len() == n_required + 1 && pushes[0].is_empty() {
+      Some(range(1, pushes.len()).collect())
+    } else {
+      None
+    };
+  }
+  None
+}
+
+/// Whether an opcode pushes one of the numbers 1 to 16
+fn is_small_int(opcode: u8) -> bool {
+  opcode >= OP_1 && opcode <= OP_16
+}
+
+/// Splits a script into the data of its pushes, or returns None if it
+/// contains anything other than minimally-encoded pushes. Small-integer
+/// opcodes give the data they push, as a script number.
+fn parse_pushes(script: &[u8]) -> Option<Vec<Vec<u8>>> {
+  let mut ret = vec![];
+  let mut i = 0;
+  while i < script.len() {
+    let opcode = script[i];
+    i += 1;
+    if opcode == OP_1NEGATE {
+      ret.push(vec![NEGATIVE_ONE]);
+      continue;
+    }
+    if is_small_int(opcode) {
+      ret.push(vec![opcode - OP_1 + 1]);
+      continue;
+    }
+    let (len, min_len) = if opcode < OP_PUSHDATA1 {
+      (opcode as uint, 0)
+    } else if opcode == OP_PUSHDATA1 && i + 1 <= script.len() {
+      i += 1;
+      (script[i - 1] as uint, OP_PUSHDATA1 as uint)
+    } else if opcode == OP_PUSHDATA2 && i + 2 <= script.len() {
+      i += 2;
+      (script[i - 2] as uint | script[i - 1] as uint << 8, 0x100)
+    } else if opcode == OP_PUSHDATA4 && i + 4 <= script.len() {
+      i += 4;
+      (script[i - 4] as uint | script[i - 3] as uint << 8 |
+       script[i - 2] as uint << 16 | script[i - 1] as uint << 24, 0x10000)
+    } else {
+      return None;
+    };
+    // A push which could have used a shorter opcode is not minimal
+    if len < min_len || i + len > script.len() {
+      return None;
+    }
+    let data = script.slice(i, i + len);
+    if len == 1 && (data[0] == NEGATIVE_ONE || (data[0] >= 1 && data[0] <= 16)) {
+      return None;
+    }
+    ret.push(data.to_vec());
+    i += len;
+  }
+  Some(ret)
+}
+
+/// Appends a minimally-encoded push of `data` to `script`
+fn write_push(script: &mut Vec<u8>, data: &[u8]) {
+  let len = data.len();
+  if len == 1 && data[0] == NEGATIVE_ONE {
+    script.push(OP_1NEGATE);
+    return;
+  }
+  if len == 1 && data[0] >= 1 && data[0] <= 16 {
+    script.push(OP_1 + data[0] - 1);
+    return;
+  }
+  if len < OP_PUSHDATA1 as uint {
+    script.push(len as u8);
+  } else if len < 0x100 {
+    script.push(OP_PUSHDATA1);
+    script.push(len as u8);
+  } else if len < 0x10000 {
+    script.push(OP_PUSHDATA2);
+    script.push(len as u8);
+    script.push((len >> 8) as u8);
+  } else {
+    script.push(OP_PUSHDATA4);
+    script.push(len as u8);
+    script.push((len >> 8) as u8);
+    script.push((len >> 16) as u8);
+    script.push((len >> 24) as u8);
+  }
+  script.push_all(data);
+}
+
+/// Given a strict-DER signature followed by a sighash byte, returns the same
+/// signature with S replaced by `n - S` if it is more than half the curve order
+fn low_s_signature(sig: &[u8]) -> Option<Vec<u8>> {
+  let (r, s, sighash) = match parse_der(sig) {
+    Some(parts) => parts,
+    None => { return None; }
+  };
+
+  let order: BigUint = from_str_radix(CURVE_ORDER, 16).unwrap();
+  let s_num = s.iter().fold(Zero::zero(), |acc: BigUint, &b| {
+    (acc << 8) + FromPrimitive::from_u8(b).unwrap()
+  });
+  if s_num.is_zero() || s_num >= order {
+    return None;
+  }
+  let s_bytes = if s_num > order >> 1 {
+    let s_low = order - s_num;
+    let mut hex = s_low.to_str_radix(16);
+    if hex.len() % 2 == 1 {
+      hex.unshift_char('0');
+    }
+    hex.as_slice().from_hex().unwrap()
+  } else {
+    // Strip the sign padding; it is put back below
+    s.iter().skip_while(|&&b| b == 0).map(|&b| b).collect()
+  };
+  let r_bytes: Vec<u8> = r.iter().skip_while(|&&b| b == 0).map(|&b| b).collect();
+
+  let mut ret = vec![0x30, 0];
+  write_der_integer(&mut ret, r_bytes.as_slice());
+  write_der_integer(&mut ret, s_bytes.as_slice());
+  *ret.get_mut(1) = (ret.len() - 2) as u8;
+  ret.push(sighash);
+  Some(ret)
+}
+
+/// Splits a strict-DER signature followed by a sighash byte into R, S and the
+/// sighash byte, following the rules of BIP66
+fn parse_der<'a>(sig: &'a [u8]) -> Option<(&'a [u8], &'a [u8], u8)> {
+  let len = sig.len();
+  if len < 9 || len > 73 || sig[0] != 0x30 || sig[1] as uint != len - 3 {
+    return None;
+  }
+  let r_len = sig[3] as uint;
+  if sig[2] != 0x02 || r_len == 0 || 5 + r_len >= len {
+    return None;
+  }
+  let s_len = sig[5 + r_len] as uint;
+  if sig[4 + r_len] != 0x02 || s_len == 0 || r_len + s_len + 7 != len {
+    return None;
+  }
+  let r = sig.slice(4, 4 + r_len);
+  let s = sig.slice(6 + r_len, 6 + r_len + s_len);
+  if !is_der_integer(r) || !is_der_integer(s) {
+    return None;
+  }
+  Some((r, s, sig[len - 1]))
+}
+
+/// Checks that a DER integer is positive and has no unnecessary padding
+fn is_der_integer(n: &[u8]) -> bool {
+  n[0] & 0x80 == 0 && !(n.len() > 1 && n[0] == 0 && n[1] & 0x80 == 0)
+}
+
+/// Appends a positive DER integer, given its big-endian bytes without padding
+fn write_der_integer(out: &mut Vec<u8>, n: &[u8]) {
+  let pad = n.len() == 0 || n[0] & 0x80 != 0;
+  out.push(0x02);
+  out.push((n.len() + if pad { 1 } else { 0 }) as u8);
+  if pad {
+    out.push(0);
+  }
+  out.push_all(n);
+}
+
+/// Returns the raw bytes of a script
+fn script_bytes(script: &Script) -> Vec<u8> {
+  // Scripts are consensus-encoded exactly as byte vectors are
+  deserialize(serialize(script).unwrap()).unwrap()
+}
+
+/// Builds a script from its raw bytes
+fn script_from_bytes(data: Vec<u8>) -> Script {
+  deserialize(serialize(&data).unwrap()).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+  use serialize::hex::FromHex;
+
+  use bitcoin::blockdata::script::Script;
+
+  use super::{canonicalize_script_sig, parse_pushes, script_from_bytes};
+
+  static HIGH_S_SIG: &'static str =
+    "304502204e45e16932b8af514961a1d3a1a25fdf3f4f7732e9d624c6c61548ab5fb8cd41\
+     022100e7eadd137135f821b79f5b5322ed6f6137921779f39c5a19b7b03ce459a9243801";
+  static LOW_S_SIG: &'static str =
+    "304402204e45e16932b8af514961a1d3a1a25fdf3f4f7732e9d624c6c61548ab5fb8cd41\
+     0220181522ec8eca07de4860a4acdd12909d831cc56cbbac4622082221a8768d1d0901";
+  static PUBKEY: &'static str =
+    "0279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798";
+  static P2PKH: &'static str = "76a914000102030405060708090a0b0c0d0e0f1011121388ac";
+
+  fn script(hex: &str) -> Script {
+    script_from_bytes(hex.from_hex().unwrap())
+  }
+
+  fn push(hex: &str) -> String {
+    format!("{:02x}{}", hex.len() / 2, hex)
+  }
+
+  #[test]
+  fn high_s_signatures_are_made_low() {
+    let pubkey = script(P2PKH);
+    let high = script(format!("{}{}", push(HIGH_S_SIG), push(PUBKEY)).as_slice());
+    let low = script(format!("{}{}", push(LOW_S_SIG), push(PUBKEY)).as_slice());
+    assert_eq!(canonicalize_script_sig(&high, &pubkey), Some(low.clone()));
+    assert_eq!(canonicalize_script_sig(&low, &pubkey), Some(low.clone()));
+  }
+
+  #[test]
+  fn signatures_are_found_by_template() {
+    // 1-of-1 multisig, spent directly and through P2SH
+    let multisig = format!("51{}51ae", push(PUBKEY));
+    let high = format!("00{}", push(HIGH_S_SIG));
+    let low = format!("00{}", push(LOW_S_SIG));
+    assert_eq!(canonicalize_script_sig(&script(high.as_slice()), &script(multisig.as_slice())),
+               Some(script(low.as_slice())));
+
+    let p2sh = script("a914000102030405060708090a0b0c0d0e0f1011121387");
+    let high = format!("{}{}", high, push(multisig.as_slice()));
+    let low = format!("{}{}", low, push(multisig.as_slice()));
+    assert_eq!(canonicalize_script_sig(&script(high.as_slice()), &p2sh),
+               Some(script(low.as_slice())));
+
+    // A push starting with 0x30 where the template has no signature is left alone
+    let not_sig = format!("{}{}", push(LOW_S_SIG), push("3000"));
+    assert_eq!(canonicalize_script_sig(&script(not_sig.as_slice()), &script(P2PKH)),
+               Some(script(not_sig.as_slice())));
+    // and a signature where there should be one must be strict DER
+    let bad_sig = format!("{}{}", push("3000"), push(PUBKEY));
+    assert_eq!(canonicalize_script_sig(&script(bad_sig.as_slice()), &script(P2PKH)), None);
+    // Unknown templates are rejected
+    let unknown = format!("{}{}", push(LOW_S_SIG), push(PUBKEY));
+    assert_eq!(canonicalize_script_sig(&script(unknown.as_slice()), &script("6a")), None);
+  }
+
+  #[test]
+  fn small_integers_are_pushes() {
+    assert_eq!(parse_pushes("004f515260".from_hex().unwrap().as_slice()),
+               Some(vec![vec![], vec![0x81], vec![1], vec![2], vec![16]]));
+    assert_eq!(parse_pushes("0111".from_hex().unwrap().as_slice()), Some(vec![vec![0x11]]));
+    // Anything else is not a push
+    assert_eq!(parse_pushes("61".from_hex().unwrap().as_slice()), None);
+  }
+
+  #[test]
+  fn non_minimal_pushes_are_rejected() {
+    for hex in ["0101", "0110", "0181", "4c0100", "4d0100aa", "4e01000000aa"].iter() {
+      assert_eq!(parse_pushes(hex.from_hex().unwrap().as_slice()), None);
+    }
+    let mut long = vec![0x4c, 0x4b];
+    long.grow(0x4b, &0);
+    assert_eq!(parse_pushes(long.as_slice()), None);
+    // Truncated pushes too
+    assert_eq!(parse_pushes("02aa".from_hex().unwrap().as_slice()), None);
+  }
+}
@@ -0,0 +1,148 @@
+/* The Wizards' Wallet
+ * Written in 2014 by
+ *   Andrew Poelstra <apoelstra@wpsoftware.net>
+ *
+ * To the extent possible under law, the author(s) have dedicated all
+ * copyright and related and neighboring rights to this software to
+ * the public domain worldwide. This software is distributed without
+ * any warranty.
+ *
+ * You should have received a copy of the CC0 Public Domain Dedication
+ * along with this software.
+ * If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+ */
+
+//! # Public Coinjoin Listener
+//!
+//! A JSON-RPC listener for untrusted coinjoin participants, separate from the
+//! main RPC server. It only passes on participant-facing calls, caps the size
+//! of requests and limits the number of requests from each IP address.
+
+use std::collections::HashMap;
+use std::io::{Acceptor, BufferedStream, IoResult, Listener as IoListener};
+use std::io::net::ip::IpAddr;
+use std::io::net::tcp::{TcpListener, TcpStream};
+use std::str;
+use std::sync::{Arc, Mutex};
+use serialize::json;
+use time::precise_time_ns;
+
+use jsonrpc::error::{standard_error, ParseError};
+
+use http_request::{BAD_REQUEST, ENTITY_TOO_LARGE, METHOD_NOT_ALLOWED, NO_CONTENT, OK,
+                   READ_TIMEOUT_MS, TOO_MANY_REQUESTS, read_head, write_response};
+use rpc_server::{RpcRequest, dispatch_body, is_public_call, response_json};
+
+/// Length of the rate-limiting window, in nanoseconds
+static RATE_LIMIT_WINDOW_NS: u64 = 60 * 1000 * 1000 * 1000;
+
+/// The public coinjoin listener
+#[deriving(Clone)]
+pub struct Listener {
+  addr: String,
+  port: u16,
+  max_request_size: uint,
+  rate_limit: uint,
+  // Per-IP start of the current window and the number of requests made in it
+  rate_state: Arc<Mutex<HashMap<IpAddr, (u64, uint)>>>,
+  rpc_tx: Sender<RpcRequest>
+}
+
+impl Listener {
+  /// Creates a new listener, returning it along with a receiver on which
+  /// participant requests will be passed on
+  pub fn new(addr: &str, port: u16, max_request_size: uint, rate_limit: uint)
+             -> (Listener, Receiver<RpcRequest>) {
+    let (tx, rx) = channel();
+    (Listener {
+      addr: addr.to_string(),
+      port: port,
+      max_request_size: max_request_size,
+      rate_limit: rate_limit,
+      rate_state: Arc::new(Mutex::new(HashMap::new())),
+      rpc_tx: tx
+    }, rx)
+  }
+
+  /// Accepts connections until an error occurs, handling each in its own task
+  pub fn serve_forever(&self) -> IoResult<()> {
+    let mut acceptor = try!(TcpListener::bind(self.addr.as_slice(), self.port).listen());
+    loop {
+      let mut stream = try!(acceptor.accept());
+      stream.set_read_timeout(Some(READ_TIMEOUT_MS));
+      let listener = self.clone();
+      spawn(proc() { listener.handle_connection(stream); });
+    }
+  }
+
+  // Records a request from `ip`, returning whether it is within the rate limit
+  fn check_rate(&self, ip: IpAddr) -> bool {
+    let now = precise_time_ns();
+    let mut state = self.rate_state.lock();
+    // Forget anybody whose window has passed, so the map doesn't grow forever
+    let stale: Vec<IpAddr> = state.iter()
+                                  .filter(|&(_, &(start, _))| now - start > RATE_LIMIT_WINDOW_NS)
+                                  .map(|(ip, _)| *ip).collect();
+    for ip in stale.iter() {
+      state.remove(ip);
+    }
+    let entry = state.find_or_insert(ip, (now, 0));
+    let (_, ref mut count) = *entry;
+    *count += 1;
+    *count <= self.rate_limit
+  }
+
+  // Reads a single request from the stream, and writes the response
+  fn handle_connection(&self, mut stream: TcpStream) {
+    // Anybody we can't identify can't be rate-limited, so is refused
+    let within_rate = match stream.peer_name() {
+      Ok(addr) => self.check_rate(addr.ip),
+      Err(_) => false
+    };
+    let mut stream = BufferedStream::new(stream);
+    if !within_rate {
+      write_response(&mut stream, TOO_MANY_REQUESTS, [], "");
+      return;
+    }
+
+    let head = match read_head(&mut stream) {
+      Ok(head) => head,
+      Err(status) => { write_response(&mut stream, status, [], ""); return; }
+    };
+    if head.method.as_slice() != "POST" {
+      write_response(&mut stream, METHOD_NOT_ALLOWED, [], "");
+      return;
+    }
+    // Refuse oversized requests before reading a byte of them
+    let content_length = match head.content_length() {
+      Ok(len) => len,
+      Err(status) => { write_response(&mut stream, status, [], ""); return; }
+    };
+    if content_length > self.max_request_size {
+      write_response(&mut stream, ENTITY_TOO_LARGE, [], "");
+      return;
+    }
+    let body = match stream.read_exact(content_length) {
+      Ok(body) => body,
+      Err(_) => { write_response(&mut stream, BAD_REQUEST, [], ""); return; }
+    };
+
+    // Check methods here too, so that the network task never sees anything else.
+    // Each request counts once against the rate limit, so batches can only
+    // hold one.
+    let response = match str::from_utf8(body.as_slice()) {
+      Some(body) => dispatch_body(body, 1, |method| is_public_call(method), &self.rpc_tx),
+      None => {
+        Some(response_json(Err(standard_error(ParseError, Some(json::String("request was not UTF-8".to_string())))),
+                           json::Null))
+      }
+    };
+    match response {
+      Some(response) => {
+        write_response(&mut stream, OK, [("Content-Type", "application/json")],
+                       response.to_string().as_slice());
+      }
+      None => { write_response(&mut stream, NO_CONTENT, [], ""); }
+    }
+  }
+}
@@ -17,33 +17,57 @@
 //! Functions and data to join transactions and to manage a centralized
 //! coinjoin server.
 
+use std::collections::TreeMap;
+use serialize::json;
+use serialize::json::ToJson;
+
 use bitcoin::util::hash::Sha256dHash;
 use bitcoin::blockdata::script::Script;
 
-use self::server::SessionState;
+use self::server::{SessionMode, SessionState};
 
+pub mod blind;
+pub mod canonical;
+pub mod listener;
 pub mod server;
 
 /// A Coinjoin-related error
 #[deriving(Clone, PartialEq, Eq, Show)]
 pub enum CoinjoinError {
+  /// No key is ready yet to issue a blinded session's output tokens
+  BlindKeyUnavailable,
   /// Tx had an input which already appears in the join
   DuplicateInput(Sha256dHash, uint),
-  /// Session is in the wrong state for this action (actual, expected)
+  /// An output with this script was already registered
+  DuplicateOutput(Script),
+  /// Session is in the wrong state for this action (expected, actual)
   IncorrectState(SessionState, SessionState),
-  /// Tx total input value exceeds the total output value -- these should match,
-  /// and fees be added using the donation address
+  /// Tx total input value exceeds the total output value -- unless the session
+  /// allows implicit miner fees these should match, and fees be added using the
+  /// donation address
   InputsExceedOutputs(u64, u64),
+  /// An input of the join was spent by a transaction outside of it
+  InputSpent(Sha256dHash, uint),
   /// Not enough fee was sent to the donation address (received, expected)
   InsufficientFee(u64, u64),
+  /// Not enough value was left unassigned to pay the miner fee (received, expected)
+  InsufficientMinerFee(u64, u64),
+  /// Output token signature did not verify
+  InvalidToken,
   /// Signed TX did not actually introduce new signed inputs
   NoNewSignedInputs,
+  /// Signed tx had an input whose scriptSig was not canonically encoded
+  NonCanonicalScriptSig(Sha256dHash, uint),
   /// Tx had a nonzero locktime
   NonZeroLocktime(uint),
   /// Tx had no output of the target size (target in sat)
   NoTargetOutput(u64),
   /// Tx total output value exceed the total input value
   OutputsExceedInputs(u64, u64),
+  /// Tx would take the session over its maximum number of inputs
+  TooManyInputs(uint),
+  /// Session already has its maximum number of participants
+  TooManyParticipants(uint),
   /// Signed tx had an input that was not the expected one
   UnexpectedInput(Sha256dHash, uint),
   /// Signed tx had an output that was not the expected one
@@ -52,10 +76,114 @@ pub enum CoinjoinError {
   UnknownInput(Sha256dHash, uint),
   /// Tx had a version which the joiner did not understand
   UnknownVersion(uint),
+  /// Tx had a different version from the session's other transactions (session, tx)
+  VersionMismatch(uint, uint),
   /// Signed tx had too many inputs
   WrongInputCount(uint),
+  /// Session does not support this action in its mode
+  WrongMode(SessionMode),
   /// Signed tx had too many outputs
   WrongOutputCount(uint)
 }
 
+/// RPC error codes and messages for each kind of `CoinjoinError`. These are
+/// part of the RPC interface, so existing codes must never be changed.
+pub static ERROR_CODES: [(i32, &'static str), ..24] = [
+  (-100, "Input already in the join"),
+  (-101, "Output already registered"),
+  (-102, "Session is in the wrong state"),
+  (-103, "Input value exceeds output value"),
+  (-104, "Input spent outside the join"),
+  (-105, "Insufficient donation"),
+  (-106, "Insufficient miner fee"),
+  (-107, "Invalid output token"),
+  (-108, "No new signed inputs"),
+  (-109, "Non-canonical scriptSig"),
+  (-110, "Nonzero locktime"),
+  (-111, "No output of the target value"),
+  (-112, "Output value exceeds input value"),
+  (-113, "Too many inputs"),
+  (-114, "Too many participants"),
+  (-115, "Unexpected input"),
+  (-116, "Unexpected output"),
+  (-117, "Unknown input"),
+  (-118, "Unknown transaction version"),
+  (-119, "Wrong number of inputs"),
+  (-120, "Wrong session mode"),
+  (-121, "Wrong number of outputs"),
+  (-122, "Transaction version differs from the session's"),
+  (-123, "Blinding key is still being generated")
+];
+
+impl CoinjoinError {
+  /// The RPC error code for this kind of error
+  pub fn code(&self) -> i32 {
+    match *self {
+      BlindKeyUnavailable => -123,
+      DuplicateInput(..) => -100,
+      DuplicateOutput(..) => -101,
+      IncorrectState(..) => -102,
+      InputsExceedOutputs(..) => -103,
+      InputSpent(..) => -104,
+      InsufficientFee(..) => -105,
+      InsufficientMinerFee(..) => -106,
+      InvalidToken => -107,
+      NoNewSignedInputs => -108,
+      NonCanonicalScriptSig(..) => -109,
+      NonZeroLocktime(..) => -110,
+      NoTargetOutput(..) => -111,
+      OutputsExceedInputs(..) => -112,
+      TooManyInputs(..) => -113,
+      TooManyParticipants(..) => -114,
+      UnexpectedInput(..) => -115,
+      UnexpectedOutput(..) => -116,
+      UnknownInput(..) => -117,
+      UnknownVersion(..) => -118,
+      VersionMismatch(..) => -122,
+      WrongInputCount(..) => -119,
+      WrongMode(..) => -120,
+      WrongOutputCount(..) => -121
+    }
+  }
 
+  /// A short description of this kind of error
+  pub fn message(&self) -> &'static str {
+    let code = self.code();
+    ERROR_CODES.iter().find(|&&(c, _)| c == code).unwrap().val1()
+  }
+
+  /// The fields of the error, as a JSON object
+  pub fn data(&self) -> json::Json {
+    let fields = match *self {
+      BlindKeyUnavailable => vec![],
+      DuplicateInput(ref txid, vout) => vec![("txid", txid.to_json()), ("vout", vout.to_json())],
+      DuplicateOutput(ref script) => vec![("script_pubkey", script.to_json())],
+      IncorrectState(expected, actual) => vec![("expected", expected.to_json()), ("actual", actual.to_json())],
+      InputsExceedOutputs(inputs, outputs) => vec![("inputs", inputs.to_json()), ("outputs", outputs.to_json())],
+      InputSpent(ref txid, vout) => vec![("txid", txid.to_json()), ("vout", vout.to_json())],
+      InsufficientFee(received, expected) => vec![("received", received.to_json()), ("expected", expected.to_json())],
+      InsufficientMinerFee(received, expected) => vec![("received", received.to_json()), ("expected", expected.to_json())],
+      InvalidToken => vec![],
+      NoNewSignedInputs => vec![],
+      NonCanonicalScriptSig(ref txid, vout) => vec![("txid", txid.to_json()), ("vout", vout.to_json())],
+      NonZeroLocktime(locktime) => vec![("locktime", locktime.to_json())],
+      NoTargetOutput(target) => vec![("target", target.to_json())],
+      OutputsExceedInputs(outputs, inputs) => vec![("outputs", outputs.to_json()), ("inputs", inputs.to_json())],
+      TooManyInputs(max) => vec![("max", max.to_json())],
+      TooManyParticipants(max) => vec![("max", max.to_json())],
+      UnexpectedInput(ref txid, vout) => vec![("txid", txid.to_json()), ("vout", vout.to_json())],
+      UnexpectedOutput(ref script, value) => vec![("script_pubkey", script.to_json()), ("value", value.to_json())],
+      UnknownInput(ref txid, vout) => vec![("txid", txid.to_json()), ("vout", vout.to_json())],
+      UnknownVersion(version) => vec![("version", version.to_json())],
+      VersionMismatch(session, tx) => vec![("session", session.to_json()), ("tx", tx.to_json())],
+      WrongInputCount(count) => vec![("count", count.to_json())],
+      WrongMode(mode) => vec![("mode", json::String(mode.to_string()))],
+      WrongOutputCount(count) => vec![("count", count.to_json())]
+    };
+    let mut obj = TreeMap::new();
+    for &(name, ref value) in fields.iter() {
+      obj.insert(name.to_string(), value.clone());
+    }
+    json::Object(obj)
+  }
+}
@@ -19,31 +19,65 @@
 use std::collections::{HashMap, TreeMap};
 use std::default::Default;
 use std::num::from_str_radix;
-use std::io::IoResult;
+use std::io::{Append, BufferedReader, File, IoResult, Write};
 use std::rand::{Rng, SeedableRng};
 use std::time::Duration;
 use serialize::json;
 use serialize::{Decodable, Decoder, Encodable, Encoder};
-use time::precise_time_ns;
+use time::{get_time, precise_time_ns};
 
-use bitcoin::blockdata::transaction::{Transaction, TxIn, PayToPubkeyHash};
+use bitcoin::blockdata::script::Script;
+use bitcoin::blockdata::transaction::{Transaction, TxIn, TxOut, PayToPubkeyHash};
 use bitcoin::blockdata::utxoset::UtxoSet;
-use bitcoin::network::serialize::{BitcoinHash, serialize_hex};
+use bitcoin::network::serialize::{BitcoinHash, serialize, serialize_hex};
 use bitcoin::util::base58::ToBase58;
+use bitcoin::util::hash::Sha256dHash;
+use bitcoin::util::misc::consume_err;
 use bitcoin::wallet::address::Address;
 
 use crypto::fortuna::Fortuna;
+use num::bigint::BigUint;
 
-use coinjoin::{CoinjoinError, DuplicateInput, IncorrectState, InsufficientFee,
-               NoNewSignedInputs, NonZeroLocktime, NoTargetOutput,
-               InputsExceedOutputs, OutputsExceedInputs, UnexpectedInput, UnexpectedOutput,
-               UnknownInput, UnknownVersion, WrongInputCount, WrongOutputCount};
+use coinjoin::blind;
+use coinjoin::canonical::canonicalize_script_sig;
+use coinjoin::{CoinjoinError, DuplicateInput, DuplicateOutput, IncorrectState, InsufficientFee,
+               InsufficientMinerFee, InvalidToken, NoNewSignedInputs, NonCanonicalScriptSig,
+               NonZeroLocktime,
+               NoTargetOutput, InputsExceedOutputs, InputSpent, OutputsExceedInputs,
+               TooManyInputs, TooManyParticipants, UnexpectedInput, UnexpectedOutput,
+               UnknownInput, UnknownVersion, VersionMismatch, WrongInputCount, WrongMode,
+               WrongOutputCount};
+
+/// Size in bytes of the version, locktime and input/output counts of a transaction
+static TX_OVERHEAD_SIZE: u64 = 10;
+/// Share of the transaction overhead charged to each participant. A join has
+/// at least two participants, so between them they cover all of it.
+static TX_OVERHEAD_SHARE: u64 = (TX_OVERHEAD_SIZE + 1) / 2;
+/// Estimated size in bytes of a signed pay-to-pubkey-hash input
+static SIGNED_INPUT_SIZE: u64 = 148;
+/// Size in bytes of a pay-to-pubkey-hash output, as registered in blinded sessions
+static TARGET_OUTPUT_SIZE: u64 = 34;
+/// Size in bits of the RSA modulus used to issue blinded output tokens
+static BLIND_KEY_BITS: uint = 2048;
+
+user_enum!(
+  #[doc="The protocol used by a session to collect outputs"]
+  #[deriving(Clone, PartialEq, Eq)]
+  pub enum SessionMode {
+    #[doc="Participants submit unsigned transactions including their target outputs"]
+    Transparent <-> "transparent",
+    #[doc="Participants register inputs and target outputs separately, using blind signatures"]
+    Blinded <-> "blinded"
+  }
+)
 
 /// Current state of the session
 #[deriving(Clone, PartialEq, Eq, PartialOrd, Ord, Show)]
 pub enum SessionState {
   /// Collecting unsigned transactions
   Joining,
+  /// Collecting anonymously-registered outputs (blinded sessions only)
+  Registering,
   /// Collecting signed transactions
   Merging,
   /// Completed successfully
@@ -60,6 +94,7 @@ impl json::ToJson for SessionState {
   fn to_json(&self) -> json::Json {
     json::String(match *self {
       Joining => "joining",
+      Registering => "registering",
       Merging => "merging",
       Complete => "complete",
       Expired => "expired",
@@ -69,6 +104,110 @@ impl json::ToJson for SessionState {
   }
 }
 
+/// Rules which unsigned transactions must follow to be accepted into a session
+#[deriving(Clone, PartialEq, Eq, Show)]
+pub struct SessionPolicy {
+  /// Protocol used to collect outputs
+  pub mode: SessionMode,
+  /// Donation required per input (satoshi)
+  pub fee_per_input: u64,
+  /// Donation required per output (satoshi)
+  pub fee_per_output: u64,
+  /// Whether participants must pay a donation at all
+  pub donation_required: bool,
+  /// Whether a transaction's inputs may exceed its outputs, the difference
+  /// being left as a miner fee
+  pub implicit_miner_fee: bool,
+  /// Whether a transaction's outputs may exceed its inputs, the difference
+  /// being made up by other participants. Such transactions pay no miner
+  /// fee of their own, so the others must leave enough over to pay for them.
+  pub allow_transfers: bool,
+  /// Target miner feerate (satoshi per byte). Each transaction must leave
+  /// enough input value unassigned to pay for its own share of the join,
+  /// and the join as a whole must meet it to be merged.
+  pub feerate: u64,
+  /// Transaction versions which are accepted
+  pub allowed_versions: Vec<u32>,
+  /// Maximum number of participants, if any
+  pub max_participants: Option<uint>,
+  /// Maximum total number of inputs across all participants, if any
+  pub max_inputs: Option<uint>
+}
+
+impl Default for SessionPolicy {
+  fn default() -> SessionPolicy {
+    SessionPolicy {
+      mode: Transparent,
+      fee_per_input: 200,
+      fee_per_output: 50,
+      donation_required: true,
+      implicit_miner_fee: false,
+      allow_transfers: false,
+      feerate: 0,
+      allowed_versions: vec![1],
+      max_participants: None,
+      max_inputs: None
+    }
+  }
+}
+
+impl json::ToJson for SessionPolicy {
+  fn to_json(&self) -> json::Json {
+    let mut obj = TreeMap::new();
+    obj.insert("mode".to_string(), json::String(self.mode.to_string()));
+    obj.insert("fee_per_input".to_string(), self.fee_per_input.to_json());
+    obj.insert("fee_per_output".to_string(), self.fee_per_output.to_json());
+    obj.insert("donation_required".to_string(), self.donation_required.to_json());
+    obj.insert("implicit_miner_fee".to_string(), self.implicit_miner_fee.to_json());
+    obj.insert("allow_transfers".to_string(), self.allow_transfers.to_json());
+    obj.insert("feerate".to_string(), self.feerate.to_json());
+    obj.insert("allowed_versions".to_string(), self.allowed_versions.to_json());
+    obj.insert("max_participants".to_string(), self.max_participants.to_json());
+    obj.insert("max_inputs".to_string(), self.max_inputs.to_json());
+    json::Object(obj)
+  }
+}
+
+/// A session policy with every field optional, as read from the user's
+/// configuration or an RPC call. Missing fields are taken from a base policy.
+#[deriving(Clone, Decodable)]
+pub struct PartialSessionPolicy {
+  mode: Option<SessionMode>,
+  fee_per_input: Option<u64>,
+  fee_per_output: Option<u64>,
+  donation_required: Option<bool>,
+  implicit_miner_fee: Option<bool>,
+  allow_transfers: Option<bool>,
+  feerate: Option<u64>,
+  allowed_versions: Option<Vec<u32>>,
+  max_participants: Option<uint>,
+  max_inputs: Option<uint>
+}
+
+impl PartialSessionPolicy {
+  /// Fills in any missing fields from `base`
+  pub fn apply(self, base: &SessionPolicy) -> SessionPolicy {
+    SessionPolicy {
+      mode: self.mode.unwrap_or(base.mode),
+      fee_per_input: self.fee_per_input.unwrap_or(base.fee_per_input),
+      fee_per_output: self.fee_per_output.unwrap_or(base.fee_per_output),
+      donation_required: self.donation_required.unwrap_or(base.donation_required),
+      implicit_miner_fee: self.implicit_miner_fee.unwrap_or(base.implicit_miner_fee),
+      allow_transfers: self.allow_transfers.unwrap_or(base.allow_transfers),
+      feerate: self.feerate.unwrap_or(base.feerate),
+      allowed_versions: self.allowed_versions.unwrap_or(base.allowed_versions.clone()),
+      max_participants: self.max_participants.or(base.max_participants),
+      max_inputs: self.max_inputs.or(base.max_inputs)
+    }
+  }
+}
+
+// An unsigned transaction submitted to a session, with the total value of its inputs
+struct Participant {
+  tx: Transaction,
+  value_in: u64
+}
+
 /// A Coinjoin session
 pub struct Session {
   id: SessionId,
@@ -81,9 +220,23 @@ pub struct Session {
   // Duration of every other phase before we expire or delete the session
   expiry_duration: Duration,
   target_value: u64,
-  unsigned: Vec<Transaction>,
+  policy: SessionPolicy,
+  unsigned: Vec<Participant>,
+  // Key used to issue output tokens, and the outputs registered with them,
+  // in blinded sessions
+  blind_key: Option<blind::SecretKey>,
+  blind_outputs: Vec<TxOut>,
   merged: Option<Transaction>,
   signed: Option<Transaction>,
+  // Hash of the merged transaction with all scriptSigs empty, which
+  // signing does not change
+  ntxid: Option<Sha256dHash>,
+  // Number of participants dropped because their inputs were spent elsewhere
+  n_dropped: uint,
+  // Number of non-donation outputs consolidated into others when merging
+  n_collapsed: uint,
+  // Reason the session moved to `Failed`, if it did
+  failure: Option<CoinjoinError>,
   donation_address: Address
 }
 
@@ -99,6 +252,8 @@ impl json::ToJson for Session {
     match self.state {
       Merging => {
         obj.insert("merged_tx".to_string(), json::String(serialize_hex(self.merged.as_ref().unwrap()).unwrap()));
+        obj.insert("ntxid".to_string(), self.ntxid.unwrap().to_json());
+        obj.insert("analysis".to_string(), self.analysis());
         obj.insert("time_until_expiry".to_string(),
                    (self.expiry_duration - time_since_switch).num_milliseconds().to_json());
       }
@@ -107,9 +262,38 @@ impl json::ToJson for Session {
                    (self.join_duration - time_since_switch).num_milliseconds().to_json());
         obj.insert("donation_address".to_string(),
                    json::String(self.donation_address.to_base58check()));
+        obj.insert("dropped_participants".to_string(), self.n_dropped.to_json());
+        obj.insert("policy".to_string(), self.policy.to_json());
+        match self.blind_key {
+          Some(ref key) => { obj.insert("blind_key".to_string(), key.public_key().to_json()); }
+          None => {}
+        }
+      }
+      Registering => {
+        obj.insert("time_until_merge".to_string(),
+                   (self.join_duration - time_since_switch).num_milliseconds().to_json());
+        obj.insert("blind_key".to_string(),
+                   self.blind_key.as_ref().unwrap().public_key().to_json());
+        obj.insert("registered_outputs".to_string(), self.blind_outputs.len().to_json());
+        obj.insert("expected_outputs".to_string(), self.unsigned.len().to_json());
       }
       Complete => {
-        obj.insert("txid".to_string(), self.signed.as_ref().unwrap().bitcoin_hash().to_json());
+        let signed = self.signed.as_ref().unwrap();
+        let fee = self.miner_fee();
+        let size = serialize(signed).unwrap().len() as u64;
+        obj.insert("txid".to_string(), signed.bitcoin_hash().to_json());
+        obj.insert("ntxid".to_string(), self.ntxid.unwrap().to_json());
+        obj.insert("fee".to_string(), fee.to_json());
+        obj.insert("feerate".to_string(), (fee as f64 / size as f64).to_json());
+        obj.insert("analysis".to_string(), self.analysis());
+        obj.insert("time_until_deletion".to_string(),
+                   (self.expiry_duration - time_since_switch).num_milliseconds().to_json());
+      }
+      Failed => {
+        match self.failure {
+          Some(ref e) => { obj.insert("failure_reason".to_string(), json::String(e.to_string())); }
+          None => {}
+        }
         obj.insert("time_until_deletion".to_string(),
                    (self.expiry_duration - time_since_switch).num_milliseconds().to_json());
       }
@@ -123,6 +307,68 @@ impl json::ToJson for Session {
   }
 }
 
+/// A record of a finished session, as kept in the history log
+#[deriving(Clone, Encodable, Decodable)]
+pub struct HistoryEntry {
+  /// The session ID
+  pub id: SessionId,
+  /// Value of the equal-sized outputs (satoshi)
+  pub target_value: u64,
+  /// Number of participants
+  pub n_participants: uint,
+  /// Txid of the joined transaction, for complete sessions
+  pub txid: Option<Sha256dHash>,
+  /// Total donation received (satoshi), for complete sessions
+  pub donation: u64,
+  /// State the session was in when it was deleted
+  pub state: String,
+  /// Time the session was deleted (seconds since the Unix epoch)
+  pub time: i64
+}
+
+impl json::ToJson for HistoryEntry {
+  fn to_json(&self) -> json::Json {
+    let mut obj = TreeMap::new();
+    obj.insert("id".to_string(), self.id.to_json());
+    obj.insert("target_value".to_string(), self.target_value.to_json());
+    obj.insert("participants".to_string(), self.n_participants.to_json());
+    match self.txid {
+      Some(txid) => { obj.insert("txid".to_string(), txid.to_json()); }
+      None => {}
+    }
+    obj.insert("donation".to_string(), self.donation.to_json());
+    obj.insert("state".to_string(), json::String(self.state.clone()));
+    obj.insert("time".to_string(), self.time.to_json());
+    json::Object(obj)
+  }
+}
+
+/// Reads every entry of a session history log. A missing log is empty.
+pub fn read_history(path: &Path) -> IoResult<Vec<HistoryEntry>> {
+  use std::io::{FileNotFound, InvalidInput, IoError};
+
+  let mut file = match File::open(path) {
+    Ok(file) => BufferedReader::new(file),
+    Err(ref e) if e.kind == FileNotFound => { return Ok(vec![]); }
+    Err(e) => { return Err(e); }
+  };
+  let mut ret = vec![];
+  for line in file.lines() {
+    let line = try!(line);
+    match json::decode(line.as_slice().trim()) {
+      Ok(entry) => ret.push(entry),
+      Err(e) => {
+        return Err(IoError {
+          kind: InvalidInput,
+          desc: "malformed coinjoin history entry",
+          detail: Some(e.to_string())
+        });
+      }
+    }
+  }
+  Ok(ret)
+}
+
 /// A session identifier
 #[deriving(Hash, PartialEq, Eq, Clone, Show)]
 pub struct SessionId(u64);
@@ -152,31 +398,36 @@ impl json::ToJson for SessionId {
 }
 
 impl Session {
-  /// Creates a new session with a random ID
+  /// Creates a new session with a random ID. Blinded sessions must be given
+  /// a key to sign output tokens with; see `Server::take_blind_key`.
   pub fn new(target_value: u64,
              join_duration: Duration,
              expiry_duration: Duration,
+             policy: SessionPolicy,
+             blind_key: Option<blind::SecretKey>,
              donation_address: Address)
              -> IoResult<Session> {
-    use std::rand;
-    let mut csrng: Fortuna = {
-      let mut rng = try!(rand::OsRng::new());
-      let mut seed = [0, ..256];
-      rng.fill_bytes(seed.as_mut_slice());
-      SeedableRng::from_seed(seed.as_slice())
-    };
+    assert!(blind_key.is_some() == (policy.mode == Blinded));
+    let mut csrng = try!(new_csrng());
     let id = SessionId(csrng.gen());
     Ok(Session {
       id: id,
       rng: csrng,
       target_value: target_value,
+      policy: policy,
       state: Joining,
       switch_time: precise_time_ns(),
       join_duration: join_duration,
       expiry_duration: expiry_duration,
       unsigned: vec![],
+      blind_key: blind_key,
+      blind_outputs: vec![],
       merged: None,
       signed: None,
+      ntxid: None,
+      n_dropped: 0,
+      n_collapsed: 0,
+      failure: None,
       donation_address: donation_address
     })
   }
@@ -186,41 +437,114 @@ impl Session {
     self.id
   }
 
-  /// Adds an unsigned transaction to a coinjoin session
+  /// Adds an unsigned transaction to a transparent coinjoin session
   pub fn add_unsigned(&mut self, tx: &Transaction, utxo_set: &UtxoSet)
                       -> Result<(), CoinjoinError> {
+    if self.policy.mode != Transparent {
+      return Err(WrongMode(self.policy.mode));
+    }
+    let value_in = try!(self.check_unsigned(tx, utxo_set));
+    self.unsigned.push(Participant { tx: tx.clone(), value_in: value_in });
+    Ok(())
+  }
+
+  /// Registers the inputs and change of an unsigned transaction with a blinded
+  /// coinjoin session, returning a blind signature on `blinded_token`. The
+  /// transaction should not have an output of the target value; its inputs
+  /// must instead cover one, which the participant registers separately using
+  /// `register_output` and the unblinded signature.
+  pub fn register_inputs(&mut self, tx: &Transaction, blinded_token: &BigUint,
+                         utxo_set: &UtxoSet) -> Result<BigUint, CoinjoinError> {
+    if self.policy.mode != Blinded {
+      return Err(WrongMode(self.policy.mode));
+    }
+    let value_in = try!(self.check_unsigned(tx, utxo_set));
+    let sig = self.blind_key.as_ref().unwrap().sign_blinded(blinded_token);
+    self.unsigned.push(Participant { tx: tx.clone(), value_in: value_in });
+    Ok(sig)
+  }
+
+  /// Registers an output of the target value with a blinded coinjoin session.
+  /// `sig` is the unblinded signature on the consensus-serialized script pubkey.
+  pub fn register_output(&mut self, script_pubkey: Script, sig: &BigUint)
+                         -> Result<(), CoinjoinError> {
+    if self.policy.mode != Blinded {
+      return Err(WrongMode(self.policy.mode));
+    }
+    if self.state != Registering {
+      return Err(IncorrectState(Registering, self.state));
+    }
+    let msg = serialize(&script_pubkey).unwrap();
+    if !self.blind_key.as_ref().unwrap().public_key().verify(msg.as_slice(), sig) {
+      return Err(InvalidToken);
+    }
+    // Since each token signs a specific script, this also stops tokens being reused
+    if self.blind_outputs.iter().any(|out| out.script_pubkey == script_pubkey) {
+      return Err(DuplicateOutput(script_pubkey));
+    }
+    self.blind_outputs.push(TxOut { value: self.target_value, script_pubkey: script_pubkey });
+    Ok(())
+  }
+
+  // Checks an unsigned transaction against the session policy, returning the
+  // total value of its inputs
+  fn check_unsigned(&self, tx: &Transaction, utxo_set: &UtxoSet) -> Result<u64, CoinjoinError> {
     if self.state != Joining {
       return Err(IncorrectState(Joining, self.state));
     }
+    // In blinded sessions the target output is registered separately, but
+    // must be paid for by this transaction's inputs
+    let blinded = self.policy.mode == Blinded;
+
+    // Check for participant and input limits
+    match self.policy.max_participants {
+      Some(max) if self.unsigned.len() >= max => { return Err(TooManyParticipants(max)); }
+      _ => {}
+    }
+    match self.policy.max_inputs {
+      Some(max) => {
+        let n_inputs = self.unsigned.iter().fold(0, |acc, p| acc + p.tx.input.len());
+        if n_inputs + tx.input.len() > max {
+          return Err(TooManyInputs(max));
+        }
+      }
+      None => {}
+    }
 
     // Check for version, locktime
-    if tx.version != 1 {
+    if !self.policy.allowed_versions.contains(&tx.version) {
       return Err(UnknownVersion(tx.version as uint));
     }
+    // The merged transaction has a single version, so participants must agree on it
+    match self.unsigned.iter().next() {
+      Some(first) if first.tx.version != tx.version => {
+        return Err(VersionMismatch(first.tx.version as uint, tx.version as uint));
+      }
+      _ => {}
+    }
     if tx.lock_time != 0 {
       return Err(NonZeroLocktime(tx.lock_time as uint));
     }
 
     // Check for output of the correct size
-    if !tx.output.iter().any(|o| o.value == self.target_value) {
+    if !blinded && !tx.output.iter().any(|o| o.value == self.target_value) {
       return Err(NoTargetOutput(self.target_value));
     }
 
     // Check for fee
-    let mut received_fee = 0;
-    let required_fee = tx.input.len() as u64 * 200 + tx.output.len() as u64 * 50;
-    for out in tx.output.iter() {
-      match out.classify(self.donation_address.network) {
-        PayToPubkeyHash(ref addr) => {
-          if addr == &self.donation_address {
-            received_fee += out.value;
-          }
+    if self.policy.donation_required {
+      let mut received_fee = 0;
+      let n_outputs = tx.output.len() + if blinded { 1 } else { 0 };
+      let required_fee = tx.input.len() as u64 * self.policy.fee_per_input +
+                         n_outputs as u64 * self.policy.fee_per_output;
+      for out in tx.output.iter() {
+        if self.is_donation(out) {
+          received_fee += out.value;
         }
-        _ => {}
       }
-    }
-    if received_fee < required_fee {
-      return Err(InsufficientFee(received_fee, required_fee));
+      if received_fee < required_fee {
+        return Err(InsufficientFee(received_fee, required_fee));
+      }
     }
 
     // Check that we know all the inputs, and that they have
@@ -232,8 +556,8 @@ impl Session {
         Some((_, out)) => { total_in += out.value; }
         None => { return Err(UnknownInput(input.prev_hash, input.prev_index as uint)); }
       }
-      for other_tx in self.unsigned.iter() { 
-        for other_input in other_tx.input.iter() {
+      for other in self.unsigned.iter() {
+        for other_input in other.tx.input.iter() {
           if input.prev_hash  == other_input.prev_hash &&
              input.prev_index == other_input.prev_index {
             return Err(DuplicateInput(input.prev_hash, input.prev_index as uint));
@@ -241,61 +565,79 @@ impl Session {
         }
       }
     }
-    let total_out = tx.output.iter().fold(0, |acc, out| acc + out.value);
+    let mut total_out = tx.output.iter().fold(0, |acc, out| acc + out.value);
+    if blinded {
+      total_out += self.target_value;
+    }
 
-    // Check that input value is <= output value
-    // TODO: there should be a session option to allow this, for doing transfers
-    if total_in < total_out {
+    // Check that input value matches output value, unless the policy allows otherwise.
+    // Transfers are checked against the whole join when merging.
+    if total_in < total_out && !self.policy.allow_transfers {
       return Err(OutputsExceedInputs(total_out, total_in));
     }
-    // TODO: there should be a session option to allow this and/or disabling donations
-    if total_in > total_out {
+    if total_in > total_out && !self.policy.implicit_miner_fee && self.policy.feerate == 0 {
       return Err(InputsExceedOutputs(total_in, total_out));
     }
 
-    // All Ok, add it
-    self.unsigned.push(tx.clone());
-    Ok(())
+    // Check that the unassigned value covers this transaction's share of the miner
+    // fee. Transfers have nothing unassigned; they are paid for by the others, which
+    // is checked against the whole join when merging.
+    if self.policy.feerate > 0 && total_in >= total_out {
+      let received_fee = if total_in > total_out { total_in - total_out } else { 0 };
+      let size = estimated_size(tx) + if blinded { TARGET_OUTPUT_SIZE } else { 0 };
+      let required_fee = self.policy.feerate * size;
+      if received_fee < required_fee {
+        return Err(InsufficientMinerFee(received_fee, required_fee));
+      }
+    }
+
+    Ok(total_in)
   }
 
   // Merges all the transactions. Shouldn't be public, this should require
   // setting the status to `Merging`
   fn merge_transactions(&mut self) {
+    // Participants all have the same version; see `check_unsigned`
+    let version = self.unsigned.iter().next().map_or(1, |p| p.tx.version);
     let mut merged = Transaction {
-      version: 1,
+      version: version,
       lock_time: 0,
       input: Vec::with_capacity(
-        self.unsigned.iter().fold(0, |acc, tx| acc + tx.input.len())),
+        self.unsigned.iter().fold(0, |acc, p| acc + p.tx.input.len())),
       output: Vec::with_capacity(
-        self.unsigned.iter().fold(0, |acc, tx| acc + tx.output.len())),
+        self.unsigned.iter().fold(0, |acc, p| acc + p.tx.output.len())),
     };
 
     // We validated inputs and outputs when bringing them in
-    for tx in self.unsigned.iter() {
-      merged.input.push_all(tx.input.as_slice());
+    for p in self.unsigned.iter() {
+      merged.input.push_all(p.tx.input.as_slice());
+    }
 
-      // When adding outputs, check for dupes and consolitate them
-      for out in tx.output.iter() {
-        let mut already_exists = false;
-        for existing_out in merged.output.mut_iter() {
-          if existing_out.script_pubkey == out.script_pubkey {
-            existing_out.value += out.value;
-            already_exists = true;
-          }
-        }
-        if !already_exists {
-          merged.output.push(out.clone());
+    // When adding outputs, check for dupes and consolitate them
+    let mut n_collapsed = 0;
+    for out in self.unsigned.iter().flat_map(|p| p.tx.output.iter())
+                   .chain(self.blind_outputs.iter()) {
+      let mut already_exists = false;
+      for existing_out in merged.output.mut_iter() {
+        if existing_out.script_pubkey == out.script_pubkey {
+          existing_out.value += out.value;
+          already_exists = true;
         }
       }
+      if !already_exists {
+        merged.output.push(out.clone());
+      } else if !self.is_donation(out) {
+        // Donations are expected to be consolidated; anything else links participants
+        n_collapsed += 1;
+      }
     }
-
-    // TODO: Randomize DER encoding
+    self.n_collapsed = n_collapsed;
 
     // Randomize the input and output order
     self.rng.shuffle(merged.input.as_mut_slice());
     self.rng.shuffle(merged.output.as_mut_slice());
 
-    self.signed = Some(Transaction {
+    let signed = Transaction {
       version: merged.version,
       lock_time: merged.lock_time,
       input: merged.input.iter().map(
@@ -306,7 +648,9 @@ impl Session {
           sequence: input.sequence
         }).collect(),
       output: merged.output.clone()
-    });
+    };
+    self.ntxid = Some(signed.bitcoin_hash());
+    self.signed = Some(signed);
     self.merged = Some(merged);
   }
 
@@ -344,22 +688,32 @@ impl Session {
       }
     }
 
-    // Check that at least one of the inputs validates
+    // Check that at least one of the inputs validates, and that every
+    // new scriptSig can be canonicalized before accepting any of them
     let mut still_needed = 0u;
-    let mut n_new_inputs = 0u;
+    let mut new_inputs = vec![];
     for (i, input) in tx.input.iter().enumerate() {
       if signed.input[i].script_sig == Default::default() {
         if input.validate(utxo_set, tx, i).is_ok() {
-          signed.input.get_mut(i).script_sig = input.script_sig.clone();
-          n_new_inputs += 1;
+          // Validation found the output being spent, so it is there
+          let (_, prev_out) = utxo_set.get_utxo(input.prev_hash, input.prev_index).unwrap();
+          match canonicalize_script_sig(&input.script_sig, &prev_out.script_pubkey) {
+            Some(script_sig) => { new_inputs.push((i, script_sig)); }
+            None => {
+              return Err(NonCanonicalScriptSig(input.prev_hash, input.prev_index as uint));
+            }
+          }
         } else {
           still_needed += 1;
         }
       }
     }
-    if n_new_inputs == 0 {
+    if new_inputs.len() == 0 {
       return Err(NoNewSignedInputs);
     }
+    for (i, script_sig) in new_inputs.move_iter() {
+      signed.input.get_mut(i).script_sig = script_sig;
+    }
     // If there are no more needed inputs, send the tx
     if still_needed == 0 {
       // Build the transaction
@@ -370,6 +724,124 @@ impl Session {
     Ok(())
   }
 
+  /// Notifies the session that `tx` was seen on the network, either in a
+  /// block or loose. If `tx` spends an input registered with the session, a
+  /// transparent session which is still joining drops the participant who
+  /// registered it. Any other session can no longer complete and so is marked
+  /// as failed.
+  pub fn process_transaction(&mut self, tx: &Transaction) {
+    match self.state {
+      Joining if self.policy.mode == Transparent => {
+        let n_before = self.unsigned.len();
+        self.unsigned.retain(|p| conflicting_input(&p.tx, tx).is_none());
+        self.n_dropped += n_before - self.unsigned.len();
+      }
+      Joining | Registering | Merging => {
+        // Once output tokens have been issued or outputs registered we cannot
+        // drop participants, since their outputs could still be registered
+        let spent = self.unsigned.iter().filter_map(|p| conflicting_input(&p.tx, tx)).next()
+                        .map(|input| (input.prev_hash, input.prev_index as uint));
+        match spent {
+          Some((hash, index)) => {
+            self.state = Failed;
+            self.failure = Some(InputSpent(hash, index));
+            self.switch_time = precise_time_ns();
+          }
+          None => {}
+        }
+      }
+      _ => {}
+    }
+  }
+
+  // The total value of the merged transaction's outputs to the donation address
+  fn donation_received(&self) -> u64 {
+    match self.merged {
+      Some(ref merged) => merged.output.iter().filter(|out| self.is_donation(*out))
+                                              .fold(0, |acc, out| acc + out.value),
+      None => 0
+    }
+  }
+
+  // Builds a history record of the session as it is now
+  fn history_entry(&self) -> HistoryEntry {
+    let complete = self.state == Complete;
+    HistoryEntry {
+      id: self.id,
+      target_value: self.target_value,
+      n_participants: self.unsigned.len(),
+      txid: if complete { self.signed.as_ref().map(|tx| tx.bitcoin_hash()) } else { None },
+      donation: if complete { self.donation_received() } else { 0 },
+      state: self.state.to_string(),
+      time: get_time().sec
+    }
+  }
+
+  // Whether an output pays to the session's donation address
+  fn is_donation(&self, out: &TxOut) -> bool {
+    match out.classify(self.donation_address.network) {
+      PayToPubkeyHash(ref addr) => addr == &self.donation_address,
+      _ => false
+    }
+  }
+
+  // Describes the privacy of the merged transaction: how many participants it
+  // has, how many indistinguishable target outputs, and how many change outputs
+  fn analysis(&self) -> json::Json {
+    let merged = self.merged.as_ref().unwrap();
+    let n_equal = merged.output.iter().filter(|out| out.value == self.target_value).count();
+    let n_change = merged.output.iter().filter(|out| {
+      out.value != self.target_value && !self.is_donation(*out)
+    }).count();
+
+    let mut obj = TreeMap::new();
+    obj.insert("participants".to_string(), self.unsigned.len().to_json());
+    obj.insert("equal_outputs".to_string(), n_equal.to_json());
+    obj.insert("change_outputs".to_string(), n_change.to_json());
+    obj.insert("collapsed_outputs".to_string(), self.n_collapsed.to_json());
+    if self.n_collapsed > 0 {
+      obj.insert("warning".to_string(),
+                 json::String(format!("{} output(s) shared a script pubkey with another and were \
+                                       consolidated, reducing the anonymity set", self.n_collapsed)));
+    }
+    json::Object(obj)
+  }
+
+  // The total value of all inputs and of all outputs submitted to the session
+  fn totals(&self) -> (u64, u64) {
+    let total_in = self.unsigned.iter().fold(0, |acc, p| acc + p.value_in);
+    let total_out = self.unsigned.iter().flat_map(|p| p.tx.output.iter())
+                        .chain(self.blind_outputs.iter())
+                        .fold(0, |acc, out| acc + out.value);
+    (total_in, total_out)
+  }
+
+  // Whether the submitted transactions, taken together, spend no more than
+  // their inputs. Individual transactions may not if transfers are allowed.
+  fn is_balanced(&self) -> bool {
+    let (total_in, total_out) = self.totals();
+    total_in >= total_out
+  }
+
+  // Whether the value left unassigned by all the submitted transactions, taken
+  // together, pays the policy feerate for the merged transaction
+  fn pays_feerate(&self) -> bool {
+    let output_size = self.blind_outputs.iter().fold(0, |acc, out| acc + serialize(out).unwrap().len() as u64);
+    let size = self.unsigned.iter().fold(TX_OVERHEAD_SIZE + output_size, |acc, p| {
+      acc + estimated_size(&p.tx) - TX_OVERHEAD_SHARE
+    });
+    self.miner_fee() >= self.policy.feerate * size
+  }
+
+  // The total value of all inputs less that of all outputs
+  fn miner_fee(&self) -> u64 {
+    let (total_in, total_out) = self.totals();
+    if total_in > total_out { total_in - total_out } else { 0 }
+  }
+
+  /// Accessor for the session policy
+  pub fn policy<'a>(&'a self) -> &'a SessionPolicy { &self.policy }
+
   /// Accessor for the current state
   pub fn state(&self) -> SessionState { self.state }
 
@@ -377,21 +849,71 @@ impl Session {
   pub fn signed_transaction<'a>(&'a self) -> Option<&'a Transaction> { self.signed.as_ref() }
 }
 
+/// Estimates the size in bytes that `tx` will contribute to the merged
+/// transaction once signed, charging it its share of the transaction overhead
+fn estimated_size(tx: &Transaction) -> u64 {
+  let output_size = tx.output.iter().fold(0, |acc, out| acc + serialize(out).unwrap().len() as u64);
+  TX_OVERHEAD_SHARE + tx.input.len() as u64 * SIGNED_INPUT_SIZE + output_size
+}
+
+/// Creates a cryptographically secure RNG, seeded from the OS
+fn new_csrng() -> IoResult<Fortuna> {
+  use std::rand;
+  let mut rng = try!(rand::OsRng::new());
+  let mut seed = [0, ..256];
+  rng.fill_bytes(seed.as_mut_slice());
+  Ok(SeedableRng::from_seed(seed.as_slice()))
+}
+
+/// Returns the first input of `tx` which spends an outpoint also spent by `other`
+fn conflicting_input<'a>(tx: &'a Transaction, other: &Transaction) -> Option<&'a TxIn> {
+  tx.input.iter().find(|input| {
+    other.input.iter().any(|other_input| input.prev_hash == other_input.prev_hash &&
+                                         input.prev_index == other_input.prev_index)
+  })
+}
+
 /// A Coinjoin session manager
 pub struct Server {
   sessions: HashMap<SessionId, Box<Session>>,
-  current: *mut Session
+  current: *mut Session,
+  // Log to which finished sessions are appended when they are deleted
+  history_path: Path,
+  // Keys for blinded sessions, which take long enough to generate that it
+  // is done ahead of time by another task
+  blind_keys: Receiver<blind::SecretKey>
 }
 
 impl Server {
-  /// Construct a new session manager
-  pub fn new() -> Server {
+  /// Construct a new session manager, logging finished sessions to `history_path`
+  pub fn new(history_path: Path) -> Server {
+    // Keep one key ready; the task exits when the server hangs up
+    let (key_tx, key_rx) = sync_channel(1);
+    spawn(proc() {
+      let mut csrng = match new_csrng() {
+        Ok(rng) => rng,
+        Err(e) => { println!("coinjoin: cannot generate blinding keys: {}", e); return; }
+      };
+      loop {
+        if key_tx.send_opt(blind::SecretKey::generate(&mut csrng, BLIND_KEY_BITS)).is_err() {
+          break;
+        }
+      }
+    });
     Server {
       sessions: HashMap::new(),
-      current: RawPtr::null()
+      current: RawPtr::null(),
+      history_path: history_path,
+      blind_keys: key_rx
     }
   }
 
+  /// Takes a key for a new blinded session, or returns None if the next
+  /// one is still being generated
+  pub fn take_blind_key(&mut self) -> Option<blind::SecretKey> {
+    self.blind_keys.try_recv().ok()
+  }
+
   /// Retrieves the current session, or None if there is not one
   pub fn current_session<'a>(&'a self) -> Option<&'a Session> {
     unsafe { self.current.as_ref() }
@@ -412,6 +934,11 @@ impl Server {
     self.sessions.find_mut(key).map(|r| &mut **r)
   }
 
+  /// Gets the state of every session still held by the server
+  pub fn session_states(&self) -> Vec<SessionState> {
+    self.sessions.values().map(|session| session.state()).collect()
+  }
+
   /// Sets the current session
   pub fn set_current_session(&mut self, sess: Session) {
     let boxed = box sess;
@@ -420,11 +947,19 @@ impl Server {
     self.current = raw;
   }
 
+  /// Notifies every session that `tx` was seen on the network
+  pub fn process_transaction(&mut self, tx: &Transaction) {
+    for (_, session) in self.sessions.mut_iter() {
+      session.process_transaction(tx);
+    }
+  }
+
   /// Updates all sessions
   pub fn update_all(&mut self) {
     let now = precise_time_ns();
 
     let mut keys_to_delete = Vec::new();
+    let mut finished = Vec::new();
 
     // Run through list, updating session states
     for (key, session) in self.sessions.mut_iter() {
@@ -433,7 +968,22 @@ impl Server {
       match session.state {
         Joining => {
           if time_since_switch > session.join_duration {
-            if session.unsigned.len() > 1 {
+            if session.unsigned.len() > 1 && session.policy.mode == Blinded {
+              session.state = Registering;
+            } else if session.unsigned.len() > 1 && session.is_balanced() && session.pays_feerate() {
+              session.state = Merging;
+              session.merge_transactions();
+            } else {
+              session.state = Unmerged;
+            }
+            session.switch_time = now;
+          }
+        }
+        Registering => {
+          // Outputs are registered for as long as inputs were
+          if time_since_switch > session.join_duration {
+            if session.blind_outputs.len() == session.unsigned.len() && session.is_balanced() &&
+               session.pays_feerate() {
               session.state = Merging;
               session.merge_transactions();
             } else {
@@ -445,9 +995,13 @@ impl Server {
         state => {
           if time_since_switch > session.expiry_duration {
             session.state = match state {
-              Joining => unreachable!(),
+              Joining | Registering => unreachable!(),
               Merging => Expired,
-              Complete | Expired | Failed | Unmerged => { keys_to_delete.push(*key); Expired }
+              Complete | Expired | Failed | Unmerged => {
+                keys_to_delete.push(*key);
+                finished.push(session.history_entry());
+                Expired
+              }
             };
             session.switch_time = now;
           }
@@ -463,9 +1017,103 @@ impl Server {
       }
       self.sessions.remove(key);
     }
+    // Record them in the history log
+    if finished.len() > 0 {
+      consume_err("Coinjoin: failed to write session history", self.append_history(finished));
+    }
+  }
+
+  // Appends entries to the history log
+  fn append_history(&self, entries: Vec<HistoryEntry>) -> IoResult<()> {
+    let mut file = try!(File::open_mode(&self.history_path, Append, Write));
+    for entry in entries.iter() {
+      try!(file.write_line(json::encode(entry).as_slice()));
+    }
+    Ok(())
   }
 }
 
+#[cfg(test)]
+mod tests {
+  use std::default::Default;
+  use std::num::FromPrimitive;
+  use std::rand::task_rng;
+  use std::time::Duration;
 
+  use bitcoin::blockdata::block::Block;
+  use bitcoin::blockdata::transaction::{Transaction, TxIn, TxOut};
+  use bitcoin::blockdata::utxoset::{UtxoSet, TxoValidation};
+  use bitcoin::network::constants::Bitcoin;
+  use bitcoin::network::serialize::{BitcoinHash, serialize};
+  use bitcoin::util::base58::FromBase58;
 
+  use coinjoin::{IncorrectState, InputSpent};
+  use coinjoin::blind;
+  use constants::DEFAULT_BLOCKCHAIN_N_FULL_BLOCKS;
+  use mock_peer::mainnet_blocks;
+  use super::{Blinded, Failed, Registering, Session, SessionPolicy};
 
+  // Spends the coinbase of `block`, keeping most of it as change
+  fn spend_coinbase(block: &Block) -> Transaction {
+    let coinbase = &block.txdata[0];
+    Transaction {
+      version: 1,
+      lock_time: 0,
+      input: vec![TxIn {
+        prev_hash: coinbase.bitcoin_hash(),
+        prev_index: 0,
+        script_sig: coinbase.output[0].script_pubkey.clone(),
+        sequence: 0xffffffff
+      }],
+      output: vec![TxOut {
+        value: coinbase.output[0].value / 2,
+        script_pubkey: coinbase.output[0].script_pubkey.clone()
+      }]
+    }
+  }
+
+  #[test]
+  fn spent_input_fails_blinded_session() {
+    let blocks = mainnet_blocks();
+    let mut utxo_set = UtxoSet::new(Bitcoin, DEFAULT_BLOCKCHAIN_N_FULL_BLOCKS);
+    for (n, block) in blocks.iter().enumerate() {
+      assert!(utxo_set.update(block, n + 1, TxoValidation).is_ok());
+    }
+    let key = blind::SecretKey::generate(&mut task_rng(), 512);
+    let public = key.public_key().clone();
+    let policy = SessionPolicy {
+      mode: Blinded,
+      donation_required: false,
+      implicit_miner_fee: true,
+      ..Default::default()
+    };
+    let donation_address = FromBase58::from_base58check("1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa").unwrap();
+    let mut session = Session::new(100000000, Duration::minutes(10), Duration::minutes(10),
+                                   policy, Some(key), donation_address).unwrap();
+
+    // Two participants each spend a coinbase, and get a token for an output
+    // paying to a later block's coinbase script
+    let r = FromPrimitive::from_u64(1234567).unwrap();
+    let mut tokens = vec![];
+    for i in range(0u, 2) {
+      let script_pubkey = blocks[i + 2].txdata[0].output[0].script_pubkey.clone();
+      let msg = serialize(&script_pubkey).unwrap();
+      let blinded = public.blind(msg.as_slice(), &r);
+      let sig = session.register_inputs(&spend_coinbase(&blocks[i]), &blinded, &utxo_set).unwrap();
+      tokens.push((script_pubkey, public.unblind(&sig, &r).unwrap()));
+    }
+
+    // The first participant's coin is then spent outside the join. Its token
+    // is already out, so the session fails rather than dropping it...
+    session.process_transaction(&spend_coinbase(&blocks[0]));
+    assert_eq!(session.state(), Failed);
+    assert_eq!(session.failure, Some(InputSpent(blocks[0].txdata[0].bitcoin_hash(), 0)));
+    assert_eq!(session.unsigned.len(), 2);
+    assert_eq!(session.n_dropped, 0);
+
+    // ...and no output can be registered with the token
+    let (script_pubkey, sig) = tokens.remove(0).unwrap();
+    assert_eq!(session.register_output(script_pubkey, &sig),
+               Err(IncorrectState(Registering, Failed)));
+  }
+}
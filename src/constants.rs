@@ -15,18 +15,63 @@
 //! # Constants
 //!
 //! Defines compile-time constants which determine operation of the wallet.
-//! As a general rule, anything in here ought to be a user-configurable
-//! option, and is on the TODO list.
+//! Most of these are defaults for options in the configuration file; the
+//! rest ought to be, and are on the TODO list.
 //!
 
-/// The number of blocks to request at once during UTXO sync
-pub static UTXO_SYNC_N_BLOCKS: uint = 500;
+/// Default number of blocks to request at once during UTXO sync
+pub static DEFAULT_UTXO_SYNC_N_BLOCKS: uint = 500;
 
-/// The number of blocks to store full blockdata on in case of reorg
-pub static BLOCKCHAIN_N_FULL_BLOCKS: uint = 100;
+/// Most blocks that may be requested at once; peers reject larger `getdata`s
+pub static MAX_UTXO_SYNC_N_BLOCKS: uint = 50000;
 
-/// The save-to-disk frequency in s
-pub static SAVE_FREQUENCY: i64 = 600; // 10 minutes
+/// Default number of blocks to store full blockdata on in case of reorg
+pub static DEFAULT_BLOCKCHAIN_N_FULL_BLOCKS: uint = 100;
+
+/// Default depth of the deepest reorg we want to survive
+pub static DEFAULT_MAX_REORG_DEPTH: uint = 50;
+
+/// Default save-to-disk frequency in s
+pub static DEFAULT_SAVE_FREQUENCY: i64 = 600; // 10 minutes
+
+/// Block hashes which the main network's header chain must have at the given
+/// heights. The last one is also the default assume-valid block.
+pub static BITCOIN_CHECKPOINTS: [(uint, &'static str), ..13] = [
+  ( 11111, "0000000069e244f73d78e8fd29ba2fd2ed618bd6fa2ee92559f542fdb26e7c1d"),
+  ( 33333, "000000002dd5588a74784eaa7ab0507a18ad16a236e7b1ce69f00d7ddfb5d0a6"),
+  ( 74000, "0000000000573993a3c9e41ce34471c079dcf5f52a0e824a81e7f953b8661a20"),
+  (105000, "00000000000291ce28027faea320c8d2b054b2e0fe44a773f3eefb151d6bdc97"),
+  (134444, "00000000000005b12ffd4cd315cd34ffd4a594f430ac814c91184a0d42d2b0fe"),
+  (168000, "000000000000099e61ea72015e79632f216fe6cb33d7899acb35b75c8303b763"),
+  (193000, "000000000000059f452a5f7340de6682a977387c17010ff6e6c3bd83ca8b1317"),
+  (210000, "000000000000048b95347e83192f69cf0366076336c639f9b7228e9ba171342e"),
+  (216116, "00000000000001b4f4b433e81ee46494af945cf96014816a4e2370f11b23df4e"),
+  (225430, "00000000000001c108384350f74090433e7fcf79a606b8e797f065b130575932"),
+  (250000, "000000000000003887df1f29024b06fc2200b55f8af8f35453d7be294df2d214"),
+  (279000, "0000000000000001ae8c72a0b0c301f67e3afca10e819efa9041e458e9bd7e40"),
+  (295000, "00000000000000004d9b4ef50f0f9d686fd69db2e03af35a100370c64632a983")
+];
+
+/// Block hashes which the test network's header chain must have at the given heights
+pub static TESTNET_CHECKPOINTS: [(uint, &'static str), ..1] = [
+  (546, "000000002a936ca763904c3c35fce2f3556c559c0214345d31b1bcebf76acb70")
+];
+
+/// Default size in bytes at which the log file is rotated
+pub static DEFAULT_LOG_MAX_SIZE: u64 = 10 * 1024 * 1024;
+
+/// Default number of rotated log files to keep
+pub static DEFAULT_LOG_MAX_FILES: uint = 5;
+
+/// Seconds to wait before restarting a dead network task the first time;
+/// this doubles with each further death
+pub static RESTART_BACKOFF_MIN: i64 = 1;
+
+/// Most seconds to wait before restarting a dead network task
+pub static RESTART_BACKOFF_MAX: i64 = 300; // 5 minutes
+
+/// Seconds a network task must run before dying for the backoff to start over
+pub static RESTART_BACKOFF_RESET: i64 = 600; // 10 minutes
 
 /// Default peer address
 pub static DEFAULT_PEER_ADDR: &'static str = "localhost";
@@ -34,9 +79,27 @@ pub static DEFAULT_PEER_ADDR: &'static str = "localhost";
 /// Default peer port
 pub static DEFAULT_PEER_PORT: u16 = 8333;
 
+/// Default peer port on testnet
+pub static DEFAULT_TESTNET_PEER_PORT: u16 = 18333;
+
 /// Default RPC server address
 pub static DEFAULT_RPC_SERVER_ADDR: &'static str = "localhost";
 
 /// Default RPC server port
 pub static DEFAULT_RPC_SERVER_PORT: u16 = 8001;
 
+/// Default RPC server port on testnet. This used to be the same as on
+/// mainnet, so testnet RPC clients relying on the default must move.
+pub static DEFAULT_TESTNET_RPC_SERVER_PORT: u16 = 18001;
+
+/// Default public coinjoin listener address
+pub static DEFAULT_COINJOIN_RPC_ADDR: &'static str = "localhost";
+
+/// Default maximum size of a request to the public coinjoin listener, in bytes
+pub static DEFAULT_COINJOIN_RPC_MAX_REQUEST_SIZE: uint = 100000;
+
+/// Default maximum number of requests per minute from one IP to the public coinjoin listener
+pub static DEFAULT_COINJOIN_RPC_RATE_LIMIT: uint = 60;
+
+/// Default metrics endpoint address
+pub static DEFAULT_METRICS_ADDR: &'static str = "localhost";
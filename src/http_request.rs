@@ -0,0 +1,195 @@
+/* The Wizards' Wallet
+ * Written in 2014 by
+ *   Andrew Poelstra <apoelstra@wpsoftware.net>
+ *
+ * To the extent possible under law, the author(s) have dedicated all
+ * copyright and related and neighboring rights to this software to
+ * the public domain worldwide. This software is distributed without
+ * any warranty.
+ *
+ * You should have received a copy of the CC0 Public Domain Dedication
+ * along with this software.
+ * If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+ */
+
+//! # HTTP Requests
+//!
+//! Just enough HTTP/1.1 for our listeners, which each read a single request
+//! per connection and then close it. Everything read before a client can be
+//! authenticated or rate-limited is bounded, so that nobody can make us
+//! buffer more than a few kilobytes by sending an endless header.
+
+use std::ascii::StrAsciiExt;
+use std::io::{IoError, TimedOut};
+use std::str;
+
+/// Longest request or header line we will read, in bytes
+static MAX_LINE_LENGTH: uint = 8 * 1024;
+
+/// Most header lines we will read
+static MAX_HEADERS: uint = 64;
+
+/// How long a read from a client may take before we give up on it, in ms
+pub static READ_TIMEOUT_MS: u64 = 30 * 1000;
+
+/// An HTTP status code and reason phrase
+pub type Status = (uint, &'static str);
+
+/// Status for a well-formed request we have answered
+pub static OK: Status = (200, "OK");
+/// Status for a request we have nothing to answer to
+pub static NO_CONTENT: Status = (204, "No Content");
+/// Status for a malformed request
+pub static BAD_REQUEST: Status = (400, "Bad Request");
+/// Status for a request without valid credentials
+pub static UNAUTHORIZED: Status = (401, "Unauthorized");
+/// Status for a request for something we don't have
+pub static NOT_FOUND: Status = (404, "Not Found");
+/// Status for a request using the wrong method
+pub static METHOD_NOT_ALLOWED: Status = (405, "Method Not Allowed");
+/// Status for a client which took too long to send its request
+pub static REQUEST_TIMEOUT: Status = (408, "Request Timeout");
+/// Status for a request whose body is too large
+pub static ENTITY_TOO_LARGE: Status = (413, "Request Entity Too Large");
+/// Status for a request whose headers are too large
+pub static HEADERS_TOO_LARGE: Status = (431, "Request Header Fields Too Large");
+/// Status for a client which has exceeded its rate limit
+pub static TOO_MANY_REQUESTS: Status = (429, "Too Many Requests");
+
+/// The request line and headers of a request
+pub struct RequestHead {
+  /// The request method, e.g. `POST`
+  pub method: String,
+  /// The request target, e.g. `/metrics`
+  pub path: String,
+  /// Header names, in lowercase, and their values
+  pub headers: Vec<(String, String)>
+}
+
+impl RequestHead {
+  /// Returns the value of the first header with the given lowercase name
+  pub fn header<'a>(&'a self, name: &str) -> Option<&'a str> {
+    self.headers.iter().find(|&&(ref n, _)| n.as_slice() == name).map(|&(_, ref v)| v.as_slice())
+  }
+
+  /// Returns the length of the request body, which is zero if not given
+  pub fn content_length(&self) -> Result<uint, Status> {
+    match self.header("content-length") {
+      Some(value) => from_str(value).ok_or(BAD_REQUEST),
+      None => Ok(0)
+    }
+  }
+}
+
+/// Reads the request line and headers of a request, leaving the stream at
+/// the start of the body. Returns the status to reply with if there is no
+/// acceptable request to read.
+pub fn read_head<B: Buffer>(stream: &mut B) -> Result<RequestHead, Status> {
+  let request_line = try!(read_line(stream));
+  let mut words = request_line.as_slice().words();
+  let (method, path) = match (words.next(), words.next()) {
+    (Some(method), Some(path)) => (method.to_string(), path.to_string()),
+    _ => { return Err(BAD_REQUEST); }
+  };
+
+  let mut headers = vec![];
+  loop {
+    let line = try!(read_line(stream));
+    if line.is_empty() {
+      break;
+    }
+    if headers.len() == MAX_HEADERS {
+      return Err(HEADERS_TOO_LARGE);
+    }
+    match line.as_slice().find(':') {
+      Some(idx) => {
+        headers.push((line.as_slice().slice_to(idx).trim().to_ascii_lower(),
+                      line.as_slice().slice_from(idx + 1).trim().to_string()));
+      }
+      None => { return Err(BAD_REQUEST); }
+    }
+  }
+  Ok(RequestHead { method: method, path: path, headers: headers })
+}
+
+/// Reads a line, without its line ending, of no more than `MAX_LINE_LENGTH` bytes
+fn read_line<B: Buffer>(stream: &mut B) -> Result<String, Status> {
+  let mut line = vec![];
+  loop {
+    match stream.read_byte() {
+      Ok(b'\n') => { break; }
+      Ok(b) => {
+        if line.len() == MAX_LINE_LENGTH {
+          return Err(HEADERS_TOO_LARGE);
+        }
+        line.push(b);
+      }
+      Err(IoError { kind: TimedOut, .. }) => { return Err(REQUEST_TIMEOUT); }
+      Err(_) => { return Err(BAD_REQUEST); }
+    }
+  }
+  if line.last() == Some(&b'\r') {
+    line.pop();
+  }
+  match str::from_utf8(line.as_slice()) {
+    Some(s) => Ok(s.to_string()),
+    None => Err(BAD_REQUEST)
+  }
+}
+
+/// Writes an HTTP response with the given extra headers and closes the connection
+pub fn write_response<W: Writer>(w: &mut W, status: Status, headers: &[(&str, &str)], body: &str) {
+  let (code, reason) = status;
+  let mut head = format!("HTTP/1.1 {} {}\r\nConnection: close\r\nContent-Length: {}\r\n",
+                         code, reason, body.len());
+  for &(name, value) in headers.iter() {
+    head.push_str(format!("{}: {}\r\n", name, value).as_slice());
+  }
+  head.push_str("\r\n");
+  let _ = w.write_str(head.as_slice());
+  let _ = w.write_str(body);
+  let _ = w.flush();
+}
+
+#[cfg(test)]
+mod tests {
+  use std::io::MemReader;
+
+  use super::{BAD_REQUEST, HEADERS_TOO_LARGE, MAX_HEADERS, MAX_LINE_LENGTH, read_head};
+
+  fn reader(s: String) -> MemReader {
+    MemReader::new(s.into_bytes())
+  }
+
+  #[test]
+  fn heads_are_parsed() {
+    let mut r = reader("POST / HTTP/1.1\r\nContent-Length: 12\r\nX-Other:  a:b \r\n\r\nbody".to_string());
+    let head = read_head(&mut r).unwrap();
+    assert_eq!(head.method.as_slice(), "POST");
+    assert_eq!(head.path.as_slice(), "/");
+    assert_eq!(head.header("x-other"), Some("a:b"));
+    assert_eq!(head.content_length(), Ok(12));
+    assert_eq!(r.read_to_end().unwrap().as_slice(), b"body");
+
+    let mut r = reader("GET /metrics HTTP/1.1\r\nContent-Length: lots\r\n\r\n".to_string());
+    assert_eq!(read_head(&mut r).unwrap().content_length(), Err(BAD_REQUEST));
+  }
+
+  #[test]
+  fn heads_are_bounded() {
+    let long_line = String::from_char(MAX_LINE_LENGTH + 1, 'a');
+    let mut r = reader(format!("POST / HTTP/1.1\r\nX-Long: {}\r\n\r\n", long_line));
+    assert_eq!(read_head(&mut r).err(), Some(HEADERS_TOO_LARGE));
+
+    let mut request = "POST / HTTP/1.1\r\n".to_string();
+    for _ in range(0, MAX_HEADERS + 1) {
+      request.push_str("X-Header: a\r\n");
+    }
+    request.push_str("\r\n");
+    assert_eq!(read_head(&mut reader(request)).err(), Some(HEADERS_TOO_LARGE));
+
+    // A connection closed mid-header is no request at all
+    let mut r = reader("POST / HTTP/1.1\r\nContent-Le".to_string());
+    assert_eq!(read_head(&mut r).err(), Some(BAD_REQUEST));
+  }
+}
@@ -0,0 +1,80 @@
+/* The Wizards' Wallet
+ * Written in 2014 by
+ *   Andrew Poelstra <apoelstra@wpsoftware.net>
+ *
+ * To the extent possible under law, the author(s) have dedicated all
+ * copyright and related and neighboring rights to this software to
+ * the public domain worldwide. This software is distributed without
+ * any warranty.
+ *
+ * You should have received a copy of the CC0 Public Domain Dedication
+ * along with this software.
+ * If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+ */
+
+//! # The Wizards' Wallet
+//!
+//! The Wizards' Wallet is a SPV Bitcoin Wallet designed for ease of prototyping
+//! and a willingness to experiment with user interfaces, and exposing potentially
+//! dangerous or experimental ideas built on top of the Bitcoin protocol.
+//!
+//! It is also written entirely in Rust to illustrate the benefits of strong type
+//! safety, including ownership and lifetime, for financial and/or cryptographic
+//! software.
+//!
+//! This library holds the wallet itself; the `wizards-wallet` daemon and the
+//! `wizards-wallet-cli` client are thin executables built on it.
+//!
+
+
+#![crate_name = "wizards_wallet"]
+#![crate_type = "lib"]
+
+#![comment = "The Wizards' Wallet"]
+#![license = "CC0"]
+
+// Experimental features we need
+#![feature(globs)]
+#![feature(phase)]
+#![feature(macro_rules)]
+
+// Coding conventions
+#![warn(non_uppercase_statics)]
+#![deny(non_camel_case_types)]
+#![deny(non_snake_case)]
+#![deny(unused_mut)]
+#![warn(missing_doc)]
+
+extern crate libc;
+extern crate num;
+extern crate rand;
+extern crate rustrt;
+extern crate serialize;
+extern crate sync;
+extern crate time;
+
+#[phase(plugin,link)] extern crate bitcoin;
+extern crate "rust-crypto" as crypto;
+extern crate jsonrpc;
+extern crate openssl;
+#[phase(plugin)] extern crate phf_mac;
+extern crate phf;
+extern crate toml;
+extern crate xdg;
+
+pub mod bitcoind;
+pub mod coinjoin;
+pub mod constants;
+pub mod http_request;
+pub mod logger;
+pub mod metrics;
+#[cfg(test)]
+pub mod mock_peer;
+pub mod rpc_client;
+pub mod rpc_listener;
+pub mod rpc_server;
+pub mod snapshot;
+pub mod supervisor;
+pub mod user_data;
+pub mod verify;
+pub mod wallet;
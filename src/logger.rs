@@ -0,0 +1,233 @@
+/* The Wizards' Wallet
+ * Written in 2014 by
+ *   Andrew Poelstra <apoelstra@wpsoftware.net>
+ *
+ * To the extent possible under law, the author(s) have dedicated all
+ * copyright and related and neighboring rights to this software to
+ * the public domain worldwide. This software is distributed without
+ * any warranty.
+ *
+ * You should have received a copy of the CC0 Public Domain Dedication
+ * along with this software.
+ * If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+ */
+
+//! # Logging
+//!
+//! Log messages are tagged with the subsystem they come from, and kept if
+//! they are at least as severe as that subsystem's level. Each network has
+//! its own logger, which writes to stdout and to a log file, as plain text
+//! or JSON lines. The file is rotated once it grows too large.
+//!
+
+use std::collections::{HashMap, TreeMap};
+use std::io::{File, IoResult, Append, Write};
+use std::io::fs;
+use std::sync::{Arc, RWLock};
+use serialize::json;
+use time;
+
+use bitcoin::network::constants::Network;
+
+use user_data::NetworkConfig;
+
+user_enum!(
+  #[doc="An error message severity level"]
+  #[deriving(Clone, PartialEq, Eq, PartialOrd, Ord)]
+  pub enum DebugLevel {
+    #[doc="Developer interest only"]
+    Debug <-> "DEBUG",
+    #[doc="Detailed information about program state"]
+    Notice <-> "NOTE",
+    #[doc="High-level information about program state"]
+    Status <-> "STATUS",
+    #[doc="Something went possibly wrong."]
+    Warning <-> "WARN",
+    #[doc="Something went wrong."]
+    Error <-> "ERROR",
+    #[doc="Something went wrong, and the program must end because of it."]
+    Fatal <-> "FATAL"
+  }
+)
+
+user_enum!(
+  #[doc="A part of the wallet which logs messages, with its own level"]
+  #[deriving(Clone, PartialEq, Eq, Hash)]
+  pub enum Subsystem {
+    #[doc="Blockchain and UTXO set sync, and saving them to disk"]
+    SyncLog <-> "sync",
+    #[doc="The connection to the network peer"]
+    P2pLog <-> "p2p",
+    #[doc="RPC requests"]
+    RpcLog <-> "rpc",
+    #[doc="The coinjoin server"]
+    CoinjoinLog <-> "coinjoin",
+    #[doc="The user's wallet"]
+    WalletLog <-> "wallet"
+  }
+)
+
+user_enum!(
+  #[doc="How log messages are written out"]
+  #[deriving(Clone, PartialEq, Eq)]
+  pub enum LogFormat {
+    #[doc="One human-readable line per message"]
+    TextFormat <-> "text",
+    #[doc="One JSON object per line"]
+    JsonFormat <-> "json"
+  }
+)
+
+// The parts of the logger which may change while running
+struct Settings {
+  default_level: DebugLevel,
+  levels: HashMap<Subsystem, DebugLevel>,
+  format: LogFormat
+}
+
+/// A handle to a network's logger. Clones share their levels, so changes
+/// made through one are seen by all.
+#[deriving(Clone)]
+pub struct Logger {
+  network: Network,
+  settings: Arc<RWLock<Settings>>,
+  tx: Sender<String>
+}
+
+impl Logger {
+  /// Starts a logger for the given network, with a task to write its output
+  pub fn new(config: &NetworkConfig) -> Logger {
+    let (tx, rx) = channel();
+    let path = config.log_path.clone();
+    let max_size = config.log_max_size;
+    let max_files = config.log_max_files;
+    spawn(proc() {
+      let mut file = match path {
+        Some(path) => match LogFile::open(path, max_size, max_files) {
+          Ok(file) => Some(file),
+          Err(e) => { println!("Failed to open log file: {}, logging to stdout only.", e); None }
+        },
+        None => None
+      };
+      for line in rx.iter() {
+        println!("{}", line);
+        match file {
+          Some(ref mut file) => {
+            match file.write_line(line.as_slice()) {
+              Err(e) => { println!("Failed to write log file: {}", e); }
+              Ok(()) => {}
+            }
+          }
+          None => {}
+        }
+      }
+    });
+
+    Logger {
+      network: config.network,
+      settings: Arc::new(RWLock::new(Settings {
+        default_level: config.debug_level,
+        levels: config.log_levels.clone(),
+        format: config.log_format
+      })),
+      tx: tx
+    }
+  }
+
+  /// Whether a message from the subsystem at the given level would be kept
+  pub fn enabled(&self, subsystem: Subsystem, level: DebugLevel) -> bool {
+    level >= self.level(subsystem)
+  }
+
+  /// Gets the level of a subsystem
+  pub fn level(&self, subsystem: Subsystem) -> DebugLevel {
+    let settings = self.settings.read();
+    settings.levels.find(&subsystem).map_or(settings.default_level, |l| *l)
+  }
+
+  /// Sets the level of a subsystem, returning the old one
+  pub fn set_level(&self, subsystem: Subsystem, level: DebugLevel) -> DebugLevel {
+    let old = self.level(subsystem);
+    self.settings.write().levels.insert(subsystem, level);
+    old
+  }
+
+  /// Takes on the levels and format of a reloaded configuration. The log
+  /// file and its rotation are fixed at startup.
+  pub fn reload(&self, config: &NetworkConfig) {
+    let mut settings = self.settings.write();
+    settings.default_level = config.debug_level;
+    settings.levels = config.log_levels.clone();
+    settings.format = config.log_format;
+  }
+
+  /// Logs a message, whatever its level
+  pub fn log(&self, subsystem: Subsystem, level: DebugLevel, message: String) {
+    let now = time::now().rfc3339();
+    let line = match self.settings.read().format {
+      TextFormat => format!("{} [{:6}] {}/{}: {}", now, level, self.network, subsystem, message),
+      JsonFormat => {
+        let mut obj = TreeMap::new();
+        obj.insert("time".to_string(), json::String(now.to_string()));
+        obj.insert("level".to_string(), json::String(level.to_string()));
+        obj.insert("network".to_string(), json::String(self.network.to_string()));
+        obj.insert("subsystem".to_string(), json::String(subsystem.to_string()));
+        obj.insert("message".to_string(), json::String(message));
+        json::Object(obj).to_string()
+      }
+    };
+    // If the writer task is gone there is nowhere left to complain to
+    let _ = self.tx.send_opt(line);
+  }
+}
+
+// A log file which is rotated once it reaches `max_size` bytes, keeping
+// `max_files` old files alongside it as `<path>.1`, `<path>.2` and so on
+struct LogFile {
+  path: Path,
+  file: File,
+  size: u64,
+  max_size: u64,
+  max_files: uint
+}
+
+impl LogFile {
+  fn open(path: Path, max_size: u64, max_files: uint) -> IoResult<LogFile> {
+    let file = try!(File::open_mode(&path, Append, Write));
+    let size = fs::stat(&path).map(|s| s.size).unwrap_or(0);
+    Ok(LogFile { path: path, file: file, size: size, max_size: max_size, max_files: max_files })
+  }
+
+  fn write_line(&mut self, line: &str) -> IoResult<()> {
+    try!(self.file.write_line(line));
+    self.size += line.len() as u64 + 1;
+    if self.size >= self.max_size {
+      try!(self.rotate());
+    }
+    Ok(())
+  }
+
+  fn rotate(&mut self) -> IoResult<()> {
+    {
+      let path = &self.path;
+      let rotated = |n: uint| path.with_extension(match path.extension_str() {
+        Some(ext) => format!("{}.{}", ext, n),
+        None => n.to_string()
+      });
+      if self.max_files == 0 {
+        try!(fs::unlink(path));
+      } else {
+        // The oldest file falls off the end
+        for n in range(1, self.max_files).rev() {
+          if rotated(n).exists() {
+            try!(fs::rename(&rotated(n), &rotated(n + 1)));
+          }
+        }
+        try!(fs::rename(path, &rotated(1)));
+      }
+    }
+    self.file = try!(File::create(&self.path));
+    self.size = 0;
+    Ok(())
+  }
+}
@@ -12,15 +12,10 @@
  * If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
  */
 
-//! # The Wizards' Wallet
+//! # The Wizards' Wallet Daemon
 //!
-//! The Wizards' Wallet is a SPV Bitcoin Wallet designed for ease of prototyping
-//! and a willingness to experiment with user interfaces, and exposing potentially
-//! dangerous or experimental ideas built on top of the Bitcoin protocol.
-//!
-//! It is also written entirely in Rust to illustrate the benefits of strong type
-//! safety, including ownership and lifetime, for financial and/or cryptographic
-//! software.
+//! Starts the wallet for each configured network, along with its RPC
+//! listeners, and reloads the configuration on SIGHUP.
 //!
 
 
@@ -41,79 +36,351 @@
 #![deny(unused_mut)]
 #![warn(missing_doc)]
 
-extern crate num;
-extern crate rand;
-extern crate rustrt;
+extern crate getopts;
+extern crate libc;
 extern crate serialize;
-extern crate sync;
-extern crate time;
 
-#[phase(plugin,link)] extern crate bitcoin;
-extern crate "rust-crypto" as crypto;
-extern crate http;
-extern crate jsonrpc;
-#[phase(plugin)] extern crate phf_mac;
-extern crate phf;
-extern crate toml;
-extern crate xdg;
+extern crate bitcoin;
+extern crate wizards_wallet;
 
 #[cfg(not(test))]
-use bitcoind::Bitcoind;
+use std::io::{File, IoError, IoResult, PathAlreadyExists};
+#[cfg(not(test))]
+use std::io::fs;
+#[cfg(not(test))]
+use std::io::signal;
+#[cfg(not(test))]
+use std::os;
 #[cfg(not(test))]
-use jsonrpc::server::JsonRpcServer;
+use serialize::{Decodable, json};
+
 #[cfg(not(test))]
-use http::server::Server;
+use getopts::{getopts, optflag, optopt, usage};
+
 #[cfg(not(test))]
-use user_data::{config_path, load_configuration};
-// Public exports to get documentation
-pub mod bitcoind;
-pub mod coinjoin;
-pub mod constants;
-pub mod rpc_server;
-pub mod user_data;
-pub mod wallet;
+use bitcoin::network::constants::Network;
+
+#[cfg(not(test))]
+use wizards_wallet::coinjoin::listener::Listener;
+#[cfg(not(test))]
+use wizards_wallet::logger::DebugLevel;
+#[cfg(not(test))]
+use wizards_wallet::metrics::{Metrics, MetricsListener};
+#[cfg(not(test))]
+use wizards_wallet::rpc_listener::{RpcListener, remove_cookie};
+#[cfg(not(test))]
+use wizards_wallet::supervisor::Supervisor;
+#[cfg(not(test))]
+use wizards_wallet::user_data::{NetworkConfig, config_path, load_configuration, pid_path,
+                                read_configuration};
 
 /// Entry point
 #[cfg(not(test))]
 fn main()
 {
+  let args = os::args();
+  let program = args[0].clone();
+  let opts = [
+    optopt("c", "config", "read configuration from PATH", "PATH"),
+    optopt("d", "datadir", "store data files without a configured path in DIR", "DIR"),
+    optopt("n", "network", "only start NETWORK (bitcoin or testnet)", "NETWORK"),
+    optopt("", "debug", "override the debug level of every network and subsystem", "LEVEL"),
+    optopt("", "rpcport", "listen for RPC on PORT (default 8001, or 18001 on testnet); requires a single network", "PORT"),
+    optflag("", "daemon", "detach and run in the background"),
+    optopt("", "pidfile", "with --daemon, write the process ID to PATH", "PATH"),
+    optflag("", "check-config", "check the configuration file and exit"),
+    optflag("h", "help", "print this message")
+  ];
+  let matches = match getopts(args.tail(), opts) {
+    Ok(m) => m,
+    Err(e) => { println!("{}", e); os::set_exit_status(1); return; }
+  };
+  if matches.opt_present("h") {
+    println!("{}", usage(format!("Usage: {} [options]", program).as_slice(), opts));
+    return;
+  }
+
+  // The daemon leaves the working directory, so paths are made absolute up front
+  let cwd = os::getcwd();
+  let path = matches.opt_str("c").map_or(config_path(), |s| cwd.join(s));
+  let data_dir = matches.opt_str("d").map(|s| cwd.join(s));
+
+  if matches.opt_present("check-config") {
+    match read_configuration(&path, data_dir.as_ref()) {
+      Ok(config) => {
+        let networks: Vec<String> = config.move_iter().map(|c| c.network.to_string()).collect();
+        println!("{}: OK, configures {}", path.display(), networks.connect(", "));
+      }
+      Err(e) => {
+        println!("{}", e.desc);
+        match e.detail {
+          Some(detail) => { println!("{}", detail); }
+          None => { println!("{}", path.display()); }
+        }
+        os::set_exit_status(1);
+      }
+    }
+    return;
+  }
+
+  let network: Option<Network> = match matches.opt_str("n") {
+    Some(s) => match decode_flag("network", s) { Some(n) => Some(n), None => { return; } },
+    None => None
+  };
+  let debug_level: Option<DebugLevel> = match matches.opt_str("debug") {
+    Some(s) => match decode_flag("debug", s) { Some(l) => Some(l), None => { return; } },
+    None => None
+  };
+  let rpc_port = match matches.opt_str("rpcport") {
+    Some(s) => match from_str::<u16>(s.as_slice()) {
+      Some(port) => Some(port),
+      None => { println!("Invalid value `{}` for --rpcport.", s); os::set_exit_status(1); return; }
+    },
+    None => None
+  };
+
   println!("Starting the Wizards' Wallet");
 
-  let config = match load_configuration(&config_path()) {
+  let config = match load_configuration(&path, data_dir.as_ref()) {
       Some(config) => config,
       None => { println!("Failed to load configuration. Shutting down."); return; }
     };
 
+  // Apply command-line overrides
+  let mut config: Vec<NetworkConfig> = config.move_iter()
+                                             .filter(|c| network.map_or(true, |n| c.network == n))
+                                             .collect();
+  if config.is_empty() {
+    match network {
+      Some(network) => { println!("No configuration for network {}. Shutting down.", network); }
+      None => { println!("No networks are configured. Shutting down."); }
+    }
+    os::set_exit_status(1);
+    return;
+  }
+  if rpc_port.is_some() && config.len() > 1 {
+    println!("--rpcport needs a single network; select one with --network. Shutting down.");
+    os::set_exit_status(1);
+    return;
+  }
+  for config in config.mut_iter() {
+    apply_overrides(config, debug_level, rpc_port);
+  }
+
+  let pid_file = if matches.opt_present("daemon") {
+    let pid_file = matches.opt_str("pidfile").map_or(pid_path(data_dir.as_ref()), |s| cwd.join(s));
+    match daemonize(&pid_file) {
+      Ok(()) => {}
+      Err(e) => { println!("Failed to start daemon: {}", e); os::set_exit_status(1); return; }
+    }
+    Some(pid_file)
+  } else {
+    None
+  };
+
+  let mut reload_txs = vec![];
+  let mut cookie_paths = vec![];
   for config in config.move_iter() {
     let network = config.network;
     println!("main: Starting a listener for {}", network);
     // Connect to bitcoind
-    let (jsonrpc, rpc_rx) = match JsonRpcServer::new(config.rpc_server_addr.as_slice(),
-                                                     config.rpc_server_port) {
+    let (rpc_listener, rpc_rx) = match RpcListener::new(&config) {
       Err(e) => {
         println!("{}: RPC server: {}, failed to start.", network, e);
         break;
       }
       Ok(tup) => tup
     };
-    // Start bitcoind
-    let bitcoind = Bitcoind::new(config, rpc_rx);
-    spawn(proc() {
-      let mut bitcoind = bitcoind;
-      match bitcoind.listen() {
-        Err(e) => {
-          println!("{}: Got error {:}, failed to start.", network, e);
-        }
-        _ => {}
+    cookie_paths.push(config.rpc_cookie_path.clone());
+    // Start the public coinjoin listener, if there is one. If not, we still
+    // need a channel for bitcoind to listen on, which must not hang up.
+    let (coinjoin_listener, coinjoin_rx, coinjoin_tx) = match config.coinjoin_rpc_port {
+      Some(port) if config.coinjoin_on => {
+        let (listener, rx) = Listener::new(config.coinjoin_rpc_addr.as_slice(), port,
+                                           config.coinjoin_rpc_max_request_size,
+                                           config.coinjoin_rpc_rate_limit);
+        (Some(listener), rx, None)
       }
+      _ => {
+        let (tx, rx) = channel();
+        (None, rx, Some(tx))
+      }
+    };
+    // Start bitcoind, under a supervisor which restarts it if it dies
+    let (reload_tx, reload_rx) = channel();
+    reload_txs.push((network, reload_tx));
+    let metrics = Metrics::new(network);
+    let metrics_listener = MetricsListener::new(&config, metrics.clone());
+    let supervisor = Supervisor::new(config, metrics, rpc_rx, coinjoin_rx, reload_rx);
+    spawn(proc() {
+      let _coinjoin_tx = coinjoin_tx;
+      let mut supervisor = supervisor;
+      supervisor.run();
     });
     // Start the RPC server
     spawn (proc() {
       println!("{}: Starting JSON RPC server...", network);
-      jsonrpc.serve_forever();
+      match rpc_listener.serve_forever() {
+        Err(e) => { println!("{}: JSON RPC server: {}", network, e); }
+        Ok(()) => {}
+      }
       println!("{}: JSON RPC server shut down.", network);
     });
+    // Start the public coinjoin listener
+    match coinjoin_listener {
+      Some(listener) => {
+        spawn(proc() {
+          println!("{}: Starting public coinjoin listener...", network);
+          match listener.serve_forever() {
+            Err(e) => { println!("{}: Public coinjoin listener: {}", network, e); }
+            Ok(()) => {}
+          }
+          println!("{}: Public coinjoin listener shut down.", network);
+        });
+      }
+      None => {}
+    }
+    // Start the metrics endpoint
+    match metrics_listener {
+      Some(listener) => {
+        spawn(proc() {
+          println!("{}: Starting metrics endpoint...", network);
+          match listener.serve_forever() {
+            Err(e) => { println!("{}: Metrics endpoint: {}", network, e); }
+            Ok(()) => {}
+          }
+          println!("{}: Metrics endpoint shut down.", network);
+        });
+      }
+      None => {}
+    }
   }
   println!("main: started all networks");
+
+  // Reload the configuration on SIGHUP, passing it on to each network, and
+  // clean up after ourselves on SIGINT. The channels must stay open for the
+  // networks to keep running, so if we can't listen for signals we just
+  // block here forever.
+  let mut signals = signal::Listener::new();
+  match signals.register(signal::HangUp) {
+    Ok(()) => {}
+    Err(e) => { println!("main: cannot listen for SIGHUP: {}, configuration will not be reloaded.", e); }
+  }
+  match signals.register(signal::Interrupt) {
+    Ok(()) => {}
+    Err(e) => { println!("main: cannot listen for SIGINT: {}", e); }
+  }
+  for signum in signals.rx.iter() {
+    match signum {
+      signal::Interrupt => {
+        println!("main: received SIGINT, shutting down");
+        for path in cookie_paths.iter() {
+          let _ = remove_cookie(path);
+        }
+        match pid_file {
+          Some(ref pid_file) => { let _ = fs::unlink(pid_file); }
+          None => {}
+        }
+        unsafe { libc::exit(0); }
+      }
+      _ => {}
+    }
+    println!("main: received SIGHUP, reloading {}", path.display());
+    match read_configuration(&path, data_dir.as_ref()) {
+      Ok(new_config) => {
+        for mut new_config in new_config.move_iter() {
+          apply_overrides(&mut new_config, debug_level, rpc_port);
+          for &(network, ref tx) in reload_txs.iter() {
+            if network == new_config.network {
+              let _ = tx.send_opt(new_config.clone());
+            }
+          }
+        }
+      }
+      Err(e) => { println!("main: failed to reload configuration: {}, keeping the old one.", e); }
+    }
+  }
 }
 
+/// Applies command-line overrides to a network's configuration
+#[cfg(not(test))]
+fn apply_overrides(config: &mut NetworkConfig, debug_level: Option<DebugLevel>, rpc_port: Option<u16>) {
+  match debug_level {
+    Some(level) => {
+      config.debug_level = level;
+      config.log_levels.clear();
+    }
+    None => {}
+  }
+  match rpc_port {
+    Some(port) => { config.rpc_server_port = port; }
+    None => {}
+  }
+}
+
+/// Decodes a command-line flag in the same way as the configuration file,
+/// reporting invalid values
+#[cfg(not(test))]
+fn decode_flag<T: Decodable<json::Decoder, json::DecoderError>>(flag: &str, value: String) -> Option<T> {
+  let mut decoder = json::Decoder::new(json::String(value.clone()));
+  match Decodable::decode(&mut decoder) {
+    Ok(res) => Some(res),
+    Err(_) => {
+      println!("Invalid value `{}` for --{}.", value, flag);
+      os::set_exit_status(1);
+      None
+    }
+  }
+}
+
+/// Forks into the background, detaches from the terminal and writes the
+/// process ID of the child to `pid_file`, which is refused if it names a
+/// process which is still running. Only the child returns.
+#[cfg(not(test))]
+fn daemonize(pid_file: &Path) -> IoResult<()> {
+  // Check for another daemon before forking, so that the user sees the error
+  if pid_file.exists() {
+    let old_pid = try!(File::open(pid_file).read_to_string());
+    match from_str::<libc::pid_t>(old_pid.as_slice().trim()) {
+      Some(pid) if unsafe { libc::kill(pid, 0) } == 0 => {
+        return Err(IoError {
+          kind: PathAlreadyExists,
+          desc: "the wallet is already running",
+          detail: Some(format!("process {} holds {}", pid, pid_file.display()))
+        });
+      }
+      // Left behind by a daemon which didn't shut down cleanly
+      _ => { try!(fs::unlink(pid_file)); }
+    }
+  }
+
+  unsafe {
+    match libc::fork() {
+      -1 => { return Err(IoError::last_error()); }
+      0 => {}
+      _ => { libc::exit(0); }
+    }
+    if libc::setsid() == -1 {
+      return Err(IoError::last_error());
+    }
+    // Don't hold on to the directory we were started in, and don't let
+    // anybody else read the files we create
+    if "/".with_c_str(|path| libc::chdir(path)) == -1 {
+      return Err(IoError::last_error());
+    }
+    libc::umask(0o027);
+    // Point stdin, stdout and stderr at /dev/null
+    let null = "/dev/null".with_c_str(|path| libc::open(path, libc::O_RDWR, 0));
+    if null == -1 {
+      return Err(IoError::last_error());
+    }
+    for fd in range(0, 3) {
+      libc::dup2(null, fd);
+    }
+    if null > 2 {
+      libc::close(null);
+    }
+  }
+  let mut file = try!(File::create(pid_file));
+  file.write_str(format!("{}\n", unsafe { libc::getpid() }).as_slice())
+}
@@ -0,0 +1,286 @@
+/* The Wizards' Wallet
+ * Written in 2014 by
+ *   Andrew Poelstra <apoelstra@wpsoftware.net>
+ *
+ * To the extent possible under law, the author(s) have dedicated all
+ * copyright and related and neighboring rights to this software to
+ * the public domain worldwide. This software is distributed without
+ * any warranty.
+ *
+ * You should have received a copy of the CC0 Public Domain Dedication
+ * along with this software.
+ * If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+ */
+
+//! # Metrics
+//!
+//! Counters and gauges describing a network's task, and an optional HTTP
+//! listener which serves them at `/metrics` in the Prometheus text format.
+//! Every metric is labelled with the network, so that several networks can
+//! be scraped into the same place.
+//!
+
+use std::collections::TreeMap;
+use std::default::Default;
+use std::io::{BufferedStream, IoResult};
+use std::io::{Acceptor, Listener};
+use std::io::net::tcp::TcpListener;
+use std::sync::{Arc, RWLock};
+use serialize::json::ToJson;
+
+use bitcoin::network::constants::Network;
+
+use coinjoin::server::{SessionState, Joining, Registering, Merging, Complete,
+                       Expired, Failed, Unmerged};
+use http_request::{METHOD_NOT_ALLOWED, NOT_FOUND, OK, READ_TIMEOUT_MS, read_head, write_response};
+use user_data::NetworkConfig;
+
+/// Every session state, in the order they are reported
+static SESSION_STATES: [SessionState, ..7] = [Joining, Registering, Merging, Complete,
+                                              Expired, Failed, Unmerged];
+
+// A count of events, and the total time they took
+#[deriving(Clone, Default)]
+struct Timing {
+  count: u64,
+  total_ns: u64
+}
+
+impl Timing {
+  fn record(&mut self, ns: u64) {
+    self.count += 1;
+    self.total_ns += ns;
+  }
+}
+
+// Per-call RPC statistics
+#[deriving(Clone, Default)]
+struct RpcStats {
+  timing: Timing,
+  errors: u64
+}
+
+#[deriving(Clone)]
+struct MetricsData {
+  header_height: u64,
+  utxo_height: u64,
+  n_utxos: u64,
+  n_pruned: u64,
+  reorgs: u64,
+  reconnects: u64,
+  connect_failures: u64,
+  restarts: u64,
+  rpc_calls: TreeMap<&'static str, RpcStats>,
+  saves: Timing,
+  // Indexed like `SESSION_STATES`
+  coinjoin_sessions: Vec<u64>
+}
+
+/// A handle to a network's metrics. Clones share the same data.
+#[deriving(Clone)]
+pub struct Metrics {
+  network: Network,
+  data: Arc<RWLock<MetricsData>>
+}
+
+impl Metrics {
+  /// Creates a fresh set of metrics for a network
+  pub fn new(network: Network) -> Metrics {
+    Metrics {
+      network: network,
+      data: Arc::new(RWLock::new(MetricsData {
+        header_height: 0,
+        utxo_height: 0,
+        n_utxos: 0,
+        n_pruned: 0,
+        reorgs: 0,
+        reconnects: 0,
+        connect_failures: 0,
+        restarts: 0,
+        rpc_calls: TreeMap::new(),
+        saves: Timing { count: 0, total_ns: 0 },
+        coinjoin_sessions: Vec::from_elem(SESSION_STATES.len(), 0)
+      }))
+    }
+  }
+
+  /// Records the height of the best header
+  pub fn set_header_height(&self, height: u64) {
+    self.data.write().header_height = height;
+  }
+
+  /// Records the height and size of the UTXO set
+  pub fn set_utxo_set(&self, height: u64, n_utxos: u64, n_pruned: u64) {
+    let mut data = self.data.write();
+    data.utxo_height = height;
+    data.n_utxos = n_utxos;
+    data.n_pruned = n_pruned;
+  }
+
+  /// Counts a reorg, i.e. a UTXO sync which had to rewind stale blocks
+  pub fn record_reorg(&self) {
+    self.data.write().reorgs += 1;
+  }
+
+  /// Counts a reconnection to the peer after the connection failed
+  pub fn record_reconnect(&self) {
+    self.data.write().reconnects += 1;
+  }
+
+  /// Counts a failed attempt to connect to the peer
+  pub fn record_connect_failure(&self) {
+    self.data.write().connect_failures += 1;
+  }
+
+  /// Counts a restart of the network task by its supervisor
+  pub fn record_restart(&self) {
+    self.data.write().restarts += 1;
+  }
+
+  /// Records an RPC call, with how long it took and whether it succeeded
+  pub fn record_rpc(&self, name: &'static str, ns: u64, ok: bool) {
+    let mut data = self.data.write();
+    if data.rpc_calls.find(&name).is_none() {
+      data.rpc_calls.insert(name, Default::default());
+    }
+    let stats = data.rpc_calls.find_mut(&name).unwrap();
+    stats.timing.record(ns);
+    if !ok {
+      stats.errors += 1;
+    }
+  }
+
+  /// Records how long it took to save the blockchain and UTXO set
+  pub fn record_save(&self, ns: u64) {
+    self.data.write().saves.record(ns);
+  }
+
+  /// Records the states of all the coinjoin server's sessions
+  pub fn set_coinjoin_sessions(&self, states: &[SessionState]) {
+    let mut counts = Vec::from_elem(SESSION_STATES.len(), 0u64);
+    for state in states.iter() {
+      match SESSION_STATES.iter().position(|s| s == state) {
+        Some(idx) => { *counts.get_mut(idx) += 1; }
+        None => {}
+      }
+    }
+    self.data.write().coinjoin_sessions = counts;
+  }
+
+  /// Renders every metric in the Prometheus text exposition format
+  pub fn render(&self) -> String {
+    let data = self.data.read().clone();
+    let network = format!("network=\"{}\"", self.network);
+    let mut ret = String::new();
+    let metric = |ret: &mut String, name: &str, kind: &str, help: &str,
+                  values: Vec<(String, String)>| {
+      ret.push_str(format!("# HELP wizards_wallet_{} {}\n", name, help).as_slice());
+      ret.push_str(format!("# TYPE wizards_wallet_{} {}\n", name, kind).as_slice());
+      for (labels, value) in values.move_iter() {
+        ret.push_str(format!("wizards_wallet_{}{{{}{}}} {}\n",
+                             name, network, labels, value).as_slice());
+      }
+    };
+    let single = |value: u64| vec![("".to_string(), value.to_string())];
+
+    metric(&mut ret, "header_height", "gauge", "Height of the best block header",
+           single(data.header_height));
+    metric(&mut ret, "utxo_height", "gauge", "Height of the last block applied to the UTXO set",
+           single(data.utxo_height));
+    metric(&mut ret, "utxos", "gauge", "Number of unspent outputs in the UTXO set",
+           single(data.n_utxos));
+    metric(&mut ret, "utxos_pruned", "gauge", "Number of provably unspendable outputs pruned",
+           single(data.n_pruned));
+    metric(&mut ret, "reorgs_total", "counter", "Number of UTXO syncs which rewound stale blocks",
+           single(data.reorgs));
+    metric(&mut ret, "peer_reconnects_total", "counter",
+           "Number of reconnections to the peer after the connection failed",
+           single(data.reconnects));
+    metric(&mut ret, "peer_connect_failures_total", "counter",
+           "Number of failed attempts to connect to the peer",
+           single(data.connect_failures));
+    metric(&mut ret, "restarts_total", "counter",
+           "Number of times the network task was restarted after dying",
+           single(data.restarts));
+
+    metric(&mut ret, "rpc_calls_total", "counter", "Number of RPC calls",
+           data.rpc_calls.iter().map(|(name, stats)| {
+             (method_label(*name), stats.timing.count.to_string())
+           }).collect());
+    metric(&mut ret, "rpc_errors_total", "counter", "Number of RPC calls which returned an error",
+           data.rpc_calls.iter().map(|(name, stats)| {
+             (method_label(*name), stats.errors.to_string())
+           }).collect());
+    metric(&mut ret, "rpc_call_seconds_total", "counter", "Time spent handling RPC calls",
+           data.rpc_calls.iter().map(|(name, stats)| {
+             (method_label(*name), seconds(stats.timing.total_ns))
+           }).collect());
+
+    metric(&mut ret, "saves_total", "counter", "Number of saves of the blockchain and UTXO set",
+           single(data.saves.count));
+    metric(&mut ret, "save_seconds_total", "counter",
+           "Time spent saving the blockchain and UTXO set",
+           vec![("".to_string(), seconds(data.saves.total_ns))]);
+
+    metric(&mut ret, "coinjoin_sessions", "gauge", "Number of coinjoin sessions in each state",
+           SESSION_STATES.iter().zip(data.coinjoin_sessions.iter()).map(|(state, count)| {
+             (format!(",state={}", state.to_json()), count.to_string())
+           }).collect());
+    ret
+  }
+}
+
+/// Formats the label for an RPC call's metrics
+fn method_label(name: &str) -> String {
+  format!(",method=\"{}\"", name)
+}
+
+/// Formats a duration in nanoseconds as seconds
+fn seconds(ns: u64) -> String {
+  format!("{:.6f}", ns as f64 / 1e9)
+}
+
+/// An HTTP listener serving a network's metrics
+pub struct MetricsListener {
+  addr: String,
+  port: u16,
+  metrics: Metrics
+}
+
+impl MetricsListener {
+  /// Creates a listener for the configured metrics address and port, or
+  /// None if the endpoint is not enabled
+  pub fn new(config: &NetworkConfig, metrics: Metrics) -> Option<MetricsListener> {
+    config.metrics_port.map(|port| MetricsListener {
+      addr: config.metrics_addr.clone(),
+      port: port,
+      metrics: metrics
+    })
+  }
+
+  /// Accepts connections until an error occurs, handling each in its own task
+  pub fn serve_forever(&self) -> IoResult<()> {
+    let mut acceptor = try!(TcpListener::bind(self.addr.as_slice(), self.port).listen());
+    loop {
+      let mut stream = try!(acceptor.accept());
+      stream.set_read_timeout(Some(READ_TIMEOUT_MS));
+      let metrics = self.metrics.clone();
+      spawn(proc() {
+        let mut stream = BufferedStream::new(stream);
+        // We need nothing from the headers, but still read them, within bounds
+        let head = match read_head(&mut stream) {
+          Ok(head) => head,
+          Err(status) => { write_response(&mut stream, status, [], ""); return; }
+        };
+        match (head.method.as_slice(), head.path.as_slice()) {
+          ("GET", "/metrics") => {
+            write_response(&mut stream, OK, [("Content-Type", "text/plain; version=0.0.4")],
+                           metrics.render().as_slice());
+          }
+          ("GET", _) => { write_response(&mut stream, NOT_FOUND, [], ""); }
+          _ => { write_response(&mut stream, METHOD_NOT_ALLOWED, [], ""); }
+        }
+      });
+    }
+  }
+}
@@ -0,0 +1,412 @@
+/* The Wizards' Wallet
+ * Written in 2014 by
+ *   Andrew Poelstra <apoelstra@wpsoftware.net>
+ *
+ * To the extent possible under law, the author(s) have dedicated all
+ * copyright and related and neighboring rights to this software to
+ * the public domain worldwide. This software is distributed without
+ * any warranty.
+ *
+ * You should have received a copy of the CC0 Public Domain Dedication
+ * along with this software.
+ * If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+ */
+
+//! # Mock Peer
+//!
+//! An in-process fake Bitcoin peer, for testing the network state machine
+//! end to end. It listens on a local port and speaks the P2P protocol to
+//! whoever connects: `getheaders` and `getdata` are answered from a canned
+//! chain (with `notfound` for anything unknown or withheld), `ping` gets a
+//! `pong` and `version` a `verack`. Tests can also make it send arbitrary
+//! messages or drop the connection, and see every message it receives.
+//!
+//! `test_config` points a wallet at a peer. For chains longer than genesis,
+//! `mainnet_blocks` gives the first few real blocks and `fork_block` a
+//! competitor to the first of them.
+
+use std::collections::{HashMap, HashSet, TreeMap};
+use std::comm::Disconnected;
+use std::default::Default;
+use std::io::{Acceptor, BufferedReader, IoResult, Listener, TimedOut};
+use std::io::net::tcp::{TcpListener, TcpStream};
+use serialize::hex::FromHex;
+
+use bitcoin::blockdata::block::{Block, LoneBlockHeader};
+use bitcoin::blockdata::constants::genesis_block;
+use bitcoin::network::constants::{Bitcoin, Network, magic};
+use bitcoin::network::encodable::{ConsensusDecodable, VarInt};
+use bitcoin::network::message::{mod, NetworkMessage, RawNetworkMessage};
+use bitcoin::network::message_blockdata::InvBlock;
+use bitcoin::network::serialize::{BitcoinHash, RawDecoder, deserialize, serialize};
+use bitcoin::util::hash::Sha256dHash;
+
+use constants::{DEFAULT_BLOCKCHAIN_N_FULL_BLOCKS, DEFAULT_MAX_REORG_DEPTH};
+use constants::{DEFAULT_SAVE_FREQUENCY, DEFAULT_UTXO_SYNC_N_BLOCKS};
+use constants::{DEFAULT_LOG_MAX_FILES, DEFAULT_LOG_MAX_SIZE};
+use logger::{Fatal, TextFormat};
+use user_data::NetworkConfig;
+
+/// Maximum number of headers sent in one `headers` message
+static MAX_HEADERS: uint = 2000;
+
+/// How often the acceptor checks whether the peer has been dropped, in ms
+static ACCEPT_TIMEOUT_MS: u64 = 100;
+
+/// Blocks 1 to 4 of the main chain, serialized
+static MAINNET_BLOCKS: [&'static str, ..4] = [
+  "010000006fe28c0ab6f1b372c1a6a246ae63f74f931e8365e15a089c68d6190000000000982051fd1e4ba744\
+   bbbe680e1fee14677ba1a3c3540bf7b1cdb606e857233e0e61bc6649ffff001d01e362990101000000010000\
+   000000000000000000000000000000000000000000000000000000000000ffffffff0704ffff001d0104ffff\
+   ffff0100f2052a0100000043410496b538e853519c726a2c91e61ec11600ae1390813a627c66fb8be7947be6\
+   3c52da7589379515d4e0a604f8141781e62294721166bf621e73a82cbf2342c858eeac00000000",
+  "010000004860eb18bf1b1620e37e9490fc8a427514416fd75159ab86688e9a8300000000d5fdcc541e25de1c\
+   7a5addedf24858b8bb665c9f36ef744ee42c316022c90f9bb0bc6649ffff001d08d2bd610101000000010000\
+   000000000000000000000000000000000000000000000000000000000000ffffffff0704ffff001d010bffff\
+   ffff0100f2052a010000004341047211a824f55b505228e4c3d5194c1fcfaa15a456abdf37f9b9d97a4040af\
+   c073dee6c89064984f03385237d92167c13e236446b417ab79a0fcae412ae3316b77ac00000000",
+  "01000000bddd99ccfda39da1b108ce1a5d70038d0a967bacb68b6b63065f626a0000000044f672226090d85d\
+   b9a9f2fbfe5f0f9609b387af7be5b7fbb7a1767c831c9e995dbe6649ffff001d05e0ed6d0101000000010000\
+   000000000000000000000000000000000000000000000000000000000000ffffffff0704ffff001d010effff\
+   ffff0100f2052a0100000043410494b9d3e76c5b1629ecf97fff95d7a4bbdac87cc26099ada28066c6ff1eb9\
+   191223cd897194a08d0c2726c5747f1db49e8cf90e75dc3e3550ae9b30086f3cd5aaac00000000",
+  "010000004944469562ae1c2c74d9a535e00b6f3e40ffbad4f2fda3895501b582000000007a06ea98cd40ba2e\
+   3288262b28638cec5337c1456aaf5eedc8e9e5a20f062bdf8cc16649ffff001d2bfee0a90101000000010000\
+   000000000000000000000000000000000000000000000000000000000000ffffffff0704ffff001d011affff\
+   ffff0100f2052a01000000434104184f32b212815c6e522e66686324030ff7e5bf08efb21f8b00614fb7690e\
+   19131dd31304c54f37baa40db231c918106bb9fd43373e37ae31a0befc6ecaefb867ac00000000"
+];
+
+/// A block at height 1 of the main chain, competing with the real one. It
+/// was mined at the minimum difficulty, and its coinbase pays to the same
+/// key as the real block 1's with a different script sig.
+static FORK_BLOCK: &'static str =
+  "010000006fe28c0ab6f1b372c1a6a246ae63f74f931e8365e15a089c68d61900000000005c2d81d34d544d44\
+   13c0e59a4a05b664214dc2928244454f05a95c6565e856cab1bd6649ffff001d2f2200230101000000010000\
+   000000000000000000000000000000000000000000000000000000000000ffffffff0704ffff001d0102ffff\
+   ffff0100f2052a0100000043410496b538e853519c726a2c91e61ec11600ae1390813a627c66fb8be7947be6\
+   3c52da7589379515d4e0a604f8141781e62294721166bf621e73a82cbf2342c858eeac00000000";
+
+/// Something the peer saw, reported back to the test
+pub enum Observed {
+  /// A new connection was accepted
+  NewConnection,
+  /// A message was received on the current connection
+  Message(NetworkMessage)
+}
+
+// Events handled by the peer task, from the test or from connection tasks.
+// Connections are numbered so that stragglers from a dropped one are ignored.
+enum Event {
+  Connected(uint, TcpStream),
+  Received(uint, NetworkMessage),
+  Closed(uint),
+  SendMessage(NetworkMessage),
+  Disconnect,
+  AddBlock(Block),
+  Rewind(uint),
+  Withhold(Sha256dHash),
+  Release(Sha256dHash),
+  Shutdown
+}
+
+/// A fake peer, serving a chain which starts at the genesis block
+pub struct MockPeer {
+  /// The local port the peer listens on
+  pub port: u16,
+  event_tx: Sender<Event>,
+  observed_rx: Receiver<Observed>,
+  // Hung up when the peer is dropped, to stop the acceptor
+  _stop_tx: Sender<()>
+}
+
+impl MockPeer {
+  /// Starts a peer listening on a free local port
+  pub fn new(network: Network) -> IoResult<MockPeer> {
+    let mut listener = try!(TcpListener::bind("127.0.0.1", 0));
+    let port = try!(listener.socket_name()).port;
+    let mut acceptor = try!(listener.listen());
+
+    let (event_tx, event_rx) = channel();
+    let (observed_tx, observed_rx) = channel();
+    let (stop_tx, stop_rx) = channel::<()>();
+
+    // Acceptor task: hand each connection to the peer task, and start a
+    // reader task for it
+    let acceptor_tx = event_tx.clone();
+    spawn(proc() {
+      let mut n_conn = 0u;
+      loop {
+        acceptor.set_timeout(Some(ACCEPT_TIMEOUT_MS));
+        match acceptor.accept() {
+          Ok(stream) => {
+            n_conn += 1;
+            let id = n_conn;
+            let reader = stream.clone();
+            if acceptor_tx.send_opt(Connected(id, stream)).is_err() {
+              break;
+            }
+            let reader_tx = acceptor_tx.clone();
+            spawn(proc() {
+              let mut decoder = RawDecoder::new(BufferedReader::new(reader));
+              loop {
+                let raw: IoResult<RawNetworkMessage> = ConsensusDecodable::consensus_decode(&mut decoder);
+                match raw {
+                  Ok(raw) => {
+                    if reader_tx.send_opt(Received(id, raw.payload)).is_err() { break; }
+                  }
+                  Err(_) => { let _ = reader_tx.send_opt(Closed(id)); break; }
+                }
+              }
+            });
+          }
+          Err(ref e) if e.kind == TimedOut => {
+            match stop_rx.try_recv() {
+              Err(Disconnected) => { break; }
+              _ => {}
+            }
+          }
+          Err(_) => { break; }
+        }
+      }
+    });
+
+    // Peer task: answer requests from the canned chain, and obey the test
+    spawn(proc() {
+      let mut chain = vec![genesis_block(network)];
+      let mut withheld = HashSet::new();
+      let mut current: Option<(uint, TcpStream)> = None;
+      loop {
+        let event = match event_rx.recv_opt() {
+          Ok(event) => event,
+          Err(()) => { break; }
+        };
+        match event {
+          Connected(id, stream) => {
+            // A new connection replaces any old one
+            match current.take() {
+              Some((_, mut old)) => { let _ = old.close_read(); let _ = old.close_write(); }
+              None => {}
+            }
+            current = Some((id, stream));
+            let _ = observed_tx.send_opt(NewConnection);
+          }
+          Received(id, msg) => {
+            let stream = match current {
+              Some((cur_id, ref mut stream)) if cur_id == id => stream,
+              _ => { continue; }
+            };
+            for reply in respond(&chain, &withheld, &msg).move_iter() {
+              let _ = send(stream, network, reply);
+            }
+            let _ = observed_tx.send_opt(Message(msg));
+          }
+          Closed(id) => {
+            match current {
+              Some((cur_id, _)) if cur_id == id => { current = None; }
+              _ => {}
+            }
+          }
+          SendMessage(msg) => {
+            match current {
+              Some((_, ref mut stream)) => { let _ = send(stream, network, msg); }
+              None => {}
+            }
+          }
+          Disconnect => {
+            match current.take() {
+              Some((_, mut stream)) => { let _ = stream.close_read(); let _ = stream.close_write(); }
+              None => {}
+            }
+          }
+          AddBlock(block) => { chain.push(block); }
+          Rewind(height) => { chain.truncate(height + 1); }
+          Withhold(hash) => { withheld.insert(hash); }
+          Release(hash) => { withheld.remove(&hash); }
+          // Connection tasks hold senders too, so the channel alone won't hang up
+          Shutdown => { break; }
+        }
+      }
+      // Hang up on the wallet when the test is done with us
+      match current {
+        Some((_, mut stream)) => { let _ = stream.close_read(); let _ = stream.close_write(); }
+        None => {}
+      }
+    });
+
+    Ok(MockPeer {
+      port: port,
+      event_tx: event_tx,
+      observed_rx: observed_rx,
+      _stop_tx: stop_tx
+    })
+  }
+
+  /// Extends the canned chain. The block's header must connect to the last one.
+  pub fn add_block(&self, block: Block) {
+    self.event_tx.send(AddBlock(block));
+  }
+
+  /// Cuts the canned chain back to the given height, so that blocks added
+  /// afterward fork from there
+  pub fn rewind(&self, height: uint) {
+    self.event_tx.send(Rewind(height));
+  }
+
+  /// Answers any future `getdata` for this block with `notfound`
+  pub fn withhold(&self, hash: Sha256dHash) {
+    self.event_tx.send(Withhold(hash));
+  }
+
+  /// Serves this block again after `withhold`
+  pub fn release(&self, hash: Sha256dHash) {
+    self.event_tx.send(Release(hash));
+  }
+
+  /// Sends a message on the current connection, if any
+  pub fn send(&self, msg: NetworkMessage) {
+    self.event_tx.send(SendMessage(msg));
+  }
+
+  /// Drops the current connection. The peer keeps listening for a new one.
+  pub fn disconnect(&self) {
+    self.event_tx.send(Disconnect);
+  }
+
+  /// Blocks until a new connection is accepted, skipping any messages
+  pub fn wait_for_connection(&self) {
+    loop {
+      match self.observed_rx.recv() {
+        NewConnection => { return; }
+        Message(_) => {}
+      }
+    }
+  }
+
+  /// Blocks until a message with the given command (e.g. "getheaders") is
+  /// received, skipping anything else, and returns it
+  pub fn wait_for(&self, command: &str) -> NetworkMessage {
+    loop {
+      match self.observed_rx.recv() {
+        Message(msg) => {
+          let raw = RawNetworkMessage { magic: 0, payload: msg };
+          if raw.command().as_slice() == command {
+            return raw.payload;
+          }
+        }
+        NewConnection => {}
+      }
+    }
+  }
+}
+
+impl Drop for MockPeer {
+  fn drop(&mut self) {
+    let _ = self.event_tx.send_opt(Shutdown);
+  }
+}
+
+/// A configuration for a wallet on the main network which syncs from a
+/// mock peer on `port`, and keeps its data in `dir`
+pub fn test_config(dir: &Path, port: u16) -> NetworkConfig {
+  NetworkConfig {
+    network: Bitcoin,
+    peer_addr: "127.0.0.1".to_string(),
+    peer_port: port,
+    rpc_server_addr: "127.0.0.1".to_string(),
+    rpc_server_port: 0,
+    rpc_users: vec![],
+    rpc_cookie_path: dir.join("rpc-cookie"),
+    rpc_tls_cert: None,
+    rpc_tls_key: None,
+    coinjoin_on: false,
+    coinjoin_policy: Default::default(),
+    coinjoin_rpc_addr: "127.0.0.1".to_string(),
+    coinjoin_rpc_port: None,
+    coinjoin_rpc_max_request_size: 0,
+    coinjoin_rpc_rate_limit: 0,
+    wallet_rpc: false,
+    metrics_addr: "127.0.0.1".to_string(),
+    metrics_port: None,
+    blockchain_path: dir.join("blockchain.dat"),
+    utxo_set_path: dir.join("utxoset.dat"),
+    utxo_snapshot_path: None,
+    utxo_snapshot_hash: None,
+    wallet_path: dir.join("wallet.toml"),
+    coinjoin_history_path: dir.join("coinjoin-history.log"),
+    utxo_sync_n_blocks: DEFAULT_UTXO_SYNC_N_BLOCKS,
+    blockchain_n_full_blocks: DEFAULT_BLOCKCHAIN_N_FULL_BLOCKS,
+    max_reorg_depth: DEFAULT_MAX_REORG_DEPTH,
+    save_frequency: DEFAULT_SAVE_FREQUENCY,
+    // Our chain has no checkpoints, and its blocks no scripts worth skipping
+    checkpoints: TreeMap::new(),
+    assume_valid: genesis_block(Bitcoin).bitcoin_hash(),
+    debug_level: Fatal,
+    log_levels: HashMap::new(),
+    log_format: TextFormat,
+    log_path: None,
+    log_max_size: DEFAULT_LOG_MAX_SIZE,
+    log_max_files: DEFAULT_LOG_MAX_FILES
+  }
+}
+
+/// Blocks 1 to 4 of the main chain, which build on its genesis block
+pub fn mainnet_blocks() -> Vec<Block> {
+  MAINNET_BLOCKS.iter().map(|hex| decode_block(*hex)).collect()
+}
+
+/// A block competing with the first of `mainnet_blocks`, which loses to
+/// them once the second arrives
+pub fn fork_block() -> Block {
+  decode_block(FORK_BLOCK)
+}
+
+fn decode_block(hex: &str) -> Block {
+  deserialize(hex.from_hex().unwrap()).unwrap()
+}
+
+/// Works out the replies to a message from the canned chain
+fn respond(chain: &Vec<Block>, withheld: &HashSet<Sha256dHash>, msg: &NetworkMessage)
+           -> Vec<NetworkMessage> {
+  match *msg {
+    message::Version(_) => vec![message::Verack],
+    message::Ping(nonce) => vec![message::Pong(nonce)],
+    message::GetHeaders(ref gh) => {
+      // Start after the first locator hash we know, or after genesis
+      let start = gh.locator_hashes.iter().filter_map(|hash| {
+        chain.iter().position(|block| block.bitcoin_hash() == *hash)
+      }).next().unwrap_or(0);
+      let headers = chain.iter().skip(start + 1).take(MAX_HEADERS).map(|block| {
+        LoneBlockHeader { header: block.header, tx_count: VarInt(0) }
+      }).collect();
+      vec![message::Headers(headers)]
+    }
+    message::GetData(ref invs) => {
+      let mut ret = vec![];
+      let mut not_found = vec![];
+      for inv in invs.iter() {
+        let block = if inv.inv_type == InvBlock && !withheld.contains(&inv.hash) {
+          chain.iter().find(|block| block.bitcoin_hash() == inv.hash)
+        } else {
+          None
+        };
+        match block {
+          Some(block) => ret.push(message::Block(block.clone())),
+          None => not_found.push(inv.clone())
+        }
+      }
+      if !not_found.is_empty() {
+        ret.push(message::NotFound(not_found));
+      }
+      ret
+    }
+    _ => vec![]
+  }
+}
+
+/// Writes a message to the stream
+fn send(stream: &mut TcpStream, network: Network, msg: NetworkMessage) -> IoResult<()> {
+  let raw = RawNetworkMessage { magic: magic(network), payload: msg };
+  let data = try!(serialize(&raw));
+  stream.write(data.as_slice())
+}
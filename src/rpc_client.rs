@@ -0,0 +1,188 @@
+/* The Wizards' Wallet
+ * Written in 2014 by
+ *   Andrew Poelstra <apoelstra@wpsoftware.net>
+ *
+ * To the extent possible under law, the author(s) have dedicated all
+ * copyright and related and neighboring rights to this software to
+ * the public domain worldwide. This software is distributed without
+ * any warranty.
+ *
+ * You should have received a copy of the CC0 Public Domain Dedication
+ * along with this software.
+ * If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+ */
+
+//! # RPC Clients
+//!
+//! Client-side use of the RPC server's `help` call, which describes each
+//! method and its parameters: converting command-line arguments to JSON
+//! parameters of the right types, and generating a shell completion script.
+//!
+
+use std::collections::TreeMap;
+use serialize::json;
+
+/// Extracts the (name, type) of each parameter of `method` from a `help` response
+pub fn param_schema(help: &json::JsonObject, method: &str) -> Vec<(String, String)> {
+  let params = match help.find(&method.to_string()).and_then(|call| call.find(&"params".to_string())) {
+    Some(&json::List(ref params)) => params,
+    _ => { return vec![]; }
+  };
+  params.iter().filter_map(|param| {
+    match (param.find(&"name".to_string()), param.find(&"type".to_string())) {
+      (Some(&json::String(ref name)), Some(&json::String(ref ty))) => Some((name.clone(), ty.clone())),
+      _ => None
+    }
+  }).collect()
+}
+
+/// Converts command-line arguments to JSON parameters of the types in `schema`.
+/// Arguments of the form `name=value`, for a known parameter name, are sent
+/// as named parameters; any others are positional.
+pub fn convert_params(schema: &[(String, String)], args: &[String]) -> Result<json::Json, String> {
+  let mut positional = vec![];
+  let mut named = TreeMap::new();
+  for (n, arg) in args.iter().enumerate() {
+    let split = arg.as_slice().find('=').and_then(|idx| {
+      let name = arg.as_slice().slice_to(idx);
+      schema.iter().find(|&&(ref pname, _)| pname.as_slice() == name)
+            .map(|&(_, ref ty)| (name, ty.as_slice(), arg.as_slice().slice_from(idx + 1)))
+    });
+    match split {
+      Some((name, ty, value)) => {
+        named.insert(name.to_string(), try!(convert_param(ty, value)));
+      }
+      None => {
+        let ty = schema.get(n).map_or("string", |&(_, ref ty)| ty.as_slice());
+        positional.push(try!(convert_param(ty, arg.as_slice())));
+      }
+    }
+  }
+
+  match (positional.is_empty(), named.is_empty()) {
+    (_, true) => Ok(json::List(positional)),
+    (true, false) => Ok(json::Object(named)),
+    (false, false) => Err("cannot mix positional and named parameters".to_string())
+  }
+}
+
+/// Converts a single argument to JSON of the given type
+fn convert_param(ty: &str, arg: &str) -> Result<json::Json, String> {
+  match ty {
+    "integer" => {
+      match (from_str::<u64>(arg), from_str::<i64>(arg)) {
+        (Some(n), _) => Ok(json::U64(n)),
+        (None, Some(n)) => Ok(json::I64(n)),
+        (None, None) => Err(format!("`{}` is not an integer", arg))
+      }
+    }
+    "object" => json::from_str(arg).map_err(|e| format!("`{}` is not a JSON object: {}", arg, e)),
+    _ => Ok(json::String(arg.to_string()))
+  }
+}
+
+/// Generates a bash completion script from a `help` response, completing
+/// command names and, after a command, its parameter names as `name=`
+pub fn completion_script(help: &json::JsonObject) -> String {
+  let mut commands = vec![];
+  let mut cases = String::new();
+  for (name, _) in help.iter() {
+    let params: Vec<String> = param_schema(help, name.as_slice()).move_iter()
+                                .map(|(name, _)| format!("{}=", name)).collect();
+    commands.push(name.clone());
+    cases.push_str(format!("    {}) words=\"{}\";;\n", name, params.connect(" ")).as_slice());
+  }
+
+  format!(r#"# bash completion for wizards-wallet-cli, generated from `help`
+_wizards_wallet_cli() {{
+  local cur="${{COMP_WORDS[COMP_CWORD]}}"
+  local prev="${{COMP_WORDS[COMP_CWORD-1]}}"
+  local method="" words="" i
+  case "$prev" in
+    -n|--network) COMPREPLY=( $(compgen -W "bitcoin testnet" -- "$cur") ); return;;
+    -c|--config) COMPREPLY=( $(compgen -f -- "$cur") ); return;;
+    -d|--datadir) COMPREPLY=( $(compgen -d -- "$cur") ); return;;
+    -u|--rpcuser|-p|--rpcpassword) return;;
+  esac
+  for ((i=1; i<COMP_CWORD; i++)); do
+    case "${{COMP_WORDS[i]}}" in
+      -n|--network|-c|--config|-d|--datadir|-u|--rpcuser|-p|--rpcpassword) ((i++));;
+      -*) ;;
+      *) method="${{COMP_WORDS[i]}}"; break;;
+    esac
+  done
+  case "$method" in
+    "") words="{} --network --config --datadir --rpcuser --rpcpassword --help";;
+{}  esac
+  # Parameter names end in `=`, so leave the cursor after them
+  if [ -n "$method" ]; then
+    compopt -o nospace
+  fi
+  COMPREPLY=( $(compgen -W "$words" -- "$cur") )
+}}
+complete -F _wizards_wallet_cli wizards-wallet-cli
+"#, commands.connect(" "), cases)
+}
+
+#[cfg(test)]
+mod tests {
+  use serialize::json;
+
+  use super::{completion_script, convert_params, param_schema};
+
+  fn help() -> json::JsonObject {
+    let help = r#"{
+      "coinjoin_start": { "params": [ { "name": "target", "type": "integer" },
+                                      { "name": "policy", "type": "object" },
+                                      { "name": "label", "type": "string" } ] },
+      "getblockcount": { "params": [] }
+    }"#;
+    match json::from_str(help) {
+      Ok(json::Object(obj)) => obj,
+      _ => unreachable!()
+    }
+  }
+
+  fn args(args: &[&str]) -> Vec<String> {
+    args.iter().map(|s| s.to_string()).collect()
+  }
+
+  fn parse(s: &str) -> Result<json::Json, String> {
+    Ok(json::from_str(s).unwrap())
+  }
+
+  #[test]
+  fn params_are_converted_by_type() {
+    let schema = param_schema(&help(), "coinjoin_start");
+    assert_eq!(convert_params(schema.as_slice(), args(&["100000", "{\"feerate\": 5}", "7"]).as_slice()),
+               parse("[100000, {\"feerate\": 5}, \"7\"]"));
+    assert_eq!(convert_params(schema.as_slice(), args(&["-5"]).as_slice()), parse("[-5]"));
+    // Arguments beyond the schema are strings
+    let schema = param_schema(&help(), "getblockcount");
+    assert_eq!(convert_params(schema.as_slice(), args(&["5"]).as_slice()), parse("[\"5\"]"));
+
+    let schema = param_schema(&help(), "coinjoin_start");
+    assert!(convert_params(schema.as_slice(), args(&["lots"]).as_slice()).is_err());
+    assert!(convert_params(schema.as_slice(), args(&["1", "{"]).as_slice()).is_err());
+  }
+
+  #[test]
+  fn known_names_make_named_params() {
+    let schema = param_schema(&help(), "coinjoin_start");
+    assert_eq!(convert_params(schema.as_slice(), args(&["label=a=b", "target=5"]).as_slice()),
+               parse("{\"label\": \"a=b\", \"target\": 5}"));
+    // Anything else with an `=` is just a positional argument
+    assert_eq!(convert_params(schema.as_slice(), args(&["1", "{}", "other=5"]).as_slice()),
+               parse("[1, {}, \"other=5\"]"));
+    assert!(convert_params(schema.as_slice(), args(&["1", "label=x"]).as_slice()).is_err());
+  }
+
+  #[test]
+  fn completion_covers_commands_and_params() {
+    let script = completion_script(&help());
+    assert!(script.as_slice().contains("words=\"coinjoin_start getblockcount --network"));
+    assert!(script.as_slice().contains("    coinjoin_start) words=\"target= policy= label=\";;\n"));
+    assert!(script.as_slice().contains("    getblockcount) words=\"\";;\n"));
+    assert!(script.as_slice().ends_with("complete -F _wizards_wallet_cli wizards-wallet-cli\n"));
+  }
+}
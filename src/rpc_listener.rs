@@ -0,0 +1,276 @@
+/* The Wizards' Wallet
+ * Written in 2014 by
+ *   Andrew Poelstra <apoelstra@wpsoftware.net>
+ *
+ * To the extent possible under law, the author(s) have dedicated all
+ * copyright and related and neighboring rights to this software to
+ * the public domain worldwide. This software is distributed without
+ * any warranty.
+ *
+ * You should have received a copy of the CC0 Public Domain Dedication
+ * along with this software.
+ * If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+ */
+
+//! # RPC Listener
+//!
+//! The authenticated HTTP listener for the main JSON-RPC interface. Clients
+//! authenticate with HTTP Basic auth, either as a user from the configuration
+//! or using the cookie file written at startup, and may only make the calls
+//! their permissions allow. The listener can optionally speak TLS.
+
+use std::io::{BufferedStream, File, IoError, IoResult, InvalidInput, OtherIoError, Truncate, Write};
+use std::io::{Acceptor, FileNotFound, Listener};
+use std::io::fs;
+use std::io::net::tcp::TcpListener;
+use std::rand::{mod, Rng};
+use std::str;
+use std::sync::Arc;
+use serialize::base64::FromBase64;
+use serialize::hex::ToHex;
+use serialize::json;
+
+use openssl::ssl::{SslContext, SslStream, Sslv23};
+use openssl::x509::PEM;
+
+use libc;
+
+use jsonrpc::error::{standard_error, ParseError};
+
+use http_request::{BAD_REQUEST, ENTITY_TOO_LARGE, METHOD_NOT_ALLOWED, NO_CONTENT, OK,
+                   READ_TIMEOUT_MS, UNAUTHORIZED, read_head, write_response};
+use rpc_server::{MAX_BATCH_SIZE, Permissions, RpcRequest, dispatch_body, is_permitted,
+                 response_json};
+use user_data::NetworkConfig;
+
+/// Username under which the cookie file's password is accepted
+static COOKIE_USER: &'static str = "__cookie__";
+
+/// Largest request body we will read, in bytes
+static MAX_REQUEST_SIZE: uint = 10 * 1024 * 1024;
+
+/// A username, password and the permissions they grant
+#[deriving(Clone, PartialEq)]
+pub struct RpcUser {
+  /// The username
+  pub name: String,
+  /// The password
+  pub password: String,
+  /// What calls the user may make
+  pub permissions: Permissions
+}
+
+/// The main RPC listener
+pub struct RpcListener {
+  addr: String,
+  port: u16,
+  users: Arc<Vec<RpcUser>>,
+  tls: Option<Arc<SslContext>>,
+  rpc_tx: Sender<RpcRequest>,
+  // Removes the cookie file when the listener shuts down
+  _cookie: Cookie
+}
+
+/// A cookie file, which is removed when dropped
+struct Cookie {
+  path: Path
+}
+
+impl Drop for Cookie {
+  fn drop(&mut self) {
+    let _ = remove_cookie(&self.path);
+  }
+}
+
+impl RpcListener {
+  /// Creates a new listener from the configuration, writing a fresh cookie
+  /// file. Returns the listener along with a receiver on which authenticated
+  /// and permitted requests will be passed on.
+  pub fn new(config: &NetworkConfig) -> IoResult<(RpcListener, Receiver<RpcRequest>)> {
+    let mut users = config.rpc_users.clone();
+    users.push(RpcUser {
+      name: COOKIE_USER.to_string(),
+      password: try!(write_cookie(&config.rpc_cookie_path)),
+      permissions: Permissions { coinjoin: true, wallet: true, files: true }
+    });
+    let cookie = Cookie { path: config.rpc_cookie_path.clone() };
+
+    let tls = match (&config.rpc_tls_cert, &config.rpc_tls_key) {
+      (&Some(ref cert), &Some(ref key)) => Some(Arc::new(try!(tls_context(cert, key)))),
+      (&None, &None) => None,
+      _ => {
+        return Err(IoError {
+          kind: InvalidInput,
+          desc: "TLS requires both rpc_tls_cert and rpc_tls_key to be set",
+          detail: None
+        });
+      }
+    };
+
+    let (tx, rx) = channel();
+    Ok((RpcListener {
+      addr: config.rpc_server_addr.clone(),
+      port: config.rpc_server_port,
+      users: Arc::new(users),
+      tls: tls,
+      rpc_tx: tx,
+      _cookie: cookie
+    }, rx))
+  }
+
+  /// Accepts connections until an error occurs, handling each in its own task
+  pub fn serve_forever(&self) -> IoResult<()> {
+    let mut acceptor = try!(TcpListener::bind(self.addr.as_slice(), self.port).listen());
+    loop {
+      let mut stream = try!(acceptor.accept());
+      stream.set_read_timeout(Some(READ_TIMEOUT_MS));
+      let users = self.users.clone();
+      let tls = self.tls.clone();
+      let rpc_tx = self.rpc_tx.clone();
+      spawn(proc() {
+        match tls {
+          Some(ctx) => {
+            match SslStream::new_server(&*ctx, stream) {
+              Ok(stream) => handle_connection(stream, users.as_slice(), &rpc_tx),
+              Err(e) => { println!("RPC listener: TLS handshake failed: {}", e); }
+            }
+          }
+          None => handle_connection(stream, users.as_slice(), &rpc_tx)
+        }
+      });
+    }
+  }
+}
+
+/// Writes a new random password to the cookie file, returning it
+fn write_cookie(path: &Path) -> IoResult<String> {
+  let mut rng = try!(rand::OsRng::new());
+  let mut secret = [0u8, ..32];
+  rng.fill_bytes(secret.as_mut_slice());
+  let password = secret.as_slice().to_hex();
+
+  // Create the file afresh so that only we can read it, whatever the
+  // permissions of any old one
+  try!(remove_cookie(path));
+  let fd = path.with_c_str(|path| unsafe {
+    libc::open(path, libc::O_WRONLY | libc::O_CREAT | libc::O_EXCL,
+               (libc::S_IRUSR | libc::S_IWUSR) as libc::c_int)
+  });
+  if fd == -1 {
+    return Err(IoError::last_error());
+  }
+  unsafe { libc::close(fd); }
+
+  let mut file = try!(File::open_mode(path, Truncate, Write));
+  try!(file.write_str(format!("{}:{}", COOKIE_USER, password).as_slice()));
+  Ok(password)
+}
+
+/// Removes the cookie file, if there is one
+pub fn remove_cookie(path: &Path) -> IoResult<()> {
+  match fs::unlink(path) {
+    Err(IoError { kind: FileNotFound, .. }) => Ok(()),
+    res => res
+  }
+}
+
+/// Builds a TLS context from PEM-encoded certificate and key files
+fn tls_context(cert: &Path, key: &Path) -> IoResult<SslContext> {
+  let tls_error = |e| IoError {
+    kind: OtherIoError,
+    desc: "failed to set up TLS",
+    detail: Some(format!("{}", e))
+  };
+  let mut ctx = try!(SslContext::new(Sslv23).map_err(|e| tls_error(e)));
+  match ctx.set_certificate_file(cert, PEM) {
+    Some(e) => { return Err(tls_error(e)); }
+    None => {}
+  }
+  match ctx.set_private_key_file(key, PEM) {
+    Some(e) => { return Err(tls_error(e)); }
+    None => {}
+  }
+  Ok(ctx)
+}
+
+/// Reads a single HTTP request from the stream, and writes the response
+fn handle_connection<S: Reader + Writer>(stream: S, users: &[RpcUser], rpc_tx: &Sender<RpcRequest>) {
+  let mut stream = BufferedStream::new(stream);
+
+  let head = match read_head(&mut stream) {
+    Ok(head) => head,
+    Err(status) => { write_response(&mut stream, status, [], ""); return; }
+  };
+  if head.method.as_slice() != "POST" {
+    write_response(&mut stream, METHOD_NOT_ALLOWED, [], "");
+    return;
+  }
+
+  // Authenticate
+  let permissions = match head.header("authorization").and_then(|auth| authenticate(auth, users)) {
+    Some(permissions) => permissions,
+    None => {
+      write_response(&mut stream, UNAUTHORIZED,
+                     [("WWW-Authenticate", "Basic realm=\"wizards-wallet\"")], "");
+      return;
+    }
+  };
+
+  let content_length = match head.content_length() {
+    Ok(len) => len,
+    Err(status) => { write_response(&mut stream, status, [], ""); return; }
+  };
+  if content_length > MAX_REQUEST_SIZE {
+    write_response(&mut stream, ENTITY_TOO_LARGE, [], "");
+    return;
+  }
+  let body = match stream.read_exact(content_length) {
+    Ok(body) => body,
+    Err(_) => { write_response(&mut stream, BAD_REQUEST, [], ""); return; }
+  };
+
+  let response = match str::from_utf8(body.as_slice()) {
+    Some(body) => dispatch_body(body, MAX_BATCH_SIZE, |method| is_permitted(method, &permissions),
+                                rpc_tx),
+    None => {
+      Some(response_json(Err(standard_error(ParseError, Some(json::String("request was not UTF-8".to_string())))),
+                         json::Null))
+    }
+  };
+  match response {
+    Some(response) => {
+      write_response(&mut stream, OK, [("Content-Type", "application/json")],
+                     response.to_string().as_slice());
+    }
+    None => { write_response(&mut stream, NO_CONTENT, [], ""); }
+  }
+}
+
+/// Checks an HTTP Basic `Authorization` header against the known users,
+/// returning the permissions of the matching user
+fn authenticate(auth: &str, users: &[RpcUser]) -> Option<Permissions> {
+  if !auth.starts_with("Basic ") {
+    return None;
+  }
+  let decoded = match auth.slice_from(6).trim().from_base64() {
+    Ok(decoded) => decoded,
+    Err(_) => { return None; }
+  };
+  let decoded = match str::from_utf8(decoded.as_slice()) {
+    Some(s) => s.to_string(),
+    None => { return None; }
+  };
+  let (name, password) = match decoded.as_slice().find(':') {
+    Some(idx) => (decoded.as_slice().slice_to(idx), decoded.as_slice().slice_from(idx + 1)),
+    None => { return None; }
+  };
+  users.iter()
+       .find(|user| user.name.as_slice() == name &&
+                    constant_time_eq(user.password.as_bytes(), password.as_bytes()))
+       .map(|user| user.permissions.clone())
+}
+
+/// Compares two byte strings in time independent of where they differ
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+  a.len() == b.len() && a.iter().zip(b.iter()).fold(0u8, |acc, (x, y)| acc | (*x ^ *y)) == 0
+}
@@ -19,6 +19,7 @@
 use std::io::{IoError, MemReader};
 use std::collections::TreeMap;
 use std::time::Duration;
+use time;
 use serialize::Decodable;
 use serialize::hex::FromHex;
 use serialize::json;
@@ -33,37 +34,181 @@ use bitcoin::blockdata::script::Script;
 use bitcoin::blockdata::transaction::Transaction;
 use bitcoin::wallet::wallet::{AccountNotFound, External};
 use jsonrpc;
-use jsonrpc::error::{standard_error, Error, InvalidParams, MethodNotFound};
+use jsonrpc::error::{standard_error, Error, InternalError, InvalidParams, InvalidRequest,
+                     MethodNotFound, ParseError};
+use num::bigint::BigUint;
 use phf::PhfOrderedMap;
 
 use bitcoind::IdleState;
-use coinjoin::server::{Complete, Server, Session, SessionId};
-use coinjoin::CoinjoinError;
+use coinjoin::server::{Blinded, Complete, PartialSessionPolicy, Server, Session, SessionId,
+                       Transparent, read_history};
+use coinjoin;
+use coinjoin::{BlindKeyUnavailable, CoinjoinError};
+use coinjoin::blind;
+use logger::{DebugLevel, Subsystem};
+use snapshot::dump_utxo_set;
+use verify::{DEFAULT_VERIFY_DEPTH, MAX_VERIFY_LEVEL, verify_chain};
 use wallet::save_wallet;
 
 pub type JsonResult = jsonrpc::JsonResult<json::Json>;
 
+/// Version of the OpenRPC specification followed by `rpc.discover`
+static OPENRPC_VERSION: &'static str = "1.2.6";
+
+/// Version of the RPC interface reported by `rpc.discover`
+static API_VERSION: &'static str = "0.0.1";
+
+/// Name under which OpenRPC clients expect to find `rpc_discover`
+static RPC_DISCOVER: &'static str = "rpc.discover";
+
+/// Most requests accepted in a single batch by the main RPC listener
+pub static MAX_BATCH_SIZE: uint = 1000;
+
+/// A request passed to the network task, with a channel for its result
+pub type RpcRequest = (Request, Sender<JsonResult>);
+
 enum RawDecodeMode {
     DecodeAsIs,
     PrependLength
 }
 
+/// The parameters of a request, either by position or by name
+pub enum Params {
+  /// Parameters given as a JSON array
+  Positional(Vec<json::Json>),
+  /// Parameters given as a JSON object, keyed by parameter name
+  Named(TreeMap<String, json::Json>)
+}
+
+/// A decoded JSON-RPC request
+pub struct Request {
+  /// The name of the call
+  pub method: String,
+  /// The call's parameters
+  pub params: Params,
+  /// The request ID, echoed back in the response
+  pub id: json::Json
+}
+
+/// The JSON type expected of a parameter
+#[deriving(Clone, PartialEq, Eq, Show)]
+pub enum ParamType {
+  /// A hex-encoded double-SHA256 hash
+  HashParam,
+  /// Hex-encoded data
+  HexParam,
+  /// An integer
+  IntegerParam,
+  /// A JSON object
+  ObjectParam,
+  /// A string
+  StringParam
+}
+
+impl ParamType {
+  /// The name of the type, as shown by `help`
+  pub fn name(&self) -> &'static str {
+    match *self {
+      HashParam => "hash",
+      HexParam => "hex",
+      IntegerParam => "integer",
+      ObjectParam => "object",
+      StringParam => "string"
+    }
+  }
+
+  /// A JSON schema describing the type
+  pub fn schema(&self) -> json::Json {
+    let mut obj = TreeMap::new();
+    obj.insert("type".to_string(), json::String(match *self {
+      IntegerParam => "integer",
+      ObjectParam => "object",
+      HashParam | HexParam | StringParam => "string"
+    }.to_string()));
+    match *self {
+      HashParam => { obj.insert("pattern".to_string(), json::String("^[0-9a-fA-F]{64}$".to_string())); }
+      HexParam => { obj.insert("pattern".to_string(), json::String("^([0-9a-fA-F]{2})*$".to_string())); }
+      _ => {}
+    }
+    json::Object(obj)
+  }
+
+  /// Whether a JSON value has this type
+  fn matches(&self, value: &json::Json) -> bool {
+    match (*self, value) {
+      (HashParam, &json::String(ref s)) => s.len() == 64 && s.as_slice().from_hex().is_ok(),
+      (HexParam, &json::String(ref s)) => s.as_slice().from_hex().is_ok(),
+      (IntegerParam, &json::I64(_)) | (IntegerParam, &json::U64(_)) => true,
+      (ObjectParam, &json::Object(_)) => true,
+      (StringParam, &json::String(_)) => true,
+      _ => false
+    }
+  }
+}
+
+/// A single parameter of an RPC command
+pub struct Param {
+  name: &'static str,
+  ty: ParamType,
+  required: bool
+}
+
+/// The JSON type of a call's result
+#[deriving(Clone, PartialEq, Eq, Show)]
+pub enum ResultType {
+  /// A JSON array
+  ArrayResult,
+  /// A boolean
+  BooleanResult,
+  /// An integer
+  IntegerResult,
+  /// A JSON object
+  ObjectResult,
+  /// A string
+  StringResult
+}
+
+impl ResultType {
+  /// The JSON schema type name
+  pub fn name(&self) -> &'static str {
+    match *self {
+      ArrayResult => "array",
+      BooleanResult => "boolean",
+      IntegerResult => "integer",
+      ObjectResult => "object",
+      StringResult => "string"
+    }
+  }
+}
+
+/// What an RPC command returns
+pub struct Returns {
+  ty: ResultType,
+  desc: &'static str
+}
+
 /// A single RPC command
 pub struct RpcCall {
   name: &'static str,
   desc: &'static str,
-  usage: &'static str,
+  params: &'static [Param],
+  result: Returns,
   coinjoin: bool,
   wallet: bool,
+  files: bool,
+  public: bool,
   call: fn(&RpcCall, &mut IdleState, Vec<json::Json>) -> JsonResult
 }
 
 // Forget you saw this macro...just forget it.
 macro_rules! rpc_calls(
   ( $( #[doc=$doc:tt]
-       #[usage=$usage:tt]
+       #[params=$params:tt]
+       #[result=$result:tt]
        #[coinjoin=$coinjoin:tt]
        #[wallet=$wallet:tt]
+       #[files=$files:tt]
+       #[public=$public:tt]
        pub fn $name:ident($($param:tt: $paramty:ty),+) $code:expr),+ ) => (
     $(
       // `tt` token trees can only be passed to a macro. On the other hand,
@@ -87,9 +232,12 @@ macro_rules! rpc_calls(
         $(stringify!($name) => RpcCall {
             name: stringify!($name),
             desc: $doc,
-            usage: $usage,
+            params: &$params,
+            result: $result,
             coinjoin: $coinjoin,
             wallet: $wallet,
+            files: $files,
+            public: $public,
             call: $name
           }
         ),+
@@ -107,211 +255,346 @@ macro_rules! rpc_calls(
 // Main RPC call list
 rpc_calls!{
   #[doc="Fetches a list of commands"]
-  #[usage=""]
+  #[params=[]]
+  #[result=Returns { ty: ObjectResult, desc: "Each command, with its description, usage and parameters" }]
   #[coinjoin=false]
   #[wallet=false]
+  #[files=false]
+  #[public=false]
   pub fn help(_: &RpcCall, idle_state: &mut IdleState, _: Vec<json::Json>) {
     let mut ret = TreeMap::new();
     for call in RPC_CALLS.values() {
       if !call.coinjoin || idle_state.config.coinjoin_on {
+        let params: Vec<json::Json> = call.params.iter().map(|param| {
+          let mut obj = TreeMap::new();
+          obj.insert("name".to_string(), json::String(param.name.to_string()));
+          obj.insert("type".to_string(), json::String(param.ty.name().to_string()));
+          obj.insert("required".to_string(), json::Boolean(param.required));
+          json::Object(obj)
+        }).collect();
         let mut obj = TreeMap::new();
         obj.insert("description".to_string(), json::String(call.desc.to_string()));
-        obj.insert("usage".to_string(), json::String(call.usage.to_string()));
+        obj.insert("usage".to_string(), json::String(usage(call)));
+        obj.insert("params".to_string(), json::List(params));
         ret.insert(call.name.to_string(), json::Object(obj));
       }
     }
     Ok(json::Object(ret))
   },
 
+  #[doc="Describes every command as an OpenRPC document. Also available as `rpc.discover`."]
+  #[params=[]]
+  #[result=Returns { ty: ObjectResult, desc: "The OpenRPC document" }]
+  #[coinjoin=false]
+  #[wallet=false]
+  #[files=false]
+  #[public=false]
+  pub fn rpc_discover(_: &RpcCall, idle_state: &mut IdleState, _: Vec<json::Json>) {
+    let mut methods = vec![];
+    for call in RPC_CALLS.values() {
+      if !call.coinjoin || idle_state.config.coinjoin_on {
+        methods.push(openrpc_method(call));
+      }
+    }
+
+    let mut info = TreeMap::new();
+    info.insert("title".to_string(), json::String("The Wizards' Wallet".to_string()));
+    info.insert("version".to_string(), json::String(API_VERSION.to_string()));
+
+    let mut components = TreeMap::new();
+    components.insert("errors".to_string(), openrpc_errors());
+
+    let mut ret = TreeMap::new();
+    ret.insert("openrpc".to_string(), json::String(OPENRPC_VERSION.to_string()));
+    ret.insert("info".to_string(), json::Object(info));
+    ret.insert("methods".to_string(), json::List(methods));
+    ret.insert("components".to_string(), json::Object(components));
+    Ok(json::Object(ret))
+  },
+
+  #[doc="Gets the effective configuration of this network, after defaults, command-line overrides and any reloads"]
+  #[params=[]]
+  #[result=Returns { ty: ObjectResult, desc: "Each option with its current value; RPC passwords are left out" }]
+  #[coinjoin=false]
+  #[wallet=false]
+  #[files=false]
+  #[public=false]
+  pub fn getconfig(_: &RpcCall, idle_state: &mut IdleState, _: Vec<json::Json>) {
+    Ok(idle_state.config.to_json())
+  },
+
+  #[doc="Gets the status of this network's task, which is restarted if it dies. Answered even while the task is down."]
+  #[params=[]]
+  #[result=Returns { ty: ObjectResult, desc: "Whether the task is running, how often it was restarted, and why it last died" }]
+  #[coinjoin=false]
+  #[wallet=false]
+  #[files=false]
+  #[public=false]
+  pub fn getnetworkstatus(_: &RpcCall, idle_state: &mut IdleState, _: Vec<json::Json>) {
+    Ok(idle_state.status.read().to_json())
+  },
+
+  #[doc="Sets the minimum severity of messages to log from a subsystem (sync, p2p, rpc, coinjoin or wallet), until the configuration is reloaded. Levels are DEBUG, NOTE, STATUS, WARN, ERROR and FATAL."]
+  #[params=[Param { name: "subsystem", ty: StringParam, required: true },
+            Param { name: "level", ty: StringParam, required: true }]]
+  #[result=Returns { ty: StringResult, desc: "The subsystem's previous level" }]
+  #[coinjoin=false]
+  #[wallet=false]
+  #[files=false]
+  #[public=false]
+  pub fn setloglevel(_: &RpcCall, idle_state: &mut IdleState, params: Vec<json::Json>) {
+    let subsystem: Subsystem = try!(decode_param(params[0].clone()));
+    let level: DebugLevel = try!(decode_param(params[1].clone()));
+    // Keep the config in step, so that `getconfig` shows the new level
+    idle_state.config.log_levels.insert(subsystem, level);
+    let old = idle_state.logger.set_level(subsystem, level);
+    Ok(json::String(old.to_string()))
+  },
+
   #[doc="Gets a specific block from the blockchain"]
-  #[usage="<hash>"]
+  #[params=[Param { name: "hash", ty: HashParam, required: true }]]
+  #[result=Returns { ty: ObjectResult, desc: "The block header, and its transactions if they are stored" }]
   #[coinjoin=false]
   #[wallet=false]
-  pub fn getblock(rpc: &RpcCall, idle_state: &mut IdleState, params: Vec<json::Json>) {
-    match params.len() {
-      1 => {
-        let blockchain = idle_state.blockchain.read();
-        let hash: Sha256dHash = try!(decode_param(params[0].clone()));
-
-        match blockchain.get_block(hash) {
-          Some(node) => {
-            let mut ret = TreeMap::new();
-            ret.insert("header".to_string(), node.block.header.to_json());
-            ret.insert("has_txdata".to_string(), json::Boolean(node.has_txdata));
-            if node.has_txdata {
-              ret.insert("transactions".to_string(), node.block.txdata.to_json());
-            }
-            Ok(json::Object(ret))
-          }
-          None => Err(bitcoin_json_error(BlockNotFound, Some(hash.to_json()))),
+  #[files=false]
+  #[public=false]
+  pub fn getblock(_: &RpcCall, idle_state: &mut IdleState, params: Vec<json::Json>) {
+    let blockchain = idle_state.blockchain.read();
+    let hash: Sha256dHash = try!(decode_param(params[0].clone()));
+
+    match blockchain.get_block(hash) {
+      Some(node) => {
+        let mut ret = TreeMap::new();
+        ret.insert("header".to_string(), node.block.header.to_json());
+        ret.insert("has_txdata".to_string(), json::Boolean(node.has_txdata));
+        if node.has_txdata {
+          ret.insert("transactions".to_string(), node.block.txdata.to_json());
         }
+        Ok(json::Object(ret))
       }
-      _ => Err(usage_error(rpc))
+      None => Err(bitcoin_json_error(BlockNotFound, Some(hash.to_json()))),
     }
   },
 
-  #[doc="Gets the current number of unspent outputs on the blockchain."]
-  #[usage=""]
+  #[doc="Rechecks the blocks at the tip of the best chain, by default the last 288 at every level. Level 0 checks proof of work, 1 difficulty retargets, 2 timestamps against the median time past, and 3 the merkle roots of blocks whose transactions are stored; each level includes those below it. A depth of 0 checks the whole chain."]
+  #[params=[Param { name: "depth", ty: IntegerParam, required: false },
+            Param { name: "level", ty: IntegerParam, required: false }]]
+  #[result=Returns { ty: ObjectResult, desc: "Whether the blocks passed, how many did, and the first failure if any" }]
   #[coinjoin=false]
   #[wallet=false]
-  pub fn getutxocount(rpc: &RpcCall, idle_state: &mut IdleState, params: Vec<json::Json>) {
-    match params.len() {
-      0 => Ok(json::U64(idle_state.utxo_set.read().n_utxos() as u64)),
-      _ => Err(usage_error(rpc))
+  #[files=false]
+  #[public=false]
+  pub fn verifychain(_: &RpcCall, idle_state: &mut IdleState, params: Vec<json::Json>) {
+    let depth: uint = match params[0] {
+      json::Null => DEFAULT_VERIFY_DEPTH,
+      ref depth => try!(decode_param(depth.clone()))
+    };
+    let level: uint = match params[1] {
+      json::Null => MAX_VERIFY_LEVEL,
+      ref level => try!(decode_param(level.clone()))
+    };
+    if level > MAX_VERIFY_LEVEL {
+      return Err(standard_error(InvalidParams,
+                                Some(json::String(format!("level must be at most {}",
+                                                          MAX_VERIFY_LEVEL)))));
     }
+
+    let blockchain = idle_state.blockchain.read();
+    let (n_checked, failure) = verify_chain(&*blockchain, idle_state.config.network, depth, level);
+    let mut ret = TreeMap::new();
+    ret.insert("valid".to_string(), json::Boolean(failure.is_none()));
+    ret.insert("checked".to_string(), n_checked.to_json());
+    ret.insert("failure".to_string(), failure.to_json());
+    Ok(json::Object(ret))
+  },
+
+  #[doc="Gets the current number of unspent outputs on the blockchain."]
+  #[params=[]]
+  #[result=Returns { ty: IntegerResult, desc: "The number of unspent outputs" }]
+  #[coinjoin=false]
+  #[wallet=false]
+  #[files=false]
+  #[public=false]
+  pub fn getutxocount(_: &RpcCall, idle_state: &mut IdleState, _: Vec<json::Json>) {
+    Ok(json::U64(idle_state.utxo_set.read().n_utxos() as u64))
+  },
+
+  #[doc="Writes a snapshot of the UTXO set to a new file, from which a new wallet can start its UTXO sync by pinning the snapshot's content hash in its configuration. Existing files are not overwritten."]
+  #[params=[Param { name: "path", ty: StringParam, required: true }]]
+  #[result=Returns { ty: ObjectResult, desc: "The snapshot's tip hash and height, content hash and number of unspent outputs" }]
+  #[coinjoin=false]
+  #[wallet=false]
+  #[files=true]
+  #[public=false]
+  pub fn dumputxoset(_: &RpcCall, idle_state: &mut IdleState, params: Vec<json::Json>) {
+    let path: String = try!(decode_param(params[0].clone()));
+    let blockchain = idle_state.blockchain.read();
+    let utxo_set = idle_state.utxo_set.read();
+    let height = blockchain.get_block(utxo_set.last_hash()).map_or(0, |node| node.height);
+    let info = try!(dump_utxo_set(&Path::new(path), idle_state.config.network, &*utxo_set, height)
+                      .map_err(|e| bitcoin_json_error(SnapshotError,
+                                                      Some(json::String(e.to_string())))));
+    Ok(info.to_json())
   },
 
   #[doc="Gets the length of the longest chain, starting from the given hash or genesis."]
-  #[usage="[start hash]"]
+  #[params=[Param { name: "start_hash", ty: HashParam, required: false }]]
+  #[result=Returns { ty: IntegerResult, desc: "The number of blocks after the start block on the longest chain" }]
   #[coinjoin=false]
   #[wallet=false]
-  pub fn getblockcount(rpc: &RpcCall, idle_state: &mut IdleState, params: Vec<json::Json>) {
-    match params.len() {
-      0 => {
-        let blockchain = idle_state.blockchain.read();
-        // Subtract 1 from the hash since the genesis counts as block 0
-        Ok(json::U64(blockchain.iter(blockchain.genesis_hash()).count() as u64 - 1))
-      }
-      1 => {
-        let blockchain = idle_state.blockchain.read();
-        let hash: Sha256dHash = try!(decode_param(params[0].clone()));
-
-        // Subtract 1 from the hash since the genesis counts as block 0
-        match blockchain.iter(hash).count() {
-          0 => Err(bitcoin_json_error(BlockNotFound, Some(hash.to_json()))),
-          n => Ok(json::U64(n as u64 - 1)),
-        }
-      }
-      _ => Err(usage_error(rpc))
+  #[files=false]
+  #[public=false]
+  pub fn getblockcount(_: &RpcCall, idle_state: &mut IdleState, params: Vec<json::Json>) {
+    let blockchain = idle_state.blockchain.read();
+    let hash: Sha256dHash = match params[0] {
+      json::Null => blockchain.genesis_hash(),
+      ref hash => try!(decode_param(hash.clone()))
+    };
+
+    // Subtract 1 from the hash since the genesis counts as block 0
+    match blockchain.iter(hash).count() {
+      0 => Err(bitcoin_json_error(BlockNotFound, Some(hash.to_json()))),
+      n => Ok(json::U64(n as u64 - 1)),
     }
   },
 
   #[doc="Decodes a raw transaction"]
-  #[usage="<hex-encoded tx data>"]
+  #[params=[Param { name: "tx", ty: HexParam, required: true }]]
+  #[result=Returns { ty: ObjectResult, desc: "The decoded transaction" }]
   #[coinjoin=false]
   #[wallet=false]
-  pub fn raw_decode(rpc: &RpcCall, _: &mut IdleState, params: Vec<json::Json>) {
-    match params.len() {
-      1 => {
-        let tx: Transaction = try!(decode_hex_param(params[0].clone(), DecodeAsIs));
-        Ok(tx.to_json())
-      }
-      _ => Err(usage_error(rpc))
-    }
+  #[files=false]
+  #[public=false]
+  pub fn raw_decode(_: &RpcCall, _: &mut IdleState, params: Vec<json::Json>) {
+    let tx: Transaction = try!(decode_hex_param(params[0].clone(), DecodeAsIs));
+    Ok(tx.to_json())
   },
 
   #[doc="Validates a raw transaction"]
-  #[usage="<hex-encoded tx data>"]
+  #[params=[Param { name: "tx", ty: HexParam, required: true }]]
+  #[result=Returns { ty: BooleanResult, desc: "true, if the transaction is valid" }]
   #[coinjoin=false]
   #[wallet=false]
-  pub fn raw_validate(rpc: &RpcCall, idle_state: &mut IdleState, params: Vec<json::Json>) {
-    match params.len() {
-      1 => {
-        let tx: Transaction = try!(decode_hex_param(params[0].clone(), DecodeAsIs));
-        let utxo_set = idle_state.utxo_set.read();
-        match tx.validate(&*utxo_set) {
-          Ok(_) => Ok(json::Boolean(true)),
-          Err(e) => Err(bitcoin_json_error(InvalidTx, Some(json::String(e.to_string()))))
-        }
-      }
-      _ => Err(usage_error(rpc))
+  #[files=false]
+  #[public=false]
+  pub fn raw_validate(_: &RpcCall, idle_state: &mut IdleState, params: Vec<json::Json>) {
+    let tx: Transaction = try!(decode_hex_param(params[0].clone(), DecodeAsIs));
+    let utxo_set = idle_state.utxo_set.read();
+    match tx.validate(&*utxo_set) {
+      Ok(_) => Ok(json::Boolean(true)),
+      Err(e) => Err(bitcoin_json_error(InvalidTx, Some(json::String(e.to_string()))))
     }
   },
 
   #[doc="Traces execution of a raw transaction's scripts"]
-  #[usage="<hex-encoded tx data>"]
+  #[params=[Param { name: "tx", ty: HexParam, required: true }]]
+  #[result=Returns { ty: ObjectResult, desc: "A trace of each input's script execution" }]
   #[coinjoin=false]
   #[wallet=false]
-  pub fn raw_trace(rpc: &RpcCall, idle_state: &mut IdleState, params: Vec<json::Json>) {
-    match params.len() {
-      1 => {
-        let tx: Transaction = try!(decode_hex_param(params[0].clone(), DecodeAsIs));
-        let utxo_set = idle_state.utxo_set.read();
-        Ok(tx.trace(&*utxo_set).to_json())
-      }
-      _ => Err(usage_error(rpc))
-    }
+  #[files=false]
+  #[public=false]
+  pub fn raw_trace(_: &RpcCall, idle_state: &mut IdleState, params: Vec<json::Json>) {
+    let tx: Transaction = try!(decode_hex_param(params[0].clone(), DecodeAsIs));
+    let utxo_set = idle_state.utxo_set.read();
+    Ok(tx.trace(&*utxo_set).to_json())
   },
 
   #[doc="Traces execution of an individual script"]
-  #[usage="<hex-encoded script>"]
+  #[params=[Param { name: "script", ty: HexParam, required: true }]]
+  #[result=Returns { ty: ObjectResult, desc: "A trace of the script's execution" }]
   #[coinjoin=false]
   #[wallet=false]
-  pub fn script_trace(rpc: &RpcCall, _: &mut IdleState, params: Vec<json::Json>) {
-    match params.len() {
-      1 => {
-        let script: Script = try!(decode_hex_param(params[0].clone(), PrependLength));
-        Ok(script.trace(&mut vec![], None).to_json())
-      }
-      _ => Err(usage_error(rpc))
-    }
+  #[files=false]
+  #[public=false]
+  pub fn script_trace(_: &RpcCall, _: &mut IdleState, params: Vec<json::Json>) {
+    let script: Script = try!(decode_hex_param(params[0].clone(), PrependLength));
+    Ok(script.trace(&mut vec![], None).to_json())
   },
 
   #[doc="Checks whether a script pubkey can be proven to have no satisfying input. Returns 'spendable' or 'unspendable'."]
-  #[usage="<hex-encoded script>"]
+  #[params=[Param { name: "script", ty: HexParam, required: true }]]
+  #[result=Returns { ty: StringResult, desc: "Either `spendable` or `unspendable`" }]
   #[coinjoin=false]
   #[wallet=false]
-  pub fn script_unspendable(rpc: &RpcCall, _: &mut IdleState, params: Vec<json::Json>) {
-    match params.len() {
-      1 => {
-        let script: Script = try!(decode_hex_param(params[0].clone(), PrependLength));
-        Ok(json::String(if script.is_provably_unspendable() { "unspendable" } else { "spendable" }.to_string()))
-      }
-      _ => Err(usage_error(rpc))
-    }
+  #[files=false]
+  #[public=false]
+  pub fn script_unspendable(_: &RpcCall, _: &mut IdleState, params: Vec<json::Json>) {
+    let script: Script = try!(decode_hex_param(params[0].clone(), PrependLength));
+    Ok(json::String(if script.is_provably_unspendable() { "unspendable" } else { "spendable" }.to_string()))
   },
 
-  #[doc="Starts a new coinjoin session"]
-  #[usage="<target amount (satoshi)> <join duration (seconds)> <merge duration (seconds)>"]
+  #[doc="Starts a new coinjoin session, with the target amount in satoshi and durations in seconds. The optional policy object overrides fields of the configured session policy."]
+  #[params=[Param { name: "target", ty: IntegerParam, required: true },
+            Param { name: "join_duration", ty: IntegerParam, required: true },
+            Param { name: "merge_duration", ty: IntegerParam, required: true },
+            Param { name: "policy", ty: ObjectParam, required: false }]]
+  #[result=Returns { ty: StringResult, desc: "The ID of the new session" }]
   #[coinjoin=true]
   #[wallet=false]
-  pub fn coinjoin_start(rpc: &RpcCall, idle_state: &mut IdleState, params: Vec<json::Json>) { 
-    match params.len() {
-      3 => {
-        let target: u64 = try!(decode_param(params[0].clone()));
-        let join_duration = Duration::seconds(try!(decode_param(params[1].clone())));
-        let expiry_duration = Duration::seconds(try!(decode_param(params[2].clone())));
-
-        // Start session manager if we haven't
-        if idle_state.coinjoin.is_none() {
-          idle_state.coinjoin = Some(Server::new());
-        }
-        // Update the server state
-        let server = idle_state.coinjoin.get_mut_ref();
-        server.update_all();
-        // Obtain a donation address
-        let mut address = idle_state.wallet.new_address("coinjoin", External);
-        if address == Err(AccountNotFound) {
-          try!(idle_state.wallet.account_insert("coinjoin".to_string())
-                 .map_err(|e| bitcoin_json_error(WalletError,
-                                                 Some(json::String(e.to_string())))));
-          address = idle_state.wallet.new_address("coinjoin", External);
-        }
-        let address = try!(address.map_err(|e| bitcoin_json_error(WalletError,
-                                               Some(json::String(e.to_string())))));
-
-        // Saveout the wallet before using the address
-        try!(save_wallet(&idle_state.config, &idle_state.wallet)
-                 .map_err(|e| bitcoin_json_error(WalletError,
-                                                 Some(json::String(e.to_string())))));
-
-        // Add the new sesion
-        let session = try!(Session::new(target, join_duration, expiry_duration, address)
-                             .map_err(|e| bitcoin_json_error(BadRng,
-                                                             Some(json::String(e.to_string())))));
-        let id = session.id();
-        server.set_current_session(session);
-        Ok(id.to_json())
+  #[files=false]
+  #[public=false]
+  pub fn coinjoin_start(_: &RpcCall, idle_state: &mut IdleState, params: Vec<json::Json>) {
+    let target: u64 = try!(decode_param(params[0].clone()));
+    let join_duration = Duration::seconds(try!(decode_param(params[1].clone())));
+    let expiry_duration = Duration::seconds(try!(decode_param(params[2].clone())));
+    let policy = match params[3] {
+      json::Null => idle_state.config.coinjoin_policy.clone(),
+      ref policy => {
+        let partial: PartialSessionPolicy = try!(decode_param(policy.clone()));
+        partial.apply(&idle_state.config.coinjoin_policy)
       }
-      _ => Err(usage_error(rpc))
+    };
+
+    // Start session manager if we haven't
+    if idle_state.coinjoin.is_none() {
+      idle_state.coinjoin = Some(Server::new(idle_state.config.coinjoin_history_path.clone()));
+    }
+    // Update the server state
+    let server = idle_state.coinjoin.get_mut_ref();
+    server.update_all();
+    // Obtain a donation address
+    let mut address = idle_state.wallet.new_address("coinjoin", External);
+    if address == Err(AccountNotFound) {
+      try!(idle_state.wallet.account_insert("coinjoin".to_string())
+             .map_err(|e| bitcoin_json_error(WalletError,
+                                             Some(json::String(e.to_string())))));
+      address = idle_state.wallet.new_address("coinjoin", External);
     }
+    let address = try!(address.map_err(|e| bitcoin_json_error(WalletError,
+                                           Some(json::String(e.to_string())))));
+
+    // Saveout the wallet before using the address
+    try!(save_wallet(&idle_state.config, &idle_state.wallet)
+             .map_err(|e| bitcoin_json_error(WalletError,
+                                             Some(json::String(e.to_string())))));
+
+    // Blinded sessions need a key, which we don't have time to generate here
+    let blind_key = match policy.mode {
+      Transparent => None,
+      Blinded => match server.take_blind_key() {
+        Some(key) => Some(key),
+        None => { return Err(bitcoin_json_error(CoinjoinError(BlindKeyUnavailable), None)); }
+      }
+    };
+
+    // Add the new sesion
+    let session = try!(Session::new(target, join_duration, expiry_duration, policy, blind_key,
+                                    address)
+                         .map_err(|e| bitcoin_json_error(BadRng,
+                                                         Some(json::String(e.to_string())))));
+    let id = session.id();
+    server.set_current_session(session);
+    Ok(id.to_json())
   },
 
   #[doc="Gets the status of the current coinjoin session"]
-  #[usage="[session id]"]
+  #[params=[Param { name: "session_id", ty: StringParam, required: false }]]
+  #[result=Returns { ty: ObjectResult, desc: "The state of the session" }]
   #[coinjoin=true]
   #[wallet=false]
-  pub fn coinjoin_status(rpc: &RpcCall, idle_state: &mut IdleState, params: Vec<json::Json>) {
+  #[files=false]
+  #[public=true]
+  pub fn coinjoin_status(_: &RpcCall, idle_state: &mut IdleState, params: Vec<json::Json>) {
     if idle_state.coinjoin.is_none() {
       return Err(bitcoin_json_error(SessionNotFound, None));
     }
@@ -319,21 +602,79 @@ rpc_calls!{
     let server = idle_state.coinjoin.get_mut_ref();
     server.update_all();
 
-    match params.len() {
-      0 => server.current_session().map_or(Err(bitcoin_json_error(SessionNotFound, None)), |s| Ok(s.to_json())),
-      1 => {
-        let id: SessionId = try!(decode_param(params[0].clone()));
-        server.session(&id).map_or(Err(bitcoin_json_error(SessionNotFound, None)), |s| Ok(s.to_json()))
-      }
-      _ => Err(usage_error(rpc))
+    let session = try!(find_session(server, &params[0]));
+    Ok(session.to_json())
+  },
+
+  #[doc="Lists finished coinjoin sessions"]
+  #[params=[]]
+  #[result=Returns { ty: ArrayResult, desc: "An entry for each finished session" }]
+  #[coinjoin=true]
+  #[wallet=false]
+  #[files=false]
+  #[public=false]
+  pub fn coinjoin_history(_: &RpcCall, idle_state: &mut IdleState, _: Vec<json::Json>) {
+    // Update the server state, so that anything just finished is logged
+    match idle_state.coinjoin {
+      Some(ref mut server) => server.update_all(),
+      None => {}
+    }
+    let history = try!(read_history(&idle_state.config.coinjoin_history_path)
+                         .map_err(|e| bitcoin_json_error(HistoryError,
+                                                         Some(json::String(e.to_string())))));
+    Ok(history.to_json())
+  },
+
+  #[doc="Sums donations received by complete coinjoin sessions since the given unix time, per day (UTC) and in total, alongside the wallet's coinjoin account balance"]
+  #[params=[Param { name: "since", ty: IntegerParam, required: false }]]
+  #[result=Returns { ty: ObjectResult, desc: "Donation totals and the coinjoin account balance" }]
+  #[coinjoin=true]
+  #[wallet=false]
+  #[files=false]
+  #[public=false]
+  pub fn coinjoin_revenue(_: &RpcCall, idle_state: &mut IdleState, params: Vec<json::Json>) {
+    let since: i64 = match params[0] {
+      json::Null => 0,
+      ref since => try!(decode_param(since.clone()))
+    };
+    match idle_state.coinjoin {
+      Some(ref mut server) => server.update_all(),
+      None => {}
+    }
+    let history = try!(read_history(&idle_state.config.coinjoin_history_path)
+                         .map_err(|e| bitcoin_json_error(HistoryError,
+                                                         Some(json::String(e.to_string())))));
+
+    let mut total = 0u64;
+    let mut n_sessions = 0u;
+    let mut by_day = TreeMap::new();
+    for entry in history.iter().filter(|e| e.time >= since && e.txid.is_some()) {
+      let day = time::at_utc(time::Timespec::new(entry.time, 0)).rfc3339();
+      let day = day.as_slice().slice_to(10).to_string();
+      let day_total = by_day.find(&day).map_or(0, |n| *n) + entry.donation;
+      by_day.insert(day, day_total);
+      total += entry.donation;
+      n_sessions += 1;
     }
+
+    let mut ret = TreeMap::new();
+    ret.insert("total".to_string(), total.to_json());
+    ret.insert("sessions".to_string(), n_sessions.to_json());
+    ret.insert("by_day".to_string(), by_day.to_json());
+    // Donations may since have been spent, so this need not match the total
+    ret.insert("wallet_balance".to_string(), idle_state.wallet.balance("coinjoin").to_json());
+    Ok(json::Object(ret))
   },
 
   #[doc="Adds a unsigned transaction to the current coinjoin session"]
-  #[usage="<rawtx> [session id]"]
+  #[params=[Param { name: "tx", ty: HexParam, required: true },
+            Param { name: "session_id", ty: StringParam, required: false }]]
+  #[result=Returns { ty: BooleanResult, desc: "true, if the transaction was accepted" }]
   #[coinjoin=true]
   #[wallet=false]
-  pub fn coinjoin_add_raw_unsigned(rpc: &RpcCall, idle_state: &mut IdleState, params: Vec<json::Json>) {
+  #[files=false]
+  #[public=true]
+  pub fn coinjoin_add_raw_unsigned(_: &RpcCall, idle_state: &mut IdleState, params: Vec<json::Json>) {
     if idle_state.coinjoin.is_none() {
       return Err(bitcoin_json_error(SessionNotFound, None));
     }
@@ -341,22 +682,7 @@ rpc_calls!{
     let server = idle_state.coinjoin.get_mut_ref();
     server.update_all();
 
-    let session = match params.len() {
-      1 => {
-        match server.current_session_mut() {
-          Some(s) => s,
-          None => { return Err(bitcoin_json_error(SessionNotFound, None)); }
-        }
-      }
-      2 => {
-        let id: SessionId = try!(decode_param(params[1].clone()));
-        match server.session_mut(&id) {
-          Some(s) => s,
-          None => { return Err(bitcoin_json_error(SessionNotFound, None)); }
-        }
-      }
-      _ => { return Err(usage_error(rpc)); }
-    };
+    let session = try!(find_session_mut(server, &params[1]));
     let tx = try!(decode_hex_param(params[0].clone(), DecodeAsIs));
     match session.add_unsigned(&tx, &*idle_state.utxo_set.read()) {
       Ok(()) => Ok(json::Boolean(true)),
@@ -364,11 +690,67 @@ rpc_calls!{
     }
   },
 
+  #[doc="Registers the inputs and change of a transaction with the current blinded coinjoin session, returning a blind signature on the given hex-encoded blinded output token"]
+  #[params=[Param { name: "tx", ty: HexParam, required: true },
+            Param { name: "blinded_token", ty: StringParam, required: true },
+            Param { name: "session_id", ty: StringParam, required: false }]]
+  #[result=Returns { ty: StringResult, desc: "The hex-encoded blind signature on the token" }]
+  #[coinjoin=true]
+  #[wallet=false]
+  #[files=false]
+  #[public=true]
+  pub fn coinjoin_register_inputs(_: &RpcCall, idle_state: &mut IdleState, params: Vec<json::Json>) {
+    if idle_state.coinjoin.is_none() {
+      return Err(bitcoin_json_error(SessionNotFound, None));
+    }
+    // Update the server state
+    let server = idle_state.coinjoin.get_mut_ref();
+    server.update_all();
+
+    let session = try!(find_session_mut(server, &params[2]));
+    let tx = try!(decode_hex_param(params[0].clone(), DecodeAsIs));
+    let token = try!(decode_bigint_param(params[1].clone()));
+    match session.register_inputs(&tx, &token, &*idle_state.utxo_set.read()) {
+      Ok(sig) => Ok(json::String(blind::to_hex(&sig))),
+      Err(e) => Err(bitcoin_json_error(CoinjoinError(e), None))
+    }
+  },
+
+  #[doc="Registers an output of the target value with the current blinded coinjoin session, given its script pubkey and hex-encoded unblinded signature. To hide which inputs it belongs to, use a separate connection from the one used to register them."]
+  #[params=[Param { name: "script_pubkey", ty: HexParam, required: true },
+            Param { name: "signature", ty: StringParam, required: true },
+            Param { name: "session_id", ty: StringParam, required: false }]]
+  #[result=Returns { ty: BooleanResult, desc: "true, if the output was accepted" }]
+  #[coinjoin=true]
+  #[wallet=false]
+  #[files=false]
+  #[public=true]
+  pub fn coinjoin_register_output(_: &RpcCall, idle_state: &mut IdleState, params: Vec<json::Json>) {
+    if idle_state.coinjoin.is_none() {
+      return Err(bitcoin_json_error(SessionNotFound, None));
+    }
+    // Update the server state
+    let server = idle_state.coinjoin.get_mut_ref();
+    server.update_all();
+
+    let session = try!(find_session_mut(server, &params[2]));
+    let script: Script = try!(decode_hex_param(params[0].clone(), PrependLength));
+    let sig = try!(decode_bigint_param(params[1].clone()));
+    match session.register_output(script, &sig) {
+      Ok(()) => Ok(json::Boolean(true)),
+      Err(e) => Err(bitcoin_json_error(CoinjoinError(e), None))
+    }
+  },
+
   #[doc="Submits a (partially-)signed transaction to the current coinjoin session"]
-  #[usage="<rawtx> [session id]"]
+  #[params=[Param { name: "tx", ty: HexParam, required: true },
+            Param { name: "session_id", ty: StringParam, required: false }]]
+  #[result=Returns { ty: BooleanResult, desc: "true, if the signatures were accepted" }]
   #[coinjoin=true]
   #[wallet=false]
-  pub fn coinjoin_add_raw_signed(rpc: &RpcCall, idle_state: &mut IdleState, params: Vec<json::Json>) {
+  #[files=false]
+  #[public=true]
+  pub fn coinjoin_add_raw_signed(_: &RpcCall, idle_state: &mut IdleState, params: Vec<json::Json>) {
     if idle_state.coinjoin.is_none() {
       return Err(bitcoin_json_error(SessionNotFound, None));
     }
@@ -376,22 +758,7 @@ rpc_calls!{
     let server = idle_state.coinjoin.get_mut_ref();
     server.update_all();
 
-    let session = match params.len() {
-      1 => {
-        match server.current_session_mut() {
-          Some(s) => s,
-          None => { return Err(bitcoin_json_error(SessionNotFound, None)); }
-        }
-      }
-      2 => {
-        let id: SessionId = try!(decode_param(params[1].clone()));
-        match server.session_mut(&id) {
-          Some(s) => s,
-          None => { return Err(bitcoin_json_error(SessionNotFound, None)); }
-        }
-      }
-      _ => { return Err(usage_error(rpc)); }
-    };
+    let session = try!(find_session_mut(server, &params[1]));
     let tx = try!(decode_hex_param(params[0].clone(), DecodeAsIs));
 
     // Add the signed transaction
@@ -410,11 +777,14 @@ rpc_calls!{
 }
 
 enum BitcoinJsonError {
+  BackendUnavailable,
   BadRng,
   BlockNotFound,
   CoinjoinError(CoinjoinError),
+  HistoryError,
   InvalidTx,
   SessionNotFound,
+  SnapshotError,
   WalletError
 }
 
@@ -449,56 +819,427 @@ fn decode_hex_param<T:ConsensusDecodable<RawDecoder<MemReader>, IoError>>(param:
                                 Some(json::String(e.to_string()))))
 }
 
+/// Decode a hex-encoded big integer parameter
+fn decode_bigint_param(param: json::Json) -> jsonrpc::JsonResult<BigUint> {
+  let hex: String = try!(decode_param(param));
+  match blind::from_hex(hex.as_slice()) {
+    Some(n) => Ok(n),
+    None => Err(standard_error(InvalidParams,
+                               Some(json::String(format!("`{}` is not a valid hex number", hex)))))
+  }
+}
+
+/// Codes and messages of the errors specific to this server, other than
+/// coinjoin errors, which are listed in `coinjoin::ERROR_CODES`. Code -3 was
+/// once used for all coinjoin errors.
+static SERVER_ERRORS: [(i32, &'static str), ..8] = [
+  (-1, "Bad RNG"),
+  (-2, "Block not found"),
+  (-4, "Transaction invalid"),
+  (-5, "Coinjoin session not found"),
+  (-6, "Wallet error"),
+  (-7, "Coinjoin history error"),
+  (-8, "Backend unavailable"),
+  (-9, "UTXO snapshot error")
+];
+
 /// Create a standard error responses
-fn bitcoin_json_error(code: BitcoinJsonError, data: Option<json::Json>) -> Error {
-  match code {
-    BadRng => Error {
-      code: -1,
-      message: "Bad RNG".to_string(),
-      data: data
-    },
-    BlockNotFound => Error {
-      code: -2,
-      message: "Block not found".to_string(),
-      data: data
-    },
-    CoinjoinError(e) => Error {
-      code: -3,
-      message: format!("Coinjoin error: {}", e),
-      data: data
-    },
-    InvalidTx => Error {
-      code: -4,
-      message: "Transaction invalid".to_string(),
-      data: data
-    },
-    SessionNotFound => Error {
-      code: -5,
-      message: "Coinjoin session not found".to_string(),
-      data: data
-    },
-    WalletError => Error {
-      code: -6,
-      message: "Wallet error".to_string(),
-      data: data
+fn bitcoin_json_error(err: BitcoinJsonError, data: Option<json::Json>) -> Error {
+  let code = match err {
+    BadRng => -1,
+    BlockNotFound => -2,
+    CoinjoinError(ref e) => {
+      // Coinjoin errors carry their own code and data
+      return Error {
+        code: e.code(),
+        message: e.message().to_string(),
+        data: Some(e.data())
+      };
     }
+    InvalidTx => -4,
+    SessionNotFound => -5,
+    WalletError => -6,
+    HistoryError => -7,
+    BackendUnavailable => -8,
+    SnapshotError => -9
+  };
+  let message = SERVER_ERRORS.iter().find(|&&(c, _)| c == code).unwrap().val1().to_string();
+  Error {
+    code: code,
+    message: message,
+    data: data
   }
 }
 
+/// The error given to requests for a network whose task is down, with its
+/// status as data if given
+pub fn backend_unavailable(status: Option<json::Json>) -> Error {
+  bitcoin_json_error(BackendUnavailable, status)
+}
+
+/// Describes a call as an OpenRPC method object
+fn openrpc_method(rpc: &RpcCall) -> json::Json {
+  let params: Vec<json::Json> = rpc.params.iter().map(|param| {
+    let mut obj = TreeMap::new();
+    obj.insert("name".to_string(), json::String(param.name.to_string()));
+    obj.insert("required".to_string(), json::Boolean(param.required));
+    obj.insert("schema".to_string(), param.ty.schema());
+    json::Object(obj)
+  }).collect();
+
+  let mut result_schema = TreeMap::new();
+  result_schema.insert("type".to_string(), json::String(rpc.result.ty.name().to_string()));
+  let mut result = TreeMap::new();
+  result.insert("name".to_string(), json::String("result".to_string()));
+  result.insert("description".to_string(), json::String(rpc.result.desc.to_string()));
+  result.insert("schema".to_string(), json::Object(result_schema));
+
+  let mut ret = TreeMap::new();
+  ret.insert("name".to_string(), json::String(rpc.name.to_string()));
+  ret.insert("description".to_string(), json::String(rpc.desc.to_string()));
+  ret.insert("paramStructure".to_string(), json::String("either".to_string()));
+  ret.insert("params".to_string(), json::List(params));
+  ret.insert("result".to_string(), json::Object(result));
+  json::Object(ret)
+}
+
+/// Describes every error a call may return, as OpenRPC error objects keyed by name
+fn openrpc_errors() -> json::Json {
+  let standard = [ParseError, InvalidRequest, MethodNotFound, InvalidParams, InternalError];
+  let mut errors: Vec<(i32, String)> = standard.iter().map(|&e| {
+    let err = standard_error(e, None);
+    (err.code, err.message)
+  }).collect();
+  errors.extend(SERVER_ERRORS.iter().map(|&(code, message)| (code, message.to_string())));
+  errors.extend(coinjoin::ERROR_CODES.iter().map(|&(code, message)| (code, message.to_string())));
+
+  let mut ret = TreeMap::new();
+  for (code, message) in errors.move_iter() {
+    let mut obj = TreeMap::new();
+    obj.insert("code".to_string(), code.to_json());
+    obj.insert("message".to_string(), json::String(message.clone()));
+    ret.insert(message, json::Object(obj));
+  }
+  json::Object(ret)
+}
+
+/// Generates a usage string from a call's parameter schema
+fn usage(rpc: &RpcCall) -> String {
+  let params: Vec<String> = rpc.params.iter().map(|param| {
+    if param.required {
+      format!("<{}: {}>", param.name, param.ty.name())
+    } else {
+      format!("[{}: {}]", param.name, param.ty.name())
+    }
+  }).collect();
+  params.connect(" ")
+}
+
 /// Generates a `usage` error message
-fn usage_error(rpc: &RpcCall) -> Error {
+fn usage_error(rpc: &RpcCall, reason: String) -> Error {
   standard_error(InvalidParams,
-                 Some(json::String(format!("Usage: {} {}", rpc.name, rpc.usage))))
+                 Some(json::String(format!("{}. Usage: {} {}", reason, rpc.name, usage(rpc)))))
+}
+
+/// Checks a request's parameters against the call's schema, returning them
+/// in schema order with `null` in place of any missing optional parameter
+fn normalize_params(rpc: &RpcCall, params: Params) -> jsonrpc::JsonResult<Vec<json::Json>> {
+  let mut ret = match params {
+    Positional(list) => {
+      if list.len() > rpc.params.len() {
+        return Err(usage_error(rpc, format!("Expected at most {} parameters, got {}",
+                                            rpc.params.len(), list.len())));
+      }
+      list
+    }
+    Named(mut map) => {
+      let mut list = Vec::with_capacity(rpc.params.len());
+      for param in rpc.params.iter() {
+        list.push(map.pop(&param.name.to_string()).unwrap_or(json::Null));
+      }
+      match map.keys().next() {
+        Some(key) => { return Err(usage_error(rpc, format!("Unknown parameter `{}`", key))); }
+        None => {}
+      }
+      list
+    }
+  };
+
+  while ret.len() < rpc.params.len() {
+    ret.push(json::Null);
+  }
+  for (param, value) in rpc.params.iter().zip(ret.iter()) {
+    if *value == json::Null {
+      if param.required {
+        return Err(usage_error(rpc, format!("Missing parameter `{}`", param.name)));
+      }
+    } else if !param.ty.matches(value) {
+      return Err(usage_error(rpc, format!("Parameter `{}` should be of type {}",
+                                          param.name, param.ty.name())));
+    }
+  }
+  Ok(ret)
+}
+
+/// Looks up a coinjoin session by ID, or the current session if the ID is `null`
+fn find_session<'a>(server: &'a Server, id: &json::Json) -> jsonrpc::JsonResult<&'a Session> {
+  let session = match *id {
+    json::Null => server.current_session(),
+    ref id => {
+      let id: SessionId = try!(decode_param(id.clone()));
+      server.session(&id)
+    }
+  };
+  session.ok_or(bitcoin_json_error(SessionNotFound, None))
+}
+
+/// Looks up a coinjoin session by ID, or the current session if the ID is `null`
+fn find_session_mut<'a>(server: &'a mut Server, id: &json::Json) -> jsonrpc::JsonResult<&'a mut Session> {
+  let session = match *id {
+    json::Null => server.current_session_mut(),
+    ref id => {
+      let id: SessionId = try!(decode_param(id.clone()));
+      server.session_mut(&id)
+    }
+  };
+  session.ok_or(bitcoin_json_error(SessionNotFound, None))
+}
+
+/// Looks up a call by name, accepting `rpc.discover` for `rpc_discover`
+fn find_call(method: &str) -> Option<&'static RpcCall> {
+  let method = if method == RPC_DISCOVER { "rpc_discover" } else { method };
+  RPC_CALLS.find_equiv(&method)
+}
+
+/// What categories of call an RPC client may make
+#[deriving(Clone, PartialEq, Eq, Show)]
+pub struct Permissions {
+  /// Whether coinjoin calls are allowed
+  pub coinjoin: bool,
+  /// Whether wallet calls are allowed
+  pub wallet: bool,
+  /// Whether calls which write files on the wallet's machine are allowed
+  pub files: bool
+}
+
+/// Whether the named call may be made by a client with the given permissions
+pub fn is_permitted(method: &str, permissions: &Permissions) -> bool {
+  match find_call(method) {
+    Some(rpc) => (!rpc.coinjoin || permissions.coinjoin) && (!rpc.wallet || permissions.wallet) &&
+                 (!rpc.files || permissions.files),
+    None => false
+  }
+}
+
+/// Decodes a single JSON-RPC request object
+pub fn parse_request(request: json::Json) -> Result<Request, Error> {
+  let mut obj = match request {
+    json::Object(obj) => obj,
+    _ => { return Err(standard_error(InvalidRequest,
+                                     Some(json::String("request is not an object".to_string())))); }
+  };
+  let method = match obj.pop(&"method".to_string()) {
+    Some(json::String(method)) => method,
+    _ => { return Err(standard_error(InvalidRequest,
+                                     Some(json::String("request has no method".to_string())))); }
+  };
+  let params = match obj.pop(&"params".to_string()) {
+    None | Some(json::Null) => Positional(vec![]),
+    Some(json::List(list)) => Positional(list),
+    Some(json::Object(map)) => Named(map),
+    Some(_) => { return Err(standard_error(InvalidRequest,
+                                           Some(json::String("params must be an array or object".to_string())))); }
+  };
+  Ok(Request {
+    method: method,
+    params: params,
+    id: obj.pop(&"id".to_string()).unwrap_or(json::Null)
+  })
+}
+
+/// Handles the body of an HTTP request, which may hold a single JSON-RPC
+/// request or a batch of up to `max_batch_size` of them. Each request whose
+/// method passes `allowed` is passed on to the network task. Returns the
+/// response body, or None if every request was a notification.
+pub fn dispatch_body(body: &str, max_batch_size: uint, allowed: |&str| -> bool,
+                     rpc_tx: &Sender<RpcRequest>) -> Option<json::Json> {
+  match json::from_str(body) {
+    Ok(json::List(batch)) => {
+      if batch.is_empty() {
+        return Some(response_json(Err(standard_error(InvalidRequest,
+                                                     Some(json::String("empty batch".to_string())))),
+                                  json::Null));
+      }
+      if batch.len() > max_batch_size {
+        let reason = format!("batch of {} requests is over the limit of {}", batch.len(), max_batch_size);
+        return Some(response_json(Err(standard_error(InvalidRequest, Some(json::String(reason)))),
+                                  json::Null));
+      }
+      let mut ret = Vec::with_capacity(batch.len());
+      for request in batch.move_iter() {
+        match dispatch_request(request, |method| allowed(method), rpc_tx) {
+          Some(response) => { ret.push(response); }
+          None => {}
+        }
+      }
+      if ret.is_empty() { None } else { Some(json::List(ret)) }
+    }
+    Ok(request) => dispatch_request(request, allowed, rpc_tx),
+    Err(e) => Some(response_json(Err(standard_error(ParseError, Some(json::String(e.to_string())))),
+                                 json::Null))
+  }
+}
+
+/// Passes a single request on to the network task, returning its response,
+/// or None if it is a notification, which gets no response
+fn dispatch_request(request: json::Json, allowed: |&str| -> bool, rpc_tx: &Sender<RpcRequest>)
+                    -> Option<json::Json> {
+  // Pull out the ID first, so that even a malformed request gets it back.
+  // A request without one is a notification.
+  let (id, is_notification) = match request {
+    json::Object(ref obj) => match obj.find(&"id".to_string()) {
+      Some(id) => (id.clone(), false),
+      None => (json::Null, true)
+    },
+    _ => (json::Null, false)
+  };
+  let result = match parse_request(request) {
+    Ok(request) => {
+      if allowed(request.method.as_slice()) {
+        // The network task may be down, or die before answering
+        let (tx, rx) = channel();
+        match rpc_tx.send_opt((request, tx)) {
+          Ok(()) => match rx.recv_opt() {
+            Ok(result) => result,
+            Err(()) => Err(backend_unavailable(None))
+          },
+          Err(_) => Err(backend_unavailable(None))
+        }
+      } else {
+        Err(standard_error(MethodNotFound, Some(json::String(request.method.clone()))))
+      }
+    }
+    // Malformed requests are answered even without an ID
+    Err(e) => { return Some(response_json(Err(e), id)); }
+  };
+  if is_notification { None } else { Some(response_json(result, id)) }
+}
+
+/// Builds a JSON-RPC response object
+pub fn response_json(result: jsonrpc::JsonResult<json::Json>, id: json::Json) -> json::Json {
+  let mut obj = TreeMap::new();
+  match result {
+    Ok(result) => {
+      obj.insert("result".to_string(), result);
+      obj.insert("error".to_string(), json::Null);
+    }
+    Err(e) => {
+      let mut err = TreeMap::new();
+      err.insert("code".to_string(), e.code.to_json());
+      err.insert("message".to_string(), json::String(e.message));
+      err.insert("data".to_string(), e.data.unwrap_or(json::Null));
+      obj.insert("result".to_string(), json::Null);
+      obj.insert("error".to_string(), json::Object(err));
+    }
+  }
+  obj.insert("id".to_string(), id);
+  json::Object(obj)
+}
+
+/// Whether the named call may be made by untrusted coinjoin participants
+pub fn is_public_call(method: &str) -> bool {
+  match find_call(method) {
+    Some(rpc) => rpc.public,
+    None => false
+  }
+}
+
+/// Makes a call, recording how long it took in the metrics
+fn timed_call(rpc: &RpcCall, idle_state: &mut IdleState, params: Vec<json::Json>) -> JsonResult {
+  let start = time::precise_time_ns();
+  let result = (rpc.call)(rpc, idle_state, params);
+  idle_state.metrics.record_rpc(rpc.name, time::precise_time_ns() - start, result.is_ok());
+  result
+}
+
+/// Handles a JSON-RPC request from the public coinjoin listener, which may
+/// only make participant-facing calls
+pub fn handle_public_rpc(request: Request, idle_state: &mut IdleState) -> JsonResult {
+  match find_call(request.method.as_slice()) {
+    Some(rpc) if rpc.public && idle_state.config.coinjoin_on => {
+      let params = try!(normalize_params(rpc, request.params));
+      timed_call(rpc, idle_state, params)
+    }
+    _ => Err(standard_error(MethodNotFound,
+                            Some(json::String(request.method.clone()))))
+  }
 }
 
 /// Handles a JSON-RPC request, returning a result to be given back to the peer
-pub fn handle_rpc(request: jsonrpc::Request, idle_state: &mut IdleState) -> JsonResult {
-  let method = request.method.as_slice();
-  match RPC_CALLS.find_equiv(&method) {
-    Some(rpc) if !rpc.coinjoin || idle_state.config.coinjoin_on =>
-      (rpc.call)(rpc, idle_state, request.params),
+pub fn handle_rpc(request: Request, idle_state: &mut IdleState) -> JsonResult {
+  match find_call(request.method.as_slice()) {
+    Some(rpc) if !rpc.coinjoin || idle_state.config.coinjoin_on => {
+      let params = try!(normalize_params(rpc, request.params));
+      timed_call(rpc, idle_state, params)
+    }
     _ => Err(standard_error(MethodNotFound,
                             Some(json::String(request.method.clone()))))
   }
 }
 
+
+#[cfg(test)]
+mod tests {
+  use serialize::json;
+
+  use super::{RpcRequest, dispatch_body};
+
+  // A network task which answers every request with its method name,
+  // and counts them
+  fn echo_task() -> (Sender<RpcRequest>, Receiver<String>) {
+    let (rpc_tx, rpc_rx) = channel::<RpcRequest>();
+    let (seen_tx, seen_rx) = channel();
+    spawn(proc() {
+      for (request, reply) in rpc_rx.iter() {
+        seen_tx.send(request.method.clone());
+        reply.send(Ok(json::String(request.method)));
+      }
+    });
+    (rpc_tx, seen_rx)
+  }
+
+  #[test]
+  fn notifications_get_no_response() {
+    let (rpc_tx, seen) = echo_task();
+    let body = r#"{"method": "getblockcount", "params": []}"#;
+    assert_eq!(dispatch_body(body, 10, |_| true, &rpc_tx), None);
+    // but are still made
+    assert_eq!(seen.recv().as_slice(), "getblockcount");
+
+    let body = r#"[{"method": "a"}, {"method": "b", "id": 1}, {"id": 2}]"#;
+    let response = dispatch_body(body, 10, |_| true, &rpc_tx).unwrap();
+    match response {
+      json::List(ref list) => {
+        // The malformed request is answered, and the notification isn't
+        assert_eq!(list.len(), 2);
+        assert_eq!(list[0].find(&"result".to_string()), Some(&json::String("b".to_string())));
+        assert_eq!(list[1].find(&"id".to_string()), Some(&json::U64(2)));
+      }
+      _ => fail!("batch got response {}", response)
+    }
+
+    let body = r#"[{"method": "a"}, {"method": "b"}]"#;
+    assert_eq!(dispatch_body(body, 10, |_| true, &rpc_tx), None);
+  }
+
+  #[test]
+  fn batches_are_limited() {
+    let (rpc_tx, seen) = echo_task();
+    let body = r#"[{"method": "a", "id": 1}, {"method": "b", "id": 2}]"#;
+    let response = dispatch_body(body, 1, |_| true, &rpc_tx).unwrap();
+    assert!(response.find(&"error".to_string()).unwrap().find(&"code".to_string()).is_some());
+    // None of the batch was made
+    drop(rpc_tx);
+    assert!(seen.recv_opt().is_err());
+  }
+}
@@ -0,0 +1,259 @@
+/* The Wizards' Wallet
+ * Written in 2014 by
+ *   Andrew Poelstra <apoelstra@wpsoftware.net>
+ *
+ * To the extent possible under law, the author(s) have dedicated all
+ * copyright and related and neighboring rights to this software to
+ * the public domain worldwide. This software is distributed without
+ * any warranty.
+ *
+ * You should have received a copy of the CC0 Public Domain Dedication
+ * along with this software.
+ * If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+ */
+
+//! # UTXO Snapshots
+//!
+//! A snapshot is a UTXO set written out together with the block it was
+//! taken at, so that a new wallet can start its UTXO sync from there rather
+//! than from genesis. The file starts with a header giving the network
+//! magic, the hash and height of the tip block, and the hash of the
+//! serialized UTXO set which follows it.
+//!
+//! Nothing in a snapshot can be checked against the blockchain, so a
+//! snapshot is only loaded if its content hash matches one pinned in the
+//! configuration by somebody who trusts it.
+//!
+
+use std::collections::TreeMap;
+use std::io::{File, IoError, IoResult, InvalidInput, MemReader, Open, PathAlreadyExists, Write};
+use std::io::fs;
+use std::rand::{Rng, task_rng};
+use serialize::json::{mod, ToJson};
+
+use libc;
+
+use bitcoin::blockdata::utxoset::UtxoSet;
+use bitcoin::network::constants::{Network, magic};
+use bitcoin::network::encodable::ConsensusDecodable;
+use bitcoin::network::serialize::{RawDecoder, deserialize, serialize};
+use bitcoin::util::hash::Sha256dHash;
+
+/// Length of the header: magic, tip hash, height and content hash
+static HEADER_LEN: uint = 4 + 32 + 8 + 32;
+
+/// What is known about a snapshot
+#[deriving(Clone, PartialEq, Eq, Show)]
+pub struct SnapshotInfo {
+  /// Hash of the last block applied to the UTXO set
+  pub tip_hash: Sha256dHash,
+  /// Height of that block
+  pub height: u64,
+  /// Hash of the serialized UTXO set
+  pub content_hash: Sha256dHash,
+  /// Number of unspent outputs in the UTXO set
+  pub n_utxos: u64
+}
+
+impl ToJson for SnapshotInfo {
+  fn to_json(&self) -> json::Json {
+    let mut obj = TreeMap::new();
+    obj.insert("tip_hash".to_string(), self.tip_hash.to_json());
+    obj.insert("height".to_string(), self.height.to_json());
+    obj.insert("content_hash".to_string(), self.content_hash.to_json());
+    obj.insert("n_utxos".to_string(), self.n_utxos.to_json());
+    json::Object(obj)
+  }
+}
+
+/// Writes a snapshot of the UTXO set, whose last block is at `height`. An
+/// existing file is never replaced, and the snapshot only appears at `path`
+/// once it has been written in full.
+pub fn dump_utxo_set(path: &Path, network: Network, utxo_set: &UtxoSet, height: uint)
+                     -> IoResult<SnapshotInfo> {
+  // Don't bother serializing the set if it has nowhere to go. This is only
+  // a courtesy: linking the snapshot into place is what keeps files safe.
+  if path.exists() {
+    return Err(already_exists(path));
+  }
+  let body = try!(serialize(utxo_set));
+  let info = SnapshotInfo {
+    tip_hash: utxo_set.last_hash(),
+    height: height as u64,
+    content_hash: Sha256dHash::from_data(body.as_slice()),
+    n_utxos: utxo_set.n_utxos() as u64
+  };
+
+  // Write next to the destination, so that it can be linked into place on the
+  // same filesystem. Unlike a rename, linking fails if anything got there first.
+  let (tmp_path, mut file) = try!(create_temp_file(path));
+  let res = write_snapshot(&mut file, network, &info, body.as_slice())
+              .and_then(|()| fs::link(&tmp_path, path).map_err(|e| {
+                if e.kind == PathAlreadyExists { already_exists(path) } else { e }
+              }));
+  drop(file);
+  // The temporary name was ours alone, so it goes whether or not we succeeded
+  let _ = fs::unlink(&tmp_path);
+  res.map(|()| info)
+}
+
+/// Creates a new file next to `path` under a random name, never opening
+/// one which already exists
+fn create_temp_file(path: &Path) -> IoResult<(Path, File)> {
+  let mut tmp_name = path.filename().unwrap_or(b"snapshot").to_vec();
+  tmp_name.push_all(format!(".{:016x}.tmp", task_rng().gen::<u64>()).as_bytes());
+  let tmp_path = path.with_filename(tmp_name);
+
+  // Permissions are as for `File::create`, less the umask
+  let fd = tmp_path.with_c_str(|tmp_path| unsafe {
+    libc::open(tmp_path, libc::O_WRONLY | libc::O_CREAT | libc::O_EXCL, 0o666 as libc::c_int)
+  });
+  if fd == -1 {
+    return Err(IoError::last_error());
+  }
+  unsafe { libc::close(fd); }
+
+  let file = try!(File::open_mode(&tmp_path, Open, Write));
+  Ok((tmp_path, file))
+}
+
+fn write_snapshot(file: &mut File, network: Network, info: &SnapshotInfo, body: &[u8]) -> IoResult<()> {
+  try!(file.write(try!(serialize(&magic(network))).as_slice()));
+  try!(file.write(try!(serialize(&info.tip_hash)).as_slice()));
+  try!(file.write(try!(serialize(&info.height)).as_slice()));
+  try!(file.write(try!(serialize(&info.content_hash)).as_slice()));
+  try!(file.write(body));
+  file.fsync()
+}
+
+/// Reads a snapshot, checking that it is for the right network and that
+/// its contents hash to `pinned_hash`
+pub fn load_utxo_snapshot(path: &Path, network: Network, pinned_hash: Sha256dHash)
+                          -> IoResult<(UtxoSet, SnapshotInfo)> {
+  let data = try!(File::open(path).read_to_end());
+  if data.len() < HEADER_LEN {
+    return Err(snapshot_error("file is too short to have a header".to_string()));
+  }
+
+  let mut decoder = RawDecoder::new(MemReader::new(data.slice_to(HEADER_LEN).to_vec()));
+  let file_magic: u32 = try!(ConsensusDecodable::consensus_decode(&mut decoder));
+  let tip_hash: Sha256dHash = try!(ConsensusDecodable::consensus_decode(&mut decoder));
+  let height: u64 = try!(ConsensusDecodable::consensus_decode(&mut decoder));
+  let content_hash: Sha256dHash = try!(ConsensusDecodable::consensus_decode(&mut decoder));
+
+  if file_magic != magic(network) {
+    return Err(snapshot_error(format!("snapshot is not for {}", network)));
+  }
+  if content_hash != pinned_hash {
+    return Err(snapshot_error(format!("snapshot has content hash {:x}, not the pinned {:x}",
+                                      content_hash, pinned_hash)));
+  }
+  let body = data.slice_from(HEADER_LEN);
+  // The header's hash means nothing unless the contents match it
+  let actual_hash = Sha256dHash::from_data(body);
+  if actual_hash != content_hash {
+    return Err(snapshot_error(format!("snapshot is corrupt: contents hash to {:x}", actual_hash)));
+  }
+
+  let utxo_set: UtxoSet = try!(deserialize(body.to_vec()));
+  if utxo_set.last_hash() != tip_hash {
+    return Err(snapshot_error(format!("snapshot header gives tip {:x}, but its UTXO set is at {:x}",
+                                      tip_hash, utxo_set.last_hash())));
+  }
+  let info = SnapshotInfo {
+    tip_hash: tip_hash,
+    height: height,
+    content_hash: content_hash,
+    n_utxos: utxo_set.n_utxos() as u64
+  };
+  Ok((utxo_set, info))
+}
+
+fn already_exists(path: &Path) -> IoError {
+  IoError {
+    kind: PathAlreadyExists,
+    desc: "Snapshot file already exists",
+    detail: Some(path.display().to_string())
+  }
+}
+
+fn snapshot_error(detail: String) -> IoError {
+  IoError {
+    kind: InvalidInput,
+    desc: "Invalid UTXO snapshot",
+    detail: Some(detail)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::io::{File, TempDir};
+  use std::io::fs;
+
+  use bitcoin::blockdata::utxoset::UtxoSet;
+  use bitcoin::network::constants::{Bitcoin, BitcoinTestnet};
+  use bitcoin::util::hash::Sha256dHash;
+
+  use constants::DEFAULT_BLOCKCHAIN_N_FULL_BLOCKS;
+  use super::{dump_utxo_set, load_utxo_snapshot};
+
+  #[test]
+  fn snapshot_needs_pinned_hash() {
+    let dir = TempDir::new("wizards-wallet-test").unwrap();
+    let path = dir.path().join("snapshot.dat");
+    let utxo_set = UtxoSet::new(Bitcoin, DEFAULT_BLOCKCHAIN_N_FULL_BLOCKS);
+    let info = dump_utxo_set(&path, Bitcoin, &utxo_set, 0).unwrap();
+    assert_eq!(info.tip_hash, utxo_set.last_hash());
+
+    let (loaded, loaded_info) = load_utxo_snapshot(&path, Bitcoin, info.content_hash).unwrap();
+    assert_eq!(loaded_info, info);
+    assert_eq!(loaded.last_hash(), utxo_set.last_hash());
+    // Any other hash, or another network, is refused
+    assert!(load_utxo_snapshot(&path, Bitcoin, Sha256dHash::from_data(b"")).is_err());
+    assert!(load_utxo_snapshot(&path, BitcoinTestnet, info.content_hash).is_err());
+
+    // As is a snapshot whose contents don't match its header
+    let mut data = File::open(&path).read_to_end().unwrap();
+    let last = data.len() - 1;
+    *data.get_mut(last) ^= 1;
+    File::create(&path).write(data.as_slice()).unwrap();
+    assert!(load_utxo_snapshot(&path, Bitcoin, info.content_hash).is_err());
+  }
+
+  #[test]
+  fn existing_files_are_not_replaced() {
+    let dir = TempDir::new("wizards-wallet-test").unwrap();
+    let path = dir.path().join("snapshot.dat");
+    File::create(&path).write_str("precious").unwrap();
+    let utxo_set = UtxoSet::new(Bitcoin, DEFAULT_BLOCKCHAIN_N_FULL_BLOCKS);
+    assert!(dump_utxo_set(&path, Bitcoin, &utxo_set, 0).is_err());
+    assert_eq!(File::open(&path).read_to_string().unwrap().as_slice(), "precious");
+
+    // A successful dump leaves nothing else behind
+    dump_utxo_set(&dir.path().join("other.dat"), Bitcoin, &utxo_set, 0).unwrap();
+    let mut names: Vec<String> = fs::readdir(dir.path()).unwrap().iter()
+                                   .map(|p| p.filename_str().unwrap().to_string()).collect();
+    names.sort();
+    assert_eq!(names, vec!["other.dat".to_string(), "snapshot.dat".to_string()]);
+  }
+
+  #[test]
+  fn stale_temporary_files_are_left_alone() {
+    let dir = TempDir::new("wizards-wallet-test").unwrap();
+    let path = dir.path().join("snapshot.dat");
+    let tmp_path = dir.path().join("snapshot.dat.tmp");
+    File::create(&tmp_path).write_str("precious").unwrap();
+    let utxo_set = UtxoSet::new(Bitcoin, DEFAULT_BLOCKCHAIN_N_FULL_BLOCKS);
+    let info = dump_utxo_set(&path, Bitcoin, &utxo_set, 0).unwrap();
+    assert_eq!(File::open(&tmp_path).read_to_string().unwrap().as_slice(), "precious");
+    assert!(load_utxo_snapshot(&path, Bitcoin, info.content_hash).is_ok());
+
+    // Nor does a failed dump touch it
+    assert!(dump_utxo_set(&path, Bitcoin, &utxo_set, 0).is_err());
+    assert_eq!(File::open(&tmp_path).read_to_string().unwrap().as_slice(), "precious");
+    let mut names: Vec<String> = fs::readdir(dir.path()).unwrap().iter()
+                                   .map(|p| p.filename_str().unwrap().to_string()).collect();
+    names.sort();
+    assert_eq!(names, vec!["snapshot.dat".to_string(), "snapshot.dat.tmp".to_string()]);
+  }
+}
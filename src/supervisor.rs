@@ -0,0 +1,360 @@
+/* The Wizards' Wallet
+ * Written in 2014 by
+ *   Andrew Poelstra <apoelstra@wpsoftware.net>
+ *
+ * To the extent possible under law, the author(s) have dedicated all
+ * copyright and related and neighboring rights to this software to
+ * the public domain worldwide. This software is distributed without
+ * any warranty.
+ *
+ * You should have received a copy of the CC0 Public Domain Dedication
+ * along with this software.
+ * If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+ */
+
+//! # Supervisor
+//!
+//! Runs a network's `Bitcoind` task, and restarts it with backoff if it
+//! dies. RPC requests pass through the supervisor on their way to the task,
+//! so that while it is down they get a "backend unavailable" error rather
+//! than hanging.
+//!
+
+use std::any::{Any, AnyRefExt};
+use std::cmp;
+use std::collections::TreeMap;
+use std::io::timer;
+use std::sync::{Arc, RWLock};
+use std::task::TaskBuilder;
+use std::time::Duration;
+use serialize::json::{mod, ToJson};
+use time;
+
+use bitcoin::network::constants::Network;
+
+use bitcoind::Bitcoind;
+use constants::{RESTART_BACKOFF_MIN, RESTART_BACKOFF_MAX, RESTART_BACKOFF_RESET};
+use logger::{Logger, SyncLog, Status, Warning, Error};
+use metrics::Metrics;
+use rpc_server::{RpcRequest, backend_unavailable};
+use user_data::NetworkConfig;
+
+/// The name of the call answered by the supervisor while the task is down
+static NETWORK_STATUS_CALL: &'static str = "getnetworkstatus";
+
+/// What is known about a network's task
+#[deriving(Clone)]
+pub struct NetworkStatus {
+  /// The network the task is for
+  pub network: Network,
+  /// Whether the task is running
+  pub running: bool,
+  /// Number of times the task has been restarted
+  pub restarts: uint,
+  /// Unix time at which the task was last started
+  pub started: Option<i64>,
+  /// Why the task last died
+  pub last_error: Option<String>,
+  /// Unix time at which the task last died
+  pub last_failure: Option<i64>,
+  /// Unix time at which the task will be restarted, while it is down
+  pub next_restart: Option<i64>
+}
+
+impl NetworkStatus {
+  /// The status of a task which has not been started yet
+  pub fn new(network: Network) -> NetworkStatus {
+    NetworkStatus {
+      network: network,
+      running: false,
+      restarts: 0,
+      started: None,
+      last_error: None,
+      last_failure: None,
+      next_restart: None
+    }
+  }
+}
+
+impl ToJson for NetworkStatus {
+  fn to_json(&self) -> json::Json {
+    let mut obj = TreeMap::new();
+    obj.insert("network".to_string(), json::String(self.network.to_string()));
+    obj.insert("running".to_string(), self.running.to_json());
+    obj.insert("restarts".to_string(), self.restarts.to_json());
+    obj.insert("started".to_string(), self.started.to_json());
+    obj.insert("last_error".to_string(), self.last_error.to_json());
+    obj.insert("last_failure".to_string(), self.last_failure.to_json());
+    obj.insert("next_restart".to_string(), self.next_restart.to_json());
+    json::Object(obj)
+  }
+}
+
+// Things the supervisor waits for
+enum Input {
+  Rpc(RpcRequest),
+  PublicRpc(RpcRequest),
+  Reload(NetworkConfig),
+  // The task stopped, for the given reason
+  Died(String),
+  // The backoff after a death is over
+  Restart
+}
+
+// Channels to a running task
+struct Backend {
+  rpc_tx: Sender<RpcRequest>,
+  coinjoin_tx: Sender<RpcRequest>,
+  config_tx: Sender<NetworkConfig>
+}
+
+/// Keeps a network's task running
+pub struct Supervisor {
+  config: NetworkConfig,
+  logger: Logger,
+  status: Arc<RWLock<NetworkStatus>>,
+  metrics: Metrics,
+  rpc_rx: Receiver<RpcRequest>,
+  coinjoin_rx: Receiver<RpcRequest>,
+  config_rx: Receiver<NetworkConfig>,
+  // Deaths and restarts are reported by helper tasks. We keep a sender
+  // ourselves so that this channel never hangs up.
+  event_tx: Sender<Input>,
+  event_rx: Receiver<Input>,
+  backend: Option<Backend>,
+  // Seconds to wait before the next restart
+  backoff: i64
+}
+
+impl Supervisor {
+  /// Constructor. Requests come in from the RPC listeners on `rpc_rx` and
+  /// `coinjoin_rx`, and reloaded configuration on `config_rx`.
+  pub fn new(config: NetworkConfig,
+             metrics: Metrics,
+             rpc_rx: Receiver<RpcRequest>,
+             coinjoin_rx: Receiver<RpcRequest>,
+             config_rx: Receiver<NetworkConfig>)
+             -> Supervisor {
+    let (event_tx, event_rx) = channel();
+    Supervisor {
+      logger: Logger::new(&config),
+      status: Arc::new(RWLock::new(NetworkStatus::new(config.network))),
+      metrics: metrics,
+      config: config,
+      rpc_rx: rpc_rx,
+      coinjoin_rx: coinjoin_rx,
+      config_rx: config_rx,
+      event_tx: event_tx,
+      event_rx: event_rx,
+      backend: None,
+      backoff: RESTART_BACKOFF_MIN
+    }
+  }
+
+  /// Starts the task and passes requests to it, forever
+  pub fn run(&mut self) {
+    self.start();
+    loop {
+      let mut input = None;
+      nu_select!(
+        request from self.rpc_rx => { input = Some(Rpc(request)); },
+        request from self.coinjoin_rx => { input = Some(PublicRpc(request)); },
+        config from self.config_rx => { input = Some(Reload(config)); },
+        event from self.event_rx => { input = Some(event); }
+      );
+      match input.unwrap() {
+        Rpc(request) => self.forward(request, false),
+        PublicRpc(request) => self.forward(request, true),
+        Reload(config) => {
+          // Keep the live-safe options for any restart, and pass them on
+          match self.config.reload(&config) {
+            Ok(restart) => {
+              match self.backend {
+                Some(ref backend) => { let _ = backend.config_tx.send_opt(config); }
+                None => {
+                  // There is no backend to take it on and report what it could not
+                  self.logger.reload(&self.config);
+                  if !restart.is_empty() {
+                    self.logger.log(SyncLog, Warning,
+                                    format!("Reloaded configuration; changes to {} need a restart \
+                                             to take effect.", restart.connect(", ")));
+                  }
+                }
+              }
+            }
+            Err(e) => {
+              self.logger.log(SyncLog, Error, format!("Rejected configuration reload: {}", e));
+            }
+          }
+        }
+        Died(reason) => self.died(reason),
+        Restart => {
+          self.status.write().restarts += 1;
+          self.metrics.record_restart();
+          self.start();
+        }
+      }
+    }
+  }
+
+  // Spawns a new task, with a helper to report when it stops
+  fn start(&mut self) {
+    let (rpc_tx, rpc_rx) = channel();
+    let (coinjoin_tx, coinjoin_rx) = channel();
+    let (config_tx, config_rx) = channel();
+    let bitcoind = Bitcoind::new(self.config.clone(), self.logger.clone(), self.status.clone(),
+                                 self.metrics.clone(), rpc_rx, coinjoin_rx, config_rx);
+    let result = TaskBuilder::new().named(format!("bitcoind-{}", self.config.network))
+                                   .try_future(proc() {
+      let mut bitcoind = bitcoind;
+      bitcoind.listen()
+    });
+    let event_tx = self.event_tx.clone();
+    spawn(proc() {
+      let reason = match result.recv() {
+        Ok(Ok(())) => "stopped".to_string(),
+        Ok(Err(e)) => e.to_string(),
+        Err(cause) => failure_message(&cause)
+      };
+      let _ = event_tx.send_opt(Died(reason));
+    });
+
+    self.backend = Some(Backend { rpc_tx: rpc_tx, coinjoin_tx: coinjoin_tx, config_tx: config_tx });
+    let mut status = self.status.write();
+    status.running = true;
+    status.started = Some(time::get_time().sec);
+    status.next_restart = None;
+  }
+
+  // Records a death and schedules a restart
+  fn died(&mut self, reason: String) {
+    self.backend = None;
+    let now = time::get_time().sec;
+    let started = self.status.read().started.unwrap_or(now);
+    // A task which ran for a good while before dying starts the backoff over
+    if now - started >= RESTART_BACKOFF_RESET {
+      self.backoff = RESTART_BACKOFF_MIN;
+    }
+    let delay = self.backoff;
+    self.backoff = cmp::min(2 * self.backoff, RESTART_BACKOFF_MAX);
+
+    self.logger.log(SyncLog, Error, format!("Network task died: {}, restarting in {}s.", reason, delay));
+    {
+      let mut status = self.status.write();
+      status.running = false;
+      status.last_error = Some(reason);
+      status.last_failure = Some(now);
+      status.next_restart = Some(now + delay);
+    }
+
+    let event_tx = self.event_tx.clone();
+    spawn(proc() {
+      timer::sleep(Duration::seconds(delay));
+      let _ = event_tx.send_opt(Restart);
+    });
+    self.logger.log(SyncLog, Status, "Requests will be refused until the restart.".to_string());
+  }
+
+  // Passes a request to the task, or refuses it if the task is down
+  fn forward(&self, (request, tx): RpcRequest, public: bool) {
+    let unsent = match self.backend {
+      Some(ref backend) => {
+        let backend_tx = if public { &backend.coinjoin_tx } else { &backend.rpc_tx };
+        match backend_tx.send_opt((request, tx)) {
+          Ok(()) => None,
+          Err(unsent) => Some(unsent)
+        }
+      }
+      None => Some((request, tx))
+    };
+    match unsent {
+      Some((request, tx)) => {
+        let status = self.status.read().to_json();
+        let result = if public {
+          // Don't tell the world why we're down
+          Err(backend_unavailable(None))
+        } else if request.method.as_slice() == NETWORK_STATUS_CALL {
+          Ok(status)
+        } else {
+          Err(backend_unavailable(Some(status)))
+        };
+        let _ = tx.send_opt(result);
+      }
+      None => {}
+    }
+  }
+}
+
+/// Gets the message a task failed with
+fn failure_message(cause: &Box<Any + Send>) -> String {
+  let cause: &Any = &**cause;
+  match cause.downcast_ref::<&'static str>() {
+    Some(s) => s.to_string(),
+    None => match cause.downcast_ref::<String>() {
+      Some(s) => s.clone(),
+      None => "unknown failure".to_string()
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::io::{File, TempDir};
+  use serialize::json;
+
+  use bitcoin::network::constants::Bitcoin;
+
+  use metrics::Metrics;
+  use mock_peer::test_config;
+  use rpc_server::{JsonResult, Positional, Request};
+  use super::{Died, Supervisor};
+
+  fn call(supervisor: &Supervisor, method: &str, public: bool) -> JsonResult {
+    let (tx, rx) = channel();
+    let request = Request { method: method.to_string(), params: Positional(vec![]), id: json::Null };
+    supervisor.forward((request, tx), public);
+    rx.recv()
+  }
+
+  #[test]
+  fn refuses_requests_while_task_is_down() {
+    let dir = TempDir::new("wizards-wallet-test").unwrap();
+    let config = test_config(dir.path(), 0);
+    // An unreadable wallet kills the task at startup, every time
+    File::create(&config.wallet_path).write_str("not [toml").unwrap();
+    let (_rpc_tx, rpc_rx) = channel();
+    let (_coinjoin_tx, coinjoin_rx) = channel();
+    let (_config_tx, config_rx) = channel();
+    let mut supervisor = Supervisor::new(config, Metrics::new(Bitcoin), rpc_rx, coinjoin_rx, config_rx);
+
+    // Step through what `run` would do, waiting for the death itself
+    supervisor.start();
+    match supervisor.event_rx.recv() {
+      Died(reason) => supervisor.died(reason),
+      _ => fail!("the task did not die")
+    }
+    {
+      let status = supervisor.status.read();
+      assert!(!status.running);
+      assert!(status.last_error.is_some());
+    }
+
+    let status = call(&supervisor, "getnetworkstatus", false).ok().unwrap();
+    assert_eq!(status.find(&"running".to_string()), Some(&json::Boolean(false)));
+    match call(&supervisor, "getblockcount", false) {
+      Ok(res) => fail!("getblockcount returned {} with the task down", res),
+      Err(e) => {
+        assert_eq!(e.code, -8);
+        assert_eq!(e.data, Some(status));
+      }
+    }
+    // Public callers aren't told why
+    match call(&supervisor, "getblockcount", true) {
+      Ok(res) => fail!("getblockcount returned {} with the task down", res),
+      Err(e) => {
+        assert_eq!(e.code, -8);
+        assert_eq!(e.data, None);
+      }
+    }
+  }
+}
@@ -17,18 +17,26 @@
 //! Functions for storing and reading data from disk are here
 //!
 
-use std::collections::HashMap;
+use std::collections::{HashMap, TreeMap};
+use std::default::Default;
 use std::io::{File, IoResult, IoError, InvalidInput, FileNotFound};
 use std::path::posix::Path;
 use std::str::from_utf8;
 use std::vec::MoveItems;
 use serialize::Decoder;
+use serialize::hex::FromHex;
+use serialize::json::{mod, ToJson};
 
 use xdg;
 
 use bitcoin::network::constants::{Network, Bitcoin, BitcoinTestnet};
+use bitcoin::network::serialize::deserialize;
+use bitcoin::util::hash::Sha256dHash;
 
-use bitcoind::{DebugLevel, Status};
+use coinjoin::server::{PartialSessionPolicy, SessionPolicy};
+use logger::{DebugLevel, LogFormat, Status, Subsystem, TextFormat};
+use rpc_listener::RpcUser;
+use rpc_server::Permissions;
 
 /// Returns the path to the user's configuration file on disk
 pub fn config_path() -> Path {
@@ -36,30 +44,105 @@ pub fn config_path() -> Path {
   dirs.want_write_config("wizards-wallet/wizards-wallet.conf")
 }
 
+/// Returns the name used for a network in data file names. The `Network`
+/// type comes from rust-bitcoin, which only knows mainnet and testnet; other
+/// networks such as regtest or signet need an arm here, and in the default
+/// ports below, once it grows them.
+fn network_name(network: Network) -> &'static str {
+  match network {
+    Bitcoin => "bitcoin",
+    BitcoinTestnet => "testnet"
+  }
+}
+
+/// Returns the default port to connect to the network peer on
+fn default_peer_port(network: Network) -> u16 {
+  use constants::{DEFAULT_PEER_PORT, DEFAULT_TESTNET_PEER_PORT};
+  match network {
+    Bitcoin => DEFAULT_PEER_PORT,
+    BitcoinTestnet => DEFAULT_TESTNET_PEER_PORT
+  }
+}
+
+/// Returns the default port to listen for RPC on, distinct for each network
+/// so that they can all run at once
+fn default_rpc_server_port(network: Network) -> u16 {
+  use constants::{DEFAULT_RPC_SERVER_PORT, DEFAULT_TESTNET_RPC_SERVER_PORT};
+  match network {
+    Bitcoin => DEFAULT_RPC_SERVER_PORT,
+    BitcoinTestnet => DEFAULT_TESTNET_RPC_SERVER_PORT
+  }
+}
+
+/// Returns the built-in checkpoints for a network, by height
+fn builtin_checkpoints(network: Network) -> TreeMap<uint, Sha256dHash> {
+  use constants::{BITCOIN_CHECKPOINTS, TESTNET_CHECKPOINTS};
+  let checkpoints = match network {
+    Bitcoin => BITCOIN_CHECKPOINTS.as_slice(),
+    BitcoinTestnet => TESTNET_CHECKPOINTS.as_slice()
+  };
+  checkpoints.iter().map(|&(height, hash)| {
+    // Hashes are written big-endian, as everywhere, but serialized little-endian
+    let mut data = hash.from_hex().unwrap();
+    data.reverse();
+    (height, deserialize(data).unwrap())
+  }).collect()
+}
+
+/// Returns the default assume-valid block, which is the last checkpoint
+fn default_assume_valid(network: Network) -> Sha256dHash {
+  let (_, hash) = builtin_checkpoints(network).move_iter().last().unwrap();
+  hash
+}
+
 /// Returns the default path to the blockchain file on disk
 fn blockchain_path(network: Network) -> Path {
   let dirs = xdg::XdgDirs::new();
-  match network {
-    Bitcoin => dirs.want_write_cache("wizards-wallet/blockchain.bitcoin.dat"),
-    BitcoinTestnet => dirs.want_write_cache("wizards-wallet/blockchain.testnet.dat")
-  }
+  dirs.want_write_cache(format!("wizards-wallet/blockchain.{}.dat", network_name(network)).as_slice())
 }
 
 /// Returns the default path to the UTXO cache on disk
 fn utxo_set_path(network: Network) -> Path {
   let dirs = xdg::XdgDirs::new();
-  match network {
-    Bitcoin => dirs.want_write_cache("wizards-wallet/utxoset.bitcoin.dat"),
-    BitcoinTestnet => dirs.want_write_cache("wizards-wallet/utxoset.testnet.dat")
-  }
+  dirs.want_write_cache(format!("wizards-wallet/utxoset.{}.dat", network_name(network)).as_slice())
 }
 
 /// Returns the default path to the user's wallet file on disk
 fn wallet_path(network: Network) -> Path {
   let dirs = xdg::XdgDirs::new();
-  match network {
-    Bitcoin => dirs.want_write_config("wizards-wallet/wallet.bitcoin.toml"),
-    BitcoinTestnet => dirs.want_write_config("wizards-wallet/wallet.testnet.toml")
+  dirs.want_write_config(format!("wizards-wallet/wallet.{}.toml", network_name(network)).as_slice())
+}
+
+/// Returns the default path to the RPC cookie file on disk
+fn rpc_cookie_path(network: Network) -> Path {
+  let dirs = xdg::XdgDirs::new();
+  dirs.want_write_config(format!("wizards-wallet/rpc-cookie.{}", network_name(network)).as_slice())
+}
+
+/// Returns the default path to the coinjoin session history log on disk
+fn coinjoin_history_path(network: Network) -> Path {
+  let dirs = xdg::XdgDirs::new();
+  dirs.want_write_config(format!("wizards-wallet/coinjoin-history.{}.log",
+                                 network_name(network)).as_slice())
+}
+
+/// Returns the default path to the log file on disk
+fn log_path(network: Network) -> Path {
+  let dirs = xdg::XdgDirs::new();
+  dirs.want_write_cache(format!("wizards-wallet/debug.{}.log", network_name(network)).as_slice())
+}
+
+/// Returns the default path to the PID file written in daemon mode
+pub fn pid_path(data_dir: Option<&Path>) -> Path {
+  let dirs = xdg::XdgDirs::new();
+  in_data_dir(data_dir, dirs.want_write_cache("wizards-wallet/wizards-wallet.pid"))
+}
+
+/// Moves a default data file into `data_dir`, if one was given
+fn in_data_dir(data_dir: Option<&Path>, default: Path) -> Path {
+  match data_dir {
+    Some(dir) => dir.join(default.filename().unwrap()),
+    None => default
   }
 }
 
@@ -70,24 +153,235 @@ pub struct NetworkConfig {
   pub network: Network,
   /// Address to connect to the network peer on
   pub peer_addr: String,
-  /// Port to connect to the network peer on
+  /// Port to connect to the network peer on; by default 8333, or 18333 on testnet
   pub peer_port: u16,
   /// Address to listen for RPC requests on
   pub rpc_server_addr: String,
-  /// Port to listen for RPC requests on
+  /// Port to listen for RPC requests on; by default 8001, or 18001 on testnet
   pub rpc_server_port: u16,
+  /// Users who may make RPC requests, besides the cookie file user
+  pub rpc_users: Vec<RpcUser>,
+  /// Path to which the RPC cookie file is written at startup
+  pub rpc_cookie_path: Path,
+  /// PEM certificate for the RPC server; if given with a key, it speaks TLS
+  pub rpc_tls_cert: Option<Path>,
+  /// PEM private key for the RPC server
+  pub rpc_tls_key: Option<Path>,
   /// Whether to operate a coinjoin server as part of RPC
   pub coinjoin_on: bool,
+  /// Default policy for new coinjoin sessions
+  pub coinjoin_policy: SessionPolicy,
+  /// Address for the public coinjoin listener. This is localhost unless
+  /// configured otherwise, so it must be set to be reachable by participants.
+  pub coinjoin_rpc_addr: String,
+  /// Port for the public coinjoin listener; if not given, it is not started
+  pub coinjoin_rpc_port: Option<u16>,
+  /// Maximum size of a request to the public coinjoin listener, in bytes
+  pub coinjoin_rpc_max_request_size: uint,
+  /// Maximum number of requests per minute from one IP to the public coinjoin listener
+  pub coinjoin_rpc_rate_limit: uint,
   /// Whether to allow wallet commands over RPC
   pub wallet_rpc: bool,
+  /// Address for the metrics endpoint
+  pub metrics_addr: String,
+  /// Port for the metrics endpoint; if not given, it is not started
+  pub metrics_port: Option<u16>,
   /// Path to the on-disk blockchain cache
   pub blockchain_path: Path,
   /// Path to the on-disk UTXO set cache
   pub utxo_set_path: Path,
+  /// UTXO snapshot to start from if there is no UTXO set on disk
+  pub utxo_snapshot_path: Option<Path>,
+  /// Content hash the UTXO snapshot must have
+  pub utxo_snapshot_hash: Option<Sha256dHash>,
   /// Path to the user's wallet
   pub wallet_path: Path,
-  /// Path to the on-disk UTXO set cache
-  pub debug_level: DebugLevel
+  /// Path to the log of finished coinjoin sessions
+  pub coinjoin_history_path: Path,
+  /// Number of blocks to request at once during UTXO sync
+  pub utxo_sync_n_blocks: uint,
+  /// Number of blocks to store full blockdata on in case of reorg
+  pub blockchain_n_full_blocks: uint,
+  /// Depth of the deepest reorg we want to survive; the UTXO set is never
+  /// rewound any further
+  pub max_reorg_depth: uint,
+  /// Save-to-disk frequency in seconds
+  pub save_frequency: i64,
+  /// Block hashes the header chain must have at given heights, built-in ones included
+  pub checkpoints: TreeMap<uint, Sha256dHash>,
+  /// Scripts are not checked in this block or below it; set it to the genesis
+  /// hash to check all of them
+  pub assume_valid: Sha256dHash,
+  /// Minimum severity of messages to log, for subsystems without their own level
+  pub debug_level: DebugLevel,
+  /// Minimum severity of messages to log from particular subsystems
+  pub log_levels: HashMap<Subsystem, DebugLevel>,
+  /// Whether to log plain text or JSON lines
+  pub log_format: LogFormat,
+  /// Path to the log file; if not given, logs only go to stdout
+  pub log_path: Option<Path>,
+  /// Size in bytes at which the log file is rotated
+  pub log_max_size: u64,
+  /// Number of rotated log files to keep
+  pub log_max_files: uint
+}
+
+impl NetworkConfig {
+  /// Checks that the options make sense together
+  pub fn validate(&self) -> IoResult<()> {
+    use constants::MAX_UTXO_SYNC_N_BLOCKS;
+
+    let problem = if self.utxo_sync_n_blocks == 0 ||
+                     self.utxo_sync_n_blocks > MAX_UTXO_SYNC_N_BLOCKS {
+      Some(format!("utxo_sync_n_blocks must be between 1 and {}", MAX_UTXO_SYNC_N_BLOCKS))
+    } else if self.blockchain_n_full_blocks <= self.max_reorg_depth {
+      // Rewinding a stale block needs its full data
+      Some(format!("blockchain_n_full_blocks ({}) must exceed max_reorg_depth ({})",
+                   self.blockchain_n_full_blocks, self.max_reorg_depth))
+    } else if self.save_frequency <= 0 {
+      Some("save_frequency must be positive".to_string())
+    } else if self.log_max_size == 0 {
+      Some("log_max_size must be positive".to_string())
+    } else if self.utxo_snapshot_path.is_some() && self.utxo_snapshot_hash.is_none() {
+      // We will not start from a snapshot we have no way to check
+      Some("utxo_snapshot_path needs a utxo_snapshot_hash".to_string())
+    } else {
+      None
+    };
+    match problem {
+      Some(problem) => Err(IoError {
+        kind: InvalidInput,
+        desc: "Invalid configuration",
+        detail: Some(format!("{}: {}", self.network, problem))
+      }),
+      None => Ok(())
+    }
+  }
+
+  /// Takes on those options from `new` which are safe to change while
+  /// running, and returns the names of any others which differ and so
+  /// need a restart to take effect. If the options we would end up with
+  /// don't make sense together, nothing is changed.
+  pub fn reload(&mut self, new: &NetworkConfig) -> IoResult<Vec<&'static str>> {
+    let mut merged = self.clone();
+    merged.utxo_sync_n_blocks = new.utxo_sync_n_blocks;
+    merged.max_reorg_depth = new.max_reorg_depth;
+    merged.save_frequency = new.save_frequency;
+    merged.checkpoints = new.checkpoints.clone();
+    merged.assume_valid = new.assume_valid;
+    merged.coinjoin_policy = new.coinjoin_policy.clone();
+    merged.debug_level = new.debug_level;
+    merged.log_levels = new.log_levels.clone();
+    merged.log_format = new.log_format;
+    // The live options are checked against those we keep, e.g. a new
+    // max_reorg_depth against the blockchain_n_full_blocks we started with
+    try!(merged.validate());
+
+    let mut ret = vec![];
+    if self.peer_addr != new.peer_addr { ret.push("peer_addr"); }
+    if self.peer_port != new.peer_port { ret.push("peer_port"); }
+    if self.rpc_server_addr != new.rpc_server_addr { ret.push("rpc_server_addr"); }
+    if self.rpc_server_port != new.rpc_server_port { ret.push("rpc_server_port"); }
+    if self.rpc_users != new.rpc_users { ret.push("rpc_user"); }
+    if self.rpc_cookie_path != new.rpc_cookie_path { ret.push("rpc_cookie_path"); }
+    if self.rpc_tls_cert != new.rpc_tls_cert { ret.push("rpc_tls_cert"); }
+    if self.rpc_tls_key != new.rpc_tls_key { ret.push("rpc_tls_key"); }
+    if self.coinjoin_on != new.coinjoin_on { ret.push("coinjoin_on"); }
+    if self.coinjoin_rpc_addr != new.coinjoin_rpc_addr { ret.push("coinjoin_rpc_addr"); }
+    if self.coinjoin_rpc_port != new.coinjoin_rpc_port { ret.push("coinjoin_rpc_port"); }
+    if self.coinjoin_rpc_max_request_size != new.coinjoin_rpc_max_request_size {
+      ret.push("coinjoin_rpc_max_request_size");
+    }
+    if self.coinjoin_rpc_rate_limit != new.coinjoin_rpc_rate_limit {
+      ret.push("coinjoin_rpc_rate_limit");
+    }
+    if self.wallet_rpc != new.wallet_rpc { ret.push("wallet_rpc"); }
+    if self.metrics_addr != new.metrics_addr { ret.push("metrics_addr"); }
+    if self.metrics_port != new.metrics_port { ret.push("metrics_port"); }
+    if self.blockchain_path != new.blockchain_path { ret.push("blockchain_path"); }
+    if self.utxo_set_path != new.utxo_set_path { ret.push("utxo_set_path"); }
+    if self.utxo_snapshot_path != new.utxo_snapshot_path { ret.push("utxo_snapshot_path"); }
+    if self.utxo_snapshot_hash != new.utxo_snapshot_hash { ret.push("utxo_snapshot_hash"); }
+    if self.wallet_path != new.wallet_path { ret.push("wallet_path"); }
+    if self.coinjoin_history_path != new.coinjoin_history_path { ret.push("coinjoin_history_path"); }
+    if self.log_path != new.log_path { ret.push("log_path"); }
+    if self.log_max_size != new.log_max_size { ret.push("log_max_size"); }
+    if self.log_max_files != new.log_max_files { ret.push("log_max_files"); }
+    // The UTXO set keeps undo data for this many blocks from when it was created
+    if self.blockchain_n_full_blocks != new.blockchain_n_full_blocks {
+      ret.push("blockchain_n_full_blocks");
+    }
+    *self = merged;
+    Ok(ret)
+  }
+}
+
+impl ToJson for NetworkConfig {
+  fn to_json(&self) -> json::Json {
+    let mut obj = TreeMap::new();
+    obj.insert("network".to_string(), json::String(self.network.to_string()));
+    obj.insert("peer_addr".to_string(), self.peer_addr.to_json());
+    obj.insert("peer_port".to_string(), self.peer_port.to_json());
+    obj.insert("rpc_server_addr".to_string(), self.rpc_server_addr.to_json());
+    obj.insert("rpc_server_port".to_string(), self.rpc_server_port.to_json());
+    // Names only; passwords stay in the configuration file
+    obj.insert("rpc_users".to_string(),
+               json::List(self.rpc_users.iter().map(|u| u.name.to_json()).collect()));
+    obj.insert("rpc_cookie_path".to_string(), path_json(&self.rpc_cookie_path));
+    obj.insert("rpc_tls".to_string(),
+               json::Boolean(self.rpc_tls_cert.is_some() && self.rpc_tls_key.is_some()));
+    obj.insert("coinjoin_on".to_string(), self.coinjoin_on.to_json());
+    obj.insert("coinjoin_policy".to_string(), self.coinjoin_policy.to_json());
+    obj.insert("coinjoin_rpc_addr".to_string(), self.coinjoin_rpc_addr.to_json());
+    obj.insert("coinjoin_rpc_port".to_string(), self.coinjoin_rpc_port.to_json());
+    obj.insert("coinjoin_rpc_max_request_size".to_string(),
+               self.coinjoin_rpc_max_request_size.to_json());
+    obj.insert("coinjoin_rpc_rate_limit".to_string(), self.coinjoin_rpc_rate_limit.to_json());
+    obj.insert("wallet_rpc".to_string(), self.wallet_rpc.to_json());
+    obj.insert("metrics_addr".to_string(), self.metrics_addr.to_json());
+    obj.insert("metrics_port".to_string(), self.metrics_port.to_json());
+    obj.insert("blockchain_path".to_string(), path_json(&self.blockchain_path));
+    obj.insert("utxo_set_path".to_string(), path_json(&self.utxo_set_path));
+    obj.insert("utxo_snapshot_path".to_string(),
+               self.utxo_snapshot_path.as_ref().map_or(json::Null, path_json));
+    obj.insert("utxo_snapshot_hash".to_string(), self.utxo_snapshot_hash.to_json());
+    obj.insert("wallet_path".to_string(), path_json(&self.wallet_path));
+    obj.insert("coinjoin_history_path".to_string(), path_json(&self.coinjoin_history_path));
+    obj.insert("utxo_sync_n_blocks".to_string(), self.utxo_sync_n_blocks.to_json());
+    obj.insert("blockchain_n_full_blocks".to_string(), self.blockchain_n_full_blocks.to_json());
+    obj.insert("max_reorg_depth".to_string(), self.max_reorg_depth.to_json());
+    obj.insert("save_frequency".to_string(), self.save_frequency.to_json());
+    let mut checkpoints = TreeMap::new();
+    for (height, hash) in self.checkpoints.iter() {
+      checkpoints.insert(height.to_string(), hash.to_json());
+    }
+    obj.insert("checkpoints".to_string(), json::Object(checkpoints));
+    obj.insert("assume_valid".to_string(), self.assume_valid.to_json());
+    obj.insert("debug_level".to_string(), json::String(self.debug_level.to_string()));
+    let mut levels = TreeMap::new();
+    for (subsystem, level) in self.log_levels.iter() {
+      levels.insert(subsystem.to_string(), json::String(level.to_string()));
+    }
+    obj.insert("log_levels".to_string(), json::Object(levels));
+    obj.insert("log_format".to_string(), json::String(self.log_format.to_string()));
+    obj.insert("log_path".to_string(), self.log_path.as_ref().map_or(json::Null, path_json));
+    obj.insert("log_max_size".to_string(), self.log_max_size.to_json());
+    obj.insert("log_max_files".to_string(), self.log_max_files.to_json());
+    json::Object(obj)
+  }
+}
+
+fn path_json(path: &Path) -> json::Json {
+  json::String(path.display().to_string())
+}
+
+#[deriving(Decodable)]
+struct TomlRpcUser {
+  name: String,
+  password: String,
+  coinjoin: Option<bool>,
+  wallet: Option<bool>,
+  files: Option<bool>
 }
 
 #[deriving(Decodable)]
@@ -96,12 +390,38 @@ struct TomlNetworkConfig {
   peer_port: Option<u16>,
   rpc_server_addr: Option<String>,
   rpc_server_port: Option<u16>,
+  rpc_user: Option<Vec<TomlRpcUser>>,
+  rpc_cookie_path: Option<Path>,
+  rpc_tls_cert: Option<Path>,
+  rpc_tls_key: Option<Path>,
   coinjoin_on: Option<bool>,
+  coinjoin_policy: Option<PartialSessionPolicy>,
+  coinjoin_rpc_addr: Option<String>,
+  coinjoin_rpc_port: Option<u16>,
+  coinjoin_rpc_max_request_size: Option<uint>,
+  coinjoin_rpc_rate_limit: Option<uint>,
   wallet_rpc: Option<bool>,
+  metrics_addr: Option<String>,
+  metrics_port: Option<u16>,
   blockchain_path: Option<Path>,
   utxo_set_path: Option<Path>,
+  utxo_snapshot_path: Option<Path>,
+  utxo_snapshot_hash: Option<Sha256dHash>,
   wallet_path: Option<Path>,
-  debug_level: Option<DebugLevel>
+  coinjoin_history_path: Option<Path>,
+  utxo_sync_n_blocks: Option<uint>,
+  blockchain_n_full_blocks: Option<uint>,
+  max_reorg_depth: Option<uint>,
+  save_frequency: Option<i64>,
+  checkpoints: Option<HashMap<String, Sha256dHash>>,
+  assume_valid: Option<Sha256dHash>,
+  debug_level: Option<DebugLevel>,
+  log_levels: Option<HashMap<Subsystem, DebugLevel>>,
+  log_format: Option<LogFormat>,
+  log_file: Option<bool>,
+  log_path: Option<Path>,
+  log_max_size: Option<u64>,
+  log_max_files: Option<uint>
 }
 
 /// A list of user configuration for all networks
@@ -117,7 +437,9 @@ impl Config {
   }
 }
 
-fn read_configuration(path: &Path) -> IoResult<Config> {
+/// Parses a configuration file, placing any data files without a configured
+/// path in `data_dir` if it is given
+pub fn read_configuration(path: &Path, data_dir: Option<&Path>) -> IoResult<Config> {
   use serialize::Decodable;
   use toml::{Parser, Decoder, Table};
 
@@ -168,68 +490,232 @@ fn read_configuration(path: &Path) -> IoResult<Config> {
     }
   };
 
-  // Move the hashmap into something nicer to use, with missing fields
-  // filled in by defaults
+  // Move the hashmap into something nicer to use
   let mut ret = Vec::with_capacity(decode.len());
   for (network, toml_config) in decode.move_iter() {
-    use constants::DEFAULT_PEER_ADDR;
-    use constants::DEFAULT_PEER_PORT;
-    use constants::DEFAULT_RPC_SERVER_ADDR;
-    use constants::DEFAULT_RPC_SERVER_PORT;
-
-    ret.push(NetworkConfig {
-      network: network,
-      peer_addr: toml_config.peer_addr.unwrap_or(DEFAULT_PEER_ADDR.to_string()),
-      peer_port: toml_config.peer_port.unwrap_or(DEFAULT_PEER_PORT),
-      rpc_server_addr: toml_config.rpc_server_addr.unwrap_or(DEFAULT_RPC_SERVER_ADDR.to_string()),
-      rpc_server_port: toml_config.rpc_server_port.unwrap_or(DEFAULT_RPC_SERVER_PORT),
-      coinjoin_on: toml_config.coinjoin_on.unwrap_or(false),
-      wallet_rpc: toml_config.wallet_rpc.unwrap_or(false),
-      blockchain_path: toml_config.blockchain_path.unwrap_or(blockchain_path(network)),
-      utxo_set_path: toml_config.utxo_set_path.unwrap_or(utxo_set_path(network)),
-      wallet_path: toml_config.wallet_path.unwrap_or(wallet_path(network)),
-      debug_level: toml_config.debug_level.unwrap_or(Status)
-    });
+    ret.push(try!(network_config(network, toml_config, data_dir)));
   }
   Ok(Config(ret))
 }
 
+/// Builds the configuration for one network from its section of the
+/// configuration file, with missing fields filled in by defaults
+fn network_config(network: Network, toml_config: TomlNetworkConfig, data_dir: Option<&Path>)
+                  -> IoResult<NetworkConfig> {
+  use constants::DEFAULT_PEER_ADDR;
+  use constants::DEFAULT_RPC_SERVER_ADDR;
+  use constants::DEFAULT_COINJOIN_RPC_ADDR;
+  use constants::DEFAULT_COINJOIN_RPC_MAX_REQUEST_SIZE;
+  use constants::DEFAULT_COINJOIN_RPC_RATE_LIMIT;
+  use constants::DEFAULT_METRICS_ADDR;
+  use constants::DEFAULT_UTXO_SYNC_N_BLOCKS;
+  use constants::DEFAULT_BLOCKCHAIN_N_FULL_BLOCKS;
+  use constants::DEFAULT_MAX_REORG_DEPTH;
+  use constants::DEFAULT_SAVE_FREQUENCY;
+  use constants::DEFAULT_LOG_MAX_SIZE;
+  use constants::DEFAULT_LOG_MAX_FILES;
+
+  // Checkpoints from the file go alongside the built-in ones, which they
+  // may not contradict
+  let mut checkpoints = builtin_checkpoints(network);
+  for (height, hash) in toml_config.checkpoints.unwrap_or(HashMap::new()).move_iter() {
+    let problem = match from_str::<uint>(height.as_slice()) {
+      None => Some(format!("checkpoint height `{}` is not a number", height)),
+      Some(height) => {
+        if checkpoints.find(&height).map_or(false, |builtin| *builtin != hash) {
+          Some(format!("checkpoint {:x} at height {} contradicts a built-in one", hash, height))
+        } else {
+          checkpoints.insert(height, hash);
+          None
+        }
+      }
+    };
+    match problem {
+      Some(problem) => {
+        return Err(IoError {
+          kind: InvalidInput,
+          desc: "Invalid configuration",
+          detail: Some(format!("{}: {}", network, problem))
+        });
+      }
+      None => {}
+    }
+  }
+
+  let config = NetworkConfig {
+    network: network,
+    peer_addr: toml_config.peer_addr.unwrap_or(DEFAULT_PEER_ADDR.to_string()),
+    peer_port: toml_config.peer_port.unwrap_or(default_peer_port(network)),
+    rpc_server_addr: toml_config.rpc_server_addr.unwrap_or(DEFAULT_RPC_SERVER_ADDR.to_string()),
+    rpc_server_port: toml_config.rpc_server_port.unwrap_or(default_rpc_server_port(network)),
+    rpc_users: toml_config.rpc_user.unwrap_or(vec![]).move_iter().map(|user| RpcUser {
+      name: user.name,
+      password: user.password,
+      permissions: Permissions {
+        coinjoin: user.coinjoin.unwrap_or(false),
+        wallet: user.wallet.unwrap_or(false),
+        files: user.files.unwrap_or(false)
+      }
+    }).collect(),
+    rpc_cookie_path: toml_config.rpc_cookie_path
+                       .unwrap_or(in_data_dir(data_dir, rpc_cookie_path(network))),
+    rpc_tls_cert: toml_config.rpc_tls_cert,
+    rpc_tls_key: toml_config.rpc_tls_key,
+    coinjoin_on: toml_config.coinjoin_on.unwrap_or(false),
+    coinjoin_policy: toml_config.coinjoin_policy.map_or(Default::default(),
+                                                        |p| p.apply(&Default::default())),
+    coinjoin_rpc_addr: toml_config.coinjoin_rpc_addr.unwrap_or(DEFAULT_COINJOIN_RPC_ADDR.to_string()),
+    coinjoin_rpc_port: toml_config.coinjoin_rpc_port,
+    coinjoin_rpc_max_request_size: toml_config.coinjoin_rpc_max_request_size
+                                     .unwrap_or(DEFAULT_COINJOIN_RPC_MAX_REQUEST_SIZE),
+    coinjoin_rpc_rate_limit: toml_config.coinjoin_rpc_rate_limit
+                               .unwrap_or(DEFAULT_COINJOIN_RPC_RATE_LIMIT),
+    wallet_rpc: toml_config.wallet_rpc.unwrap_or(false),
+    metrics_addr: toml_config.metrics_addr.unwrap_or(DEFAULT_METRICS_ADDR.to_string()),
+    metrics_port: toml_config.metrics_port,
+    blockchain_path: toml_config.blockchain_path
+                       .unwrap_or(in_data_dir(data_dir, blockchain_path(network))),
+    utxo_set_path: toml_config.utxo_set_path
+                     .unwrap_or(in_data_dir(data_dir, utxo_set_path(network))),
+    utxo_snapshot_path: toml_config.utxo_snapshot_path,
+    utxo_snapshot_hash: toml_config.utxo_snapshot_hash,
+    wallet_path: toml_config.wallet_path
+                   .unwrap_or(in_data_dir(data_dir, wallet_path(network))),
+    coinjoin_history_path: toml_config.coinjoin_history_path
+                             .unwrap_or(in_data_dir(data_dir, coinjoin_history_path(network))),
+    utxo_sync_n_blocks: toml_config.utxo_sync_n_blocks.unwrap_or(DEFAULT_UTXO_SYNC_N_BLOCKS),
+    blockchain_n_full_blocks: toml_config.blockchain_n_full_blocks
+                                .unwrap_or(DEFAULT_BLOCKCHAIN_N_FULL_BLOCKS),
+    max_reorg_depth: toml_config.max_reorg_depth.unwrap_or(DEFAULT_MAX_REORG_DEPTH),
+    save_frequency: toml_config.save_frequency.unwrap_or(DEFAULT_SAVE_FREQUENCY),
+    checkpoints: checkpoints,
+    assume_valid: toml_config.assume_valid.unwrap_or(default_assume_valid(network)),
+    debug_level: toml_config.debug_level.unwrap_or(Status),
+    log_levels: toml_config.log_levels.unwrap_or(HashMap::new()),
+    log_format: toml_config.log_format.unwrap_or(TextFormat),
+    log_path: if toml_config.log_file.unwrap_or(true) {
+                Some(toml_config.log_path.unwrap_or(in_data_dir(data_dir, log_path(network))))
+              } else {
+                None
+              },
+    log_max_size: toml_config.log_max_size.unwrap_or(DEFAULT_LOG_MAX_SIZE),
+    log_max_files: toml_config.log_max_files.unwrap_or(DEFAULT_LOG_MAX_FILES)
+  };
+  try!(config.validate());
+  Ok(config)
+}
+
+/// The configuration used when there is no configuration file: the bitcoin
+/// network, with every field defaulted just as if it were missing from a file
+fn default_configuration(data_dir: Option<&Path>) -> IoResult<Config> {
+  use serialize::Decodable;
+  use toml::{Decoder, Table};
+
+  // An empty table decodes with every field missing
+  let mut d = Decoder::new(Table(TreeMap::new()));
+  let toml_config: TomlNetworkConfig = try!(Decodable::decode(&mut d).map_err(|err| IoError {
+    kind: InvalidInput,
+    desc: "TOML parser error",
+    detail: Some(err.to_string())
+  }));
+  Ok(Config(vec![try!(network_config(Bitcoin, toml_config, data_dir))]))
+}
+
+/// Parses a configuration file, or gives the default configuration if there
+/// is none. Unlike `load_configuration`, this prints nothing.
+pub fn read_configuration_or_default(path: &Path, data_dir: Option<&Path>) -> IoResult<Config> {
+  match read_configuration(path, data_dir) {
+    Err(ref err) if err.kind == FileNotFound => default_configuration(data_dir),
+    res => res
+  }
+}
+
 /// Parses a configuration file and returns its bounty
-pub fn load_configuration(path: &Path) -> Option<Config> {
+pub fn load_configuration(path: &Path, data_dir: Option<&Path>) -> Option<Config> {
   // Try to parse the user's config file
-  match read_configuration(path) {
-    Ok(res) => Some(res),
+  let res = match read_configuration(path, data_dir) {
+    // For file not found, we use the default configuration...
+    Err(ref err) if err.kind == FileNotFound => {
+      println!("Did not find {}, using default configuration.", path.display());
+      default_configuration(data_dir)
+    }
+    res => res
+  };
+  match res {
+    Ok(config) => Some(config),
+    // But for anything else, the user must've made a mistake. Better to do nothing.
     Err(err) => {
-      // For file not found, we use the default configuration...
-      if err.kind == FileNotFound {
-        use constants::DEFAULT_PEER_ADDR;
-        use constants::DEFAULT_PEER_PORT;
-        use constants::DEFAULT_RPC_SERVER_ADDR;
-        use constants::DEFAULT_RPC_SERVER_PORT;
-
-        println!("Did not find {}, using default configuration.", path.display());
-
-        Some(Config(vec![
-          NetworkConfig {
-            network: Bitcoin,
-            peer_addr: DEFAULT_PEER_ADDR.to_string(),
-            peer_port: DEFAULT_PEER_PORT,
-            rpc_server_addr: DEFAULT_RPC_SERVER_ADDR.to_string(),
-            rpc_server_port: DEFAULT_RPC_SERVER_PORT,
-            coinjoin_on: false,
-            wallet_rpc: false,
-            blockchain_path: blockchain_path(Bitcoin),
-            utxo_set_path: utxo_set_path(Bitcoin),
-            wallet_path: wallet_path(Bitcoin),
-            debug_level: Status
-          }]))
-      }
-      // But for anything else, the user must've made a mistake. Better to do nothing.
-      else {
-        println!("{}", err);
-        None
-      }
+      println!("{}", err);
+      None
     }
   }
 }
 
+#[cfg(test)]
+mod tests {
+  use std::io::{File, TempDir};
+  use serialize::json::ToJson;
+
+  use constants::{DEFAULT_BLOCKCHAIN_N_FULL_BLOCKS, DEFAULT_MAX_REORG_DEPTH};
+  use constants::DEFAULT_UTXO_SYNC_N_BLOCKS;
+  use logger::Debug;
+  use mock_peer::test_config;
+  use super::{default_configuration, read_configuration};
+
+  #[test]
+  fn reload_applies_only_live_options() {
+    let dir = TempDir::new("wizards-wallet-test").unwrap();
+    let mut config = test_config(dir.path(), 0);
+    let mut new = config.clone();
+    new.utxo_sync_n_blocks = 10;
+    new.debug_level = Debug;
+    new.rpc_tls_cert = Some(dir.path().join("cert.pem"));
+    new.coinjoin_rpc_rate_limit += 1;
+    new.blockchain_n_full_blocks = 2 * DEFAULT_BLOCKCHAIN_N_FULL_BLOCKS;
+
+    assert_eq!(config.reload(&new).unwrap(),
+               vec!["rpc_tls_cert", "coinjoin_rpc_rate_limit", "blockchain_n_full_blocks"]);
+    assert_eq!(config.utxo_sync_n_blocks, 10);
+    assert_eq!(config.debug_level, Debug);
+    // The others are left as they were until a restart
+    assert!(config.rpc_tls_cert.is_none());
+    assert_eq!(config.coinjoin_rpc_rate_limit, new.coinjoin_rpc_rate_limit - 1);
+    // The UTXO set was built with the old window
+    assert_eq!(config.blockchain_n_full_blocks, DEFAULT_BLOCKCHAIN_N_FULL_BLOCKS);
+  }
+
+  #[test]
+  fn reload_rejects_invalid_config() {
+    let dir = TempDir::new("wizards-wallet-test").unwrap();
+    let mut config = test_config(dir.path(), 0);
+    // Too deep for the full blocks we keep, which only change on restart
+    let mut new = config.clone();
+    new.utxo_sync_n_blocks = 10;
+    new.max_reorg_depth = 2 * DEFAULT_BLOCKCHAIN_N_FULL_BLOCKS;
+    new.blockchain_n_full_blocks = 4 * DEFAULT_BLOCKCHAIN_N_FULL_BLOCKS;
+    assert!(new.validate().is_ok());
+    assert!(config.reload(&new).is_err());
+    // and nothing was taken on
+    assert_eq!(config.utxo_sync_n_blocks, DEFAULT_UTXO_SYNC_N_BLOCKS);
+    assert_eq!(config.max_reorg_depth, DEFAULT_MAX_REORG_DEPTH);
+
+    new.max_reorg_depth = 0;
+    assert_eq!(config.reload(&new).unwrap(), vec!["blockchain_n_full_blocks"]);
+    assert_eq!(config.utxo_sync_n_blocks, 10);
+    assert_eq!(config.max_reorg_depth, 0);
+  }
+
+  #[test]
+  fn default_configuration_is_an_empty_file() {
+    let dir = TempDir::new("wizards-wallet-test").unwrap();
+    let path = dir.path().join("wizards-wallet.toml");
+    File::create(&path).write_str("[bitcoin]\n").unwrap();
+
+    let from_file: Vec<_> = read_configuration(&path, Some(dir.path())).unwrap()
+                              .move_iter().map(|c| c.to_json()).collect();
+    let default: Vec<_> = default_configuration(Some(dir.path())).unwrap()
+                            .move_iter().map(|c| c.to_json()).collect();
+    assert_eq!(from_file.len(), 1);
+    assert_eq!(from_file, default);
+  }
+}
@@ -0,0 +1,370 @@
+/* The Wizards' Wallet
+ * Written in 2014 by
+ *   Andrew Poelstra <apoelstra@wpsoftware.net>
+ *
+ * To the extent possible under law, the author(s) have dedicated all
+ * copyright and related and neighboring rights to this software to
+ * the public domain worldwide. This software is distributed without
+ * any warranty.
+ *
+ * You should have received a copy of the CC0 Public Domain Dedication
+ * along with this software.
+ * If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+ */
+
+//! # Chain Verification
+//!
+//! Rechecks the blocks at the tip of a blockchain, for confidence in one
+//! loaded from disk. Each level adds checks to those below it:
+//!
+//! 0. Proof of work: each header's hash meets its own target, which is
+//!    no easier than the network allows.
+//! 1. Difficulty: targets change only at retargets, to what the timestamps
+//!    of the previous period call for.
+//! 2. Timestamps: each is later than the median of the eleven before it.
+//! 3. Merkle roots: blocks whose transactions we store commit to them.
+//!
+
+use std::collections::TreeMap;
+use std::num::FromPrimitive;
+use std::uint;
+use serialize::json::{mod, ToJson};
+
+use bitcoin::blockdata::block::{Block, BlockHeader};
+use bitcoin::blockdata::blockchain::Blockchain;
+use bitcoin::blockdata::constants::genesis_block;
+use bitcoin::blockdata::transaction::Transaction;
+use bitcoin::network::constants::{Network, Bitcoin};
+use bitcoin::network::serialize::{BitcoinHash, serialize};
+use bitcoin::util::hash::Sha256dHash;
+use bitcoin::util::uint::Uint256;
+
+/// The highest verification level
+pub static MAX_VERIFY_LEVEL: uint = 3;
+
+/// Number of blocks to check if not told otherwise; a couple of days' worth
+pub static DEFAULT_VERIFY_DEPTH: uint = 288;
+
+/// Number of blocks between difficulty retargets
+static RETARGET_INTERVAL: uint = 2016;
+
+/// Time that a retarget period should take, in seconds
+static TARGET_TIMESPAN: u32 = 14 * 24 * 60 * 60;
+
+/// Number of earlier blocks whose median time a block must be later than
+static MEDIAN_TIME_SPAN: uint = 11;
+
+/// The first block which failed a check, and why
+#[deriving(Clone, PartialEq, Eq, Show)]
+pub struct VerifyFailure {
+  /// Height of the block
+  pub height: uint,
+  /// Hash of the block
+  pub hash: Sha256dHash,
+  /// Level of the check which failed
+  pub level: uint,
+  /// What was wrong
+  pub reason: String
+}
+
+impl ToJson for VerifyFailure {
+  fn to_json(&self) -> json::Json {
+    let mut obj = TreeMap::new();
+    obj.insert("height".to_string(), self.height.to_json());
+    obj.insert("hash".to_string(), self.hash.to_json());
+    obj.insert("level".to_string(), self.level.to_json());
+    obj.insert("reason".to_string(), self.reason.to_json());
+    json::Object(obj)
+  }
+}
+
+/// Checks the last `depth` blocks of the best chain, or all of it if `depth`
+/// is zero, up to the given level. Returns how many blocks passed, and the
+/// first failure if there was one.
+pub fn verify_chain(blockchain: &Blockchain, network: Network, depth: uint, level: uint)
+                    -> (uint, Option<VerifyFailure>) {
+  let max_target = genesis_block(network).header.target();
+  let (n_blocks, n_kept) = if depth == 0 {
+    (uint::MAX, uint::MAX)
+  } else {
+    // Keep the blocks before the last one checked, for its median time
+    (depth, depth + MEDIAN_TIME_SPAN)
+  };
+  let chain: Vec<(uint, &Block, bool)> = blockchain.rev_iter(blockchain.best_tip_hash())
+                                                   .take(n_kept)
+                                                   .map(|node| (node.height as uint, &node.block,
+                                                                node.has_txdata))
+                                                   .collect();
+
+  let mut n_checked = 0;
+  for (idx, &(height, block, has_txdata)) in chain.iter().enumerate().take(n_blocks) {
+    let failure = |level: uint, reason: String| VerifyFailure {
+      height: height,
+      hash: block.bitcoin_hash(),
+      level: level,
+      reason: reason
+    };
+    let header = &block.header;
+
+    // Proof of work
+    match check_proof_of_work(header, max_target) {
+      Some(reason) => { return (n_checked, Some(failure(0, reason))); }
+      None => {}
+    }
+
+    // Everything else is relative to earlier blocks, which genesis doesn't have
+    if height > 0 && idx + 1 < chain.len() {
+      let (_, prev_block, _) = chain[idx + 1];
+      let prev = &prev_block.header;
+
+      // Difficulty
+      if level >= 1 {
+        if height % RETARGET_INTERVAL == 0 {
+          match retarget(blockchain, prev, max_target) {
+            Some(expected) if expected != header.target() => {
+              let reason = format!("bits {:x} do not match the retarget", header.bits);
+              return (n_checked, Some(failure(1, reason)));
+            }
+            Some(_) => {}
+            None => {
+              let reason = "blocks of the previous retarget period are missing".to_string();
+              return (n_checked, Some(failure(1, reason)));
+            }
+          }
+        } else if network == Bitcoin && header.bits != prev.bits {
+          // Testnet allows minimum-difficulty blocks between retargets, so
+          // there we only check the retargets themselves
+          let reason = format!("bits changed from {:x} to {:x} between retargets",
+                               prev.bits, header.bits);
+          return (n_checked, Some(failure(1, reason)));
+        }
+      }
+
+      // Timestamps
+      if level >= 2 {
+        let times: Vec<u32> = chain.iter().skip(idx + 1).take(MEDIAN_TIME_SPAN)
+                                   .map(|&(_, block, _)| block.header.time).collect();
+        match check_time(header.time, times) {
+          Some(reason) => { return (n_checked, Some(failure(2, reason))); }
+          None => {}
+        }
+      }
+    }
+
+    // Merkle roots
+    if level >= 3 && has_txdata {
+      match merkle_root(block.txdata.as_slice()) {
+        Some(root) if root == header.merkle_root => {}
+        Some(root) => {
+          let reason = format!("transactions have merkle root {:x}, not {:x}",
+                               root, header.merkle_root);
+          return (n_checked, Some(failure(3, reason)));
+        }
+        None => {
+          return (n_checked, Some(failure(3, "block has no transactions".to_string())));
+        }
+      }
+    }
+    n_checked += 1;
+  }
+  (n_checked, None)
+}
+
+/// Checks that a header's hash meets its target, and that the target is no
+/// easier than `max_target`
+fn check_proof_of_work(header: &BlockHeader, max_target: Uint256) -> Option<String> {
+  let target = header.target();
+  if target > max_target {
+    Some(format!("target of bits {:x} is easier than the network allows", header.bits))
+  } else if header.bitcoin_hash().into_le() > target {
+    Some("hash does not meet the target".to_string())
+  } else {
+    None
+  }
+}
+
+/// Checks that a block's time is after the median of the given earlier ones
+fn check_time(time: u32, mut earlier: Vec<u32>) -> Option<String> {
+  earlier.sort();
+  let median = earlier[earlier.len() / 2];
+  if time <= median {
+    Some(format!("time {} is not after the median time past {}", time, median))
+  } else {
+    None
+  }
+}
+
+/// Works out the target a retarget should give, from the last header of
+/// the previous period, as it would be encoded in a header's bits
+fn retarget(blockchain: &Blockchain, last: &BlockHeader, max_target: Uint256) -> Option<Uint256> {
+  // Find the first header of the period
+  let mut first = *last;
+  for _ in range(1, RETARGET_INTERVAL) {
+    first = match blockchain.get_block(first.prev_blockhash) {
+      Some(node) => node.block.header,
+      None => { return None; }
+    };
+  }
+
+  Some(next_target(last.target(), first.time, last.time, max_target))
+}
+
+/// Works out a retarget from the last target of the previous period and the
+/// times of its first and last blocks
+fn next_target(last_target: Uint256, first_time: u32, last_time: u32, max_target: Uint256) -> Uint256 {
+  // The change is limited to a factor of four either way
+  let timespan = if last_time > first_time { last_time - first_time } else { 0 };
+  let timespan = if timespan < TARGET_TIMESPAN / 4 {
+    TARGET_TIMESPAN / 4
+  } else if timespan > TARGET_TIMESPAN * 4 {
+    TARGET_TIMESPAN * 4
+  } else {
+    timespan
+  };
+  let target_timespan: Uint256 = FromPrimitive::from_u32(TARGET_TIMESPAN).unwrap();
+  let target = last_target.mul_u32(timespan) / target_timespan;
+  compact_target(if target > max_target { max_target } else { target })
+}
+
+/// Truncates a target to the precision of the compact form used in headers,
+/// which has a 23-bit mantissa
+fn compact_target(target: Uint256) -> Uint256 {
+  let mut size = (target.bits() + 7) / 8;
+  if size <= 3 {
+    return target;
+  }
+  // A mantissa with its top bit set would be read as negative, so is
+  // stored one byte shorter
+  if (target >> 8 * (size - 3)).low_u32() & 0x00800000 != 0 {
+    size += 1;
+  }
+  (target >> 8 * (size - 3)) << 8 * (size - 3)
+}
+
+/// Works out the merkle root of a list of transactions
+fn merkle_root(txdata: &[Transaction]) -> Option<Sha256dHash> {
+  merkle_root_of(txdata.iter().map(|tx| tx.bitcoin_hash()).collect())
+}
+
+/// Works out the merkle root of a list of transaction hashes
+fn merkle_root_of(mut hashes: Vec<Sha256dHash>) -> Option<Sha256dHash> {
+  while hashes.len() > 1 {
+    // An odd hash out is paired with itself
+    let mut next = Vec::with_capacity((hashes.len() + 1) / 2);
+    for pair in hashes.as_slice().chunks(2) {
+      let mut data = serialize(&pair[0]).unwrap();
+      data.push_all(serialize(pair.last().unwrap()).unwrap().as_slice());
+      next.push(Sha256dHash::from_data(data.as_slice()));
+    }
+    hashes = next;
+  }
+  hashes.pop()
+}
+
+#[cfg(test)]
+mod tests {
+  use serialize::hex::FromHex;
+
+  use bitcoin::blockdata::blockchain::Blockchain;
+  use bitcoin::blockdata::constants::genesis_block;
+  use bitcoin::network::constants::Bitcoin;
+  use bitcoin::network::serialize::deserialize;
+  use bitcoin::util::hash::Sha256dHash;
+  use bitcoin::util::uint::Uint256;
+
+  use mock_peer::mainnet_blocks;
+  use super::{MAX_VERIFY_LEVEL, TARGET_TIMESPAN, check_proof_of_work, check_time,
+              compact_target, merkle_root, merkle_root_of, next_target, verify_chain};
+
+  // Hashes are written big-endian, but serialized little-endian
+  fn hash(hex: &str) -> Sha256dHash {
+    let mut data = hex.from_hex().unwrap();
+    data.reverse();
+    deserialize(data).unwrap()
+  }
+
+  fn target(bits: u32) -> Uint256 {
+    let mut header = genesis_block(Bitcoin).header;
+    header.bits = bits;
+    header.target()
+  }
+
+  fn max_target() -> Uint256 {
+    genesis_block(Bitcoin).header.target()
+  }
+
+  #[test]
+  fn retargets_match_mainnet() {
+    // Block 32256, the first retarget to change anything; blocks 30240 and
+    // 32255 bound the period before it
+    let retarget = next_target(target(0x1d00ffff), 1261130161, 1262152739, max_target());
+    assert_eq!(retarget, target(0x1d00d86a));
+    // Targets read from headers are already compact
+    assert_eq!(compact_target(retarget), retarget);
+  }
+
+  #[test]
+  fn retargets_are_clamped() {
+    // A period taking no time at all only cuts the target to a quarter
+    assert_eq!(next_target(target(0x1d00ffff), 1262152739, 1262152739, max_target()),
+               target(0x1c3fffc0));
+    // and one taking forever only multiplies it by four, which here would
+    // be easier than the network allows
+    assert_eq!(next_target(target(0x1d00d86a), 0, 4 * TARGET_TIMESPAN, max_target()),
+               max_target());
+  }
+
+  #[test]
+  fn merkle_root_matches_mainnet() {
+    // The four transactions of block 100000
+    let txids = vec![hash("8c14f0db3df150123e6f3dbbf30f8b955a8249b62ac1d1ff16284aefa3d06d87"),
+                     hash("fff2525b8931402dd09222c50775608f75787bd2b87e56995a7bdd30f79702c4"),
+                     hash("6359f0868171b1d194cbee1af2f16ea598ae8fad666d9b012c8ed2b79a236ec4"),
+                     hash("e9a66845e05d5abc0ad04ec80f774a7e585c6e8db975962d069a522137b80c1d")];
+    assert_eq!(merkle_root_of(txids),
+               Some(hash("f3e94742aca4b5ef85488dc37c06c3282295ffec960994b2c0d5ac2a25a95766")));
+    assert_eq!(merkle_root_of(vec![]), None);
+
+    // A lone transaction is its own root
+    for block in mainnet_blocks().iter() {
+      assert_eq!(merkle_root(block.txdata.as_slice()), Some(block.header.merkle_root));
+    }
+  }
+
+  #[test]
+  fn tampered_header_fails_proof_of_work() {
+    let header = mainnet_blocks()[0].header;
+    assert_eq!(check_proof_of_work(&header, max_target()), None);
+
+    let mut tampered = header;
+    tampered.nonce += 1;
+    assert!(check_proof_of_work(&tampered, max_target()).is_some());
+    // Nor can the target be made easier to compensate
+    let mut tampered = header;
+    tampered.bits = 0x1d01ffff;
+    assert!(check_proof_of_work(&tampered, max_target()).is_some());
+  }
+
+  #[test]
+  fn tampered_timestamp_fails() {
+    let blocks = mainnet_blocks();
+    let genesis_time = genesis_block(Bitcoin).header.time;
+    // Each earlier block, newest first, as the chain is walked
+    let mut earlier: Vec<u32> = blocks.slice_to(3).iter().rev().map(|b| b.header.time).collect();
+    earlier.push(genesis_time);
+    assert_eq!(check_time(blocks[3].header.time, earlier.clone()), None);
+    // Back to the median of the four, block 2's time, is too early
+    assert!(check_time(blocks[1].header.time, earlier.clone()).is_some());
+    assert!(check_time(genesis_time, earlier).is_some());
+  }
+
+  #[test]
+  fn mainnet_chain_passes() {
+    let mut blockchain = Blockchain::new(Bitcoin);
+    for block in mainnet_blocks().move_iter() {
+      blockchain.add_block(block).unwrap();
+    }
+    // Genesis included
+    assert_eq!(verify_chain(&blockchain, Bitcoin, 0, MAX_VERIFY_LEVEL), (5, None));
+  }
+}